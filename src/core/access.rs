@@ -0,0 +1,233 @@
+use crate::config::Config;
+
+// Outcome of checking a path against the deny/allow lists
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Access {
+    Allowed,
+    // Blocked by a directory rule, answered with 403
+    Forbidden,
+    // Blocked by a file or extension rule, answered with 404 so the file's existence isn't leaked
+    Hidden,
+}
+
+impl Access {
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Access::Allowed => None,
+            Access::Forbidden => Some(403),
+            Access::Hidden => Some(404),
+        }
+    }
+}
+
+// Applies deny_files, deny_extensions, deny_directories and allow_directories to paths
+// relative to root. Every matching rule gets a rank and the highest one wins, deny winning ties:
+//
+//   1. deny_files, which name the file itself
+//   2. directory rules, by how deep in the path the directory they matched is
+//   3. deny_extensions
+//   4. directory rules made only of wildcards, e.g. `deny_directories *`
+//
+// So `deny_directories .git` still covers `/assets/images/.git/HEAD` when `assets/images` is
+// allowed, the .git directory sits deeper than the allowed one.
+//
+// Directory patterns containing a `/` are anchored at root, single segment patterns match a
+// directory of that name at any depth. `*` and `?` glob within a single segment. Files sitting
+// directly in root are never matched by directory rules.
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    deny_files: Vec<String>,
+    deny_extensions: Vec<String>,
+    deny_directories: Vec<String>,
+    allow_directories: Vec<String>,
+}
+
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let text = text.as_bytes();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*` if the current attempt fails
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Ranks a directory pattern matching any of the directories: 0 for wildcard-only patterns,
+// otherwise one more than the depth of the deepest directory it matched, leaving 1 for
+// deny_extensions
+fn match_directory(pattern: &str, directories: &[&str]) -> Option<usize> {
+    let segments: Vec<&str> = pattern.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.is_empty() {
+        return None;
+    }
+
+    let depth = if pattern.contains('/') {
+        let matched = segments.len() <= directories.len()
+            && segments.iter().zip(directories).all(|(p, d)| glob_match(p, d));
+        matched.then_some(segments.len())
+    } else {
+        directories.iter().rposition(|d| glob_match(segments[0], d)).map(|i| i + 1)
+    }?;
+
+    if segments.iter().all(|s| s.chars().all(|c| c == '*')) {
+        Some(0)
+    } else {
+        Some(depth + 1)
+    }
+}
+
+impl AccessControl {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            deny_files: config.deny_files().to_vec(),
+            deny_extensions: config.deny_extensions().to_vec(),
+            deny_directories: config.deny_directories().to_vec(),
+            allow_directories: config.allow_directories().to_vec(),
+        }
+    }
+
+    // Checks a request path such as `/assets/css/app.css`
+    pub fn check(&self, path: &str) -> Access {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let (file, directories) = match segments.split_last() {
+            Some((file, directories)) => (*file, directories),
+            None => return Access::Allowed,
+        };
+
+        if self.deny_files.iter().any(|pattern| glob_match(pattern, file)) {
+            return Access::Hidden;
+        }
+
+        let allow = self.allow_directories.iter()
+            .filter_map(|p| match_directory(p, directories))
+            .max();
+        let mut deny = self.deny_directories.iter()
+            .filter_map(|p| match_directory(p, directories))
+            .max()
+            .map(|rank| (rank, Access::Forbidden));
+
+        if let Some((_, extension)) = file.rsplit_once('.') {
+            let denied = self.deny_extensions.iter()
+                .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(extension));
+            if denied && deny.is_none_or(|(score, _)| score < 1) {
+                deny = Some((1, Access::Hidden));
+            }
+        }
+
+        match (allow, deny) {
+            (Some(allow), Some((deny, _))) if allow > deny => Access::Allowed,
+            (_, Some((_, access))) => access,
+            _ => Access::Allowed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(deny_files: &[&str], deny_extensions: &[&str], deny_directories: &[&str], allow_directories: &[&str]) -> AccessControl {
+        let to_vec = |list: &[&str]| list.iter().map(|s| s.to_string()).collect();
+        AccessControl {
+            deny_files: to_vec(deny_files),
+            deny_extensions: to_vec(deny_extensions),
+            deny_directories: to_vec(deny_directories),
+            allow_directories: to_vec(allow_directories),
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("*.bak", "config.php.bak"));
+        assert!(glob_match("file?.txt", "file1.txt"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(glob_match(".git", ".git"));
+        assert!(!glob_match("*.bak", "config.php"));
+        assert!(!glob_match("file?.txt", "file10.txt"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+    }
+
+    #[test]
+    fn test_deny_files_and_extensions() {
+        let acl = access(&["index.html", "*.bak"], &["html", ".JS"], &[], &[]);
+        assert_eq!(acl.check("/index.html"), Access::Hidden);
+        assert_eq!(acl.check("/docs/index.html"), Access::Hidden);
+        assert_eq!(acl.check("/config.php.bak"), Access::Hidden);
+        assert_eq!(acl.check("/about.HTML"), Access::Hidden);
+        assert_eq!(acl.check("/app.js"), Access::Hidden);
+        assert_eq!(acl.check("/index.php"), Access::Allowed);
+        assert_eq!(acl.check("/"), Access::Allowed);
+    }
+
+    #[test]
+    fn test_deny_directories() {
+        let acl = access(&[], &[], &[".git", "storage/logs"], &[]);
+        assert_eq!(acl.check("/.git/config"), Access::Forbidden);
+        assert_eq!(acl.check("/vendor/pkg/.git/HEAD"), Access::Forbidden);
+        assert_eq!(acl.check("/storage/logs/today.log"), Access::Forbidden);
+        assert_eq!(acl.check("/app/storage/logs/today.log"), Access::Allowed);
+        assert_eq!(acl.check("/storage/public/a.png"), Access::Allowed);
+    }
+
+    #[test]
+    fn test_allow_beats_wildcard_deny() {
+        let acl = access(&[], &[], &["*", ".git"], &["assets/images", "assets/css"]);
+        assert_eq!(acl.check("/index.php"), Access::Allowed);
+        assert_eq!(acl.check("/assets/images/logo.png"), Access::Allowed);
+        assert_eq!(acl.check("/assets/css/nested/app.css"), Access::Allowed);
+        assert_eq!(acl.check("/assets/fonts/a.woff"), Access::Forbidden);
+        assert_eq!(acl.check("/vendor/autoload.php"), Access::Forbidden);
+        // The .git directory is deeper than the allowed one
+        assert_eq!(acl.check("/assets/images/.git/HEAD"), Access::Forbidden);
+    }
+
+    #[test]
+    fn test_most_specific_match_wins() {
+        let acl = access(&[], &[], &["assets/private", "assets/*/secret"], &["assets"]);
+        assert_eq!(acl.check("/assets/app.css"), Access::Allowed);
+        assert_eq!(acl.check("/assets/private/key.pem"), Access::Forbidden);
+        assert_eq!(acl.check("/assets/img/secret/a.png"), Access::Forbidden);
+
+        // Allowing a directory inside a denied one
+        let acl = access(&[], &[], &["storage"], &["storage/public"]);
+        assert_eq!(acl.check("/storage/public/a.png"), Access::Allowed);
+        assert_eq!(acl.check("/storage/public/storage/a.png"), Access::Forbidden);
+
+        // Same depth, deny wins
+        let acl = access(&[], &[], &["assets"], &["assets"]);
+        assert_eq!(acl.check("/assets/app.css"), Access::Forbidden);
+        let acl = access(&[], &[], &["*"], &["*"]);
+        assert_eq!(acl.check("/assets/app.css"), Access::Forbidden);
+    }
+
+    #[test]
+    fn test_extensions_between_wildcards_and_directories() {
+        let acl = access(&[], &["js"], &[], &["assets/js"]);
+        assert_eq!(acl.check("/assets/js/app.js"), Access::Allowed);
+        assert_eq!(acl.check("/src/app.js"), Access::Hidden);
+
+        let acl = access(&[], &["js"], &[], &["*"]);
+        assert_eq!(acl.check("/src/app.js"), Access::Hidden);
+
+        // deny_files beats everything
+        let acl = access(&["secret.js"], &[], &[], &["assets/js"]);
+        assert_eq!(acl.check("/assets/js/secret.js"), Access::Hidden);
+    }
+}
@@ -0,0 +1,420 @@
+use crate::http_validator::{parse_field, Headers};
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+
+// Longest chunk size or trailer line we accept, chunk extensions included
+pub const MAX_LINE: usize = 8 * 1024;
+// Total size of the trailer section
+const MAX_TRAILERS: usize = 64 * 1024;
+
+// Why a chunked body couldn't be decoded
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ChunkedError {
+    InvalidSize,
+    // The chunk sizes add up to more than the limit, or more than fits in a u64
+    TooLarge,
+    MissingCrlf,
+    LineTooLong,
+    InvalidTrailer,
+    TrailersTooLarge,
+}
+
+impl fmt::Display for ChunkedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ChunkedError::InvalidSize => "invalid chunk size",
+            ChunkedError::TooLarge => "chunked body too large",
+            ChunkedError::MissingCrlf => "missing CRLF after chunk data",
+            ChunkedError::LineTooLong => "chunk size line too long",
+            ChunkedError::InvalidTrailer => "invalid trailer",
+            ChunkedError::TrailersTooLarge => "trailers too large",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+impl std::error::Error for ChunkedError {}
+
+impl From<ChunkedError> for io::Error {
+    fn from(error: ChunkedError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+fn unexpected_eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-body")
+}
+
+// Parses `1a3f;name=value`, extensions are ignored
+fn parse_size(line: &[u8]) -> Result<u64, ChunkedError> {
+    let size = line.split(|&b| b == b';').next().unwrap_or_default().trim_ascii_end();
+    if size.is_empty() || !size.iter().all(u8::is_ascii_hexdigit) {
+        return Err(ChunkedError::InvalidSize);
+    }
+    // Only hex digits, so this can only fail on overflow
+    let size = std::str::from_utf8(size).map_err(|_| ChunkedError::InvalidSize)?;
+    u64::from_str_radix(size, 16).map_err(|_| ChunkedError::TooLarge)
+}
+
+// Strips the CRLF, or bare LF, ending a line
+fn without_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// What `ChunkedParser::parse` found at the start of its input
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Step {
+    // Nothing more can be parsed until more input arrives
+    Incomplete,
+    // A chunk size line, the line ending after a chunk's data or a trailer line, this many bytes
+    Framing(usize),
+    // This many bytes of payload
+    Data(usize),
+    // The empty line ending the trailers and the body, this many bytes
+    Done(usize),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum State {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailers,
+    Done,
+}
+
+// Decodes a `Transfer-Encoding: chunked` body, see RFC 9112 section 7.1. Input is pushed in as
+// it arrives, and each call reports one step along with how many bytes it took, so callers can
+// copy or drop the framing as they like. Everything reading chunked bodies goes through this.
+#[derive(Debug)]
+pub struct ChunkedParser {
+    state: State,
+    // Most payload accepted in total
+    limit: u64,
+    total: u64,
+    trailers: Headers,
+    trailers_size: usize,
+}
+
+impl ChunkedParser {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            state: State::Size,
+            limit,
+            total: 0,
+            trailers: Headers::new(),
+            trailers_size: 0,
+        }
+    }
+
+    // Whether the last chunk and the trailers have been parsed
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    fn in_data(&self) -> bool {
+        matches!(self.state, State::Data(_))
+    }
+
+    // Trailer fields, only complete once the parser is done
+    pub fn trailers(&self) -> &Headers {
+        &self.trailers
+    }
+
+    pub fn take_trailers(&mut self) -> Headers {
+        std::mem::take(&mut self.trailers)
+    }
+
+    // Parses one step from the start of `input`. The caller is expected to drop the bytes the
+    // step covers from its input before the next call.
+    pub fn parse(&mut self, input: &[u8]) -> Result<Step, ChunkedError> {
+        match self.state {
+            State::Done => return Ok(Step::Done(0)),
+            State::Data(remaining) => {
+                let n = (input.len() as u64).min(remaining);
+                if n == 0 {
+                    return Ok(Step::Incomplete);
+                }
+                self.state = if n == remaining { State::DataEnd } else { State::Data(remaining - n) };
+                return Ok(Step::Data(n as usize));
+            }
+            _ => {}
+        }
+
+        let window = &input[..input.len().min(MAX_LINE + 1)];
+        let length = match window.iter().position(|&b| b == b'\n') {
+            Some(end) => end + 1,
+            None if window.len() <= MAX_LINE => return Ok(Step::Incomplete),
+            None if self.state == State::Trailers => return Err(ChunkedError::TrailersTooLarge),
+            None => return Err(ChunkedError::LineTooLong),
+        };
+        let line = without_line_ending(&input[..length]);
+
+        match self.state {
+            State::Size => {
+                let size = parse_size(line)?;
+                // A huge chunk size would wrap the sum around and slip under the limit
+                self.total = self.total.checked_add(size).filter(|&total| total <= self.limit)
+                    .ok_or(ChunkedError::TooLarge)?;
+                self.state = if size == 0 { State::Trailers } else { State::Data(size) };
+            }
+            State::DataEnd if line.is_empty() => self.state = State::Size,
+            State::DataEnd => return Err(ChunkedError::MissingCrlf),
+            State::Trailers if line.is_empty() => {
+                self.state = State::Done;
+                return Ok(Step::Done(length));
+            }
+            State::Trailers => {
+                self.trailers_size += line.len();
+                if self.trailers_size > MAX_TRAILERS {
+                    return Err(ChunkedError::TrailersTooLarge);
+                }
+                let (name, value) = parse_field(line).map_err(|_| ChunkedError::InvalidTrailer)?;
+                self.trailers.append(&name, &value);
+            }
+            State::Data(_) | State::Done => unreachable!(),
+        }
+        Ok(Step::Framing(length))
+    }
+}
+
+// Runs the parser over what the reader has buffered, at most `max_data` bytes of payload at a
+// time, and hands the step and the bytes it covers to `f` before consuming them. A line split
+// across reads is gathered whole first.
+fn advance<R, F>(parser: &mut ChunkedParser, reader: &mut R, max_data: usize, f: F) -> io::Result<Step>
+where
+    R: BufRead + ?Sized,
+    F: FnOnce(Step, &[u8]) -> io::Result<()>,
+{
+    let input = reader.fill_buf()?;
+    let input = if parser.in_data() { &input[..input.len().min(max_data)] } else { input };
+    let step = parser.parse(input)?;
+    let n = match step {
+        Step::Incomplete => {
+            let mut line = Vec::new();
+            reader.take(MAX_LINE as u64 + 1).read_until(b'\n', &mut line)?;
+            let step = parser.parse(&line)?;
+            if step == Step::Incomplete {
+                return Err(unexpected_eof());
+            }
+            f(step, &line)?;
+            return Ok(step);
+        }
+        Step::Framing(n) | Step::Data(n) | Step::Done(n) => n,
+    };
+    f(step, &input[..n])?;
+    reader.consume(n);
+    Ok(step)
+}
+
+// Reads the payload of a chunked body. Reading stops at the end of the message, leaving
+// anything after it in the reader.
+pub struct ChunkedDecoder<R> {
+    reader: R,
+    parser: ChunkedParser,
+}
+
+impl<R: BufRead> ChunkedDecoder<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            parser: ChunkedParser::new(u64::MAX),
+        }
+    }
+
+    // Whether the last chunk and the trailers have been read
+    pub fn is_done(&self) -> bool {
+        self.parser.is_done()
+    }
+
+    // Trailer fields, only complete once the decoder is done
+    pub fn trailers(&self) -> &Headers {
+        self.parser.trailers()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: BufRead> Read for ChunkedDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !buf.is_empty() && !self.parser.is_done() {
+            let mut copied = 0;
+            advance(&mut self.parser, &mut self.reader, buf.len(), |step, bytes| {
+                if let Step::Data(n) = step {
+                    buf[..n].copy_from_slice(bytes);
+                    copied = n;
+                }
+                Ok(())
+            })?;
+            if copied > 0 {
+                return Ok(copied);
+            }
+        }
+        Ok(0)
+    }
+}
+
+// Encodes everything written to it as chunks, `finish` writes the last chunk and trailers
+pub struct ChunkedEncoder<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> ChunkedEncoder<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn finish(mut self, trailers: &Headers) -> io::Result<W> {
+        self.writer.write_all(b"0\r\n")?;
+        for (name, value) in trailers.iter() {
+            write!(self.writer, "{}: ", name)?;
+            self.writer.write_all(value)?;
+            self.writer.write_all(b"\r\n")?;
+        }
+        self.writer.write_all(b"\r\n")?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for ChunkedEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        write!(self.writer, "{:x}\r\n", buf.len())?;
+        self.writer.write_all(buf)?;
+        self.writer.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Copies a chunked body unchanged, framing included, stopping right after its end
+pub fn pass_through<R: BufRead, W: Write + ?Sized>(reader: &mut R, writer: &mut W) -> io::Result<()> {
+    let mut parser = ChunkedParser::new(u64::MAX);
+    while !parser.is_done() {
+        advance(&mut parser, reader, usize::MAX, |step, bytes| pass_step(step, bytes, writer))?;
+    }
+    Ok(())
+}
+
+// Writes the bytes a step of `ChunkedParser::parse` covered on, the way `pass_through` does
+pub fn pass_step<W: Write + ?Sized>(step: Step, bytes: &[u8], writer: &mut W) -> io::Result<()> {
+    match step {
+        Step::Incomplete => Ok(()),
+        Step::Data(_) => writer.write_all(bytes),
+        // Lines go out with CRLF, whatever the sender ended them with
+        _ => {
+            writer.write_all(without_line_ending(bytes))?;
+            writer.write_all(b"\r\n")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Returns the payload, the trailers and whatever followed the body
+    fn decode(body: &[u8]) -> io::Result<(Vec<u8>, Headers, Vec<u8>)> {
+        let mut decoder = ChunkedDecoder::new(body);
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded)?;
+        assert!(decoder.is_done());
+        let trailers = decoder.trailers().clone();
+        Ok((decoded, trailers, decoder.into_inner().to_vec()))
+    }
+
+    #[test]
+    fn test_decode() {
+        let (body, trailers, rest) = decode(b"5\r\nhello\r\n6;ext=\"a\"\r\n world\r\n0\r\n\r\nGET / HTTP/1.1").unwrap();
+        assert_eq!(body, b"hello world");
+        assert!(trailers.is_empty());
+        // The next pipelined message is left alone
+        assert_eq!(rest, b"GET / HTTP/1.1");
+
+        let (body, trailers, _) = decode(b"A\n0123456789\n0\nExpires: never\nX-Sum:  42 \n\n").unwrap();
+        assert_eq!(body, b"0123456789");
+        assert_eq!(trailers.iter().collect::<Vec<_>>(), [("Expires", &b"never"[..]), ("X-Sum", b"42")]);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode(b"zz\r\n").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(decode(b"-1\r\n").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(decode(b"ffffffffffffffffff\r\n").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(decode(b"5\r\nhelloX\r\n0\r\n\r\n").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(decode(b"0\r\nno colon\r\n\r\n").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(decode(b"5\r\nhel").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(decode(b"5\r\nhello\r\n").unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        let long = [b'1'; MAX_LINE + 1];
+        assert_eq!(decode(&long).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_lines_split_across_reads() {
+        let body = b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Sum: 42\r\n\r\nrest";
+        let mut decoder = ChunkedDecoder::new(io::BufReader::with_capacity(3, &body[..]));
+        let mut decoded = Vec::new();
+        decoder.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, b"hello world");
+        assert_eq!(decoder.trailers().get("x-sum"), Some("42"));
+
+        let mut reader = decoder.into_inner();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"rest");
+
+        let mut output = Vec::new();
+        pass_through(&mut io::BufReader::with_capacity(2, &body[..]), &mut output).unwrap();
+        assert_eq!(output, &body[..body.len() - 4]);
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let data: Vec<u8> = (0..=255u8).cycle().take(100_000).collect();
+        let mut encoder = ChunkedEncoder::new(Vec::new());
+        for piece in data.chunks(7000) {
+            encoder.write_all(piece).unwrap();
+        }
+        encoder.write_all(b"").unwrap();
+        let mut trailers = Headers::new();
+        trailers.append("X-Sum", b"1");
+        let encoded = encoder.finish(&trailers).unwrap();
+        assert!(encoded.starts_with(b"1b58\r\n"));
+        assert!(encoded.ends_with(b"\r\n0\r\nX-Sum: 1\r\n\r\n"));
+
+        let (decoded, decoded_trailers, _) = decode(&encoded).unwrap();
+        assert!(decoded == data);
+        assert_eq!(decoded_trailers, trailers);
+    }
+
+    #[test]
+    fn test_pass_through() {
+        let body = b"5;x=1\r\nhello\r\n0\r\nX-Sum: 1\r\n\r\n";
+        let mut input = [&body[..], b"next"].concat();
+        let mut reader = &input[..];
+        let mut output = Vec::new();
+        pass_through(&mut reader, &mut output).unwrap();
+        assert_eq!(output, body);
+        assert_eq!(reader, b"next");
+
+        // Bare LFs come out as CRLFs
+        input = b"2\nhi\n0\n\n".to_vec();
+        output.clear();
+        pass_through(&mut &input[..], &mut output).unwrap();
+        assert_eq!(output, b"2\r\nhi\r\n0\r\n\r\n");
+
+        let truncated = b"5\r\nhello\r\n";
+        assert!(pass_through(&mut &truncated[..], &mut Vec::new()).is_err());
+    }
+}
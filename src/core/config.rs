@@ -1,23 +1,214 @@
+use crate::workers::QueueFull;
 use std::io::{self, Error, ErrorKind};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Where a listener accepts connections, an IP address and port or `unix:/path`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddress {
+    fn is_ipv6(&self) -> bool {
+        matches!(self, ListenAddress::Tcp(addr) if addr.is_ipv6())
+    }
+}
+
+impl From<SocketAddr> for ListenAddress {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddress::Tcp(addr)
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// A `listen` line, the address to accept connections on and the options written after it,
+// e.g. `[::]:443 backlog=1024`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listen {
+    pub address: ListenAddress,
+    // Connections the kernel queues up before they're accepted
+    pub backlog: i32,
+    // Whether an IPv6 listener leaves IPv4 to a separate 0.0.0.0 listener
+    pub ipv6only: bool,
+}
+
+impl Listen {
+    pub fn new(address: impl Into<ListenAddress>) -> Self {
+        Listen {
+            address: address.into(),
+            backlog: 511,
+            ipv6only: true,
+        }
+    }
+
+    fn set_param(&mut self, param: &str) -> io::Result<()> {
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("Invalid listen parameter for {}: {}", self.address, param));
+        match param.split_once('=') {
+            // servw serves a single site, there's no other server to pick by Host
+            None if param == "default_server" => {
+                return Err(Error::new(ErrorKind::InvalidData, format!("default_server has no effect on {}, servw serves a single site", self.address)));
+            }
+            Some(("backlog", value)) => {
+                self.backlog = value.parse::<i32>().ok().filter(|b| *b > 0).ok_or_else(invalid)?;
+            }
+            Some(("ipv6only", value)) if self.address.is_ipv6() => {
+                self.ipv6only = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(invalid()),
+                };
+            }
+            _ => return Err(invalid()),
+        }
+        Ok(())
+    }
+}
+
+// Accepts `addr:port`, `[addr]:port` for IPv6, `unix:/path` for a Unix domain socket, and
+// `*:port` or a bare port for every IPv4 address, as nginx does
+pub fn parse_listen_address(value: &str) -> Option<ListenAddress> {
+    if let Some(path) = value.strip_prefix("unix:") {
+        return (!path.is_empty()).then(|| ListenAddress::Unix(PathBuf::from(path)));
+    }
+    let port = value.strip_prefix("*:").unwrap_or(value);
+    if let Ok(port) = port.parse::<u16>() {
+        return Some(SocketAddr::from(([0, 0, 0, 0], port)).into());
+    }
+    value.parse::<SocketAddr>().ok().map(ListenAddress::Tcp)
+}
+
+// A `servers` entry along with the parameters written after it, e.g. `127.0.0.1:3001 weight=3`
+#[derive(Debug, Clone, PartialEq)]
+pub struct UpstreamServer {
+    pub address: String,
+    pub weight: u32,
+    // Failed attempts within fail_timeout before the server is ejected for fail_timeout, 0 never ejects
+    pub max_fails: u32,
+    pub fail_timeout: Duration,
+}
+
+impl UpstreamServer {
+    pub fn new(address: &str) -> Self {
+        UpstreamServer {
+            address: address.to_string(),
+            weight: 1,
+            max_fails: 1,
+            fail_timeout: Duration::from_secs(10),
+        }
+    }
+
+    fn set_param(&mut self, key: &str, value: &str) -> io::Result<()> {
+        match key {
+            "weight" => {
+                self.weight = value.parse::<u32>()
+                    .ok()
+                    .filter(|w| *w > 0)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Invalid weight for {}: {}", self.address, value)))?;
+            }
+            "max_fails" => {
+                self.max_fails = value.parse::<u32>()
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, format!("Invalid max_fails for {}: {}", self.address, value)))?;
+            }
+            "fail_timeout" => {
+                self.fail_timeout = parse_duration(value)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Invalid fail_timeout for {}: {}", self.address, value)))?;
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown server parameter: {}", key),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Accepts `500ms`, `5s`, `2m` or a bare number of seconds
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let number = number.parse::<u64>().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "s" => Some(Duration::from_secs(number)),
+        // Too large to be meant, rejected rather than wrapped
+        "m" => number.checked_mul(60).map(Duration::from_secs),
+        _ => None,
+    }
+}
+
+// Accepts a number of bytes with an optional `k`, `m` or `g` suffix
+pub fn parse_size(value: &str) -> Option<u64> {
+    let lower = value.to_ascii_lowercase();
+    let (number, multiplier) = match lower.chars().last()? {
+        'k' => (&lower[..lower.len() - 1], 1024),
+        'm' => (&lower[..lower.len() - 1], 1024 * 1024),
+        'g' => (&lower[..lower.len() - 1], 1024 * 1024 * 1024),
+        _ => (lower.as_str(), 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
-    listen: String,
+    listen: Vec<Listen>,
     index: String,
     pass: String,
     deny_files: Vec<String>,
     deny_extensions: Vec<String>,
     lb_algo: String,
-    servers: Vec<String>,
+    servers: Vec<UpstreamServer>,
     root: String,
     deny_directories: Vec<String>,
     allow_directories: Vec<String>,
+    health_check_interval: Duration,
+    health_check_timeout: Duration,
+    health_check_rise: u32,
+    health_check_fall: u32,
+    health_check_path: String,
+    upstream_retries: usize,
+    client_max_body_size: u64,
+    keepalive_timeout: Duration,
+    keepalive_requests: usize,
+    upstream_keepalive: usize,
+    upstream_keepalive_timeout: Duration,
+    worker_threads: usize,
+    worker_queue: usize,
+    worker_queue_full: QueueFull,
+    client_header_timeout: Duration,
+    client_body_timeout: Duration,
+    client_send_timeout: Duration,
+    upstream_connect_timeout: Duration,
+    upstream_read_timeout: Duration,
+    cgi_timeout: Duration,
+    shutdown_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Config {
     pub fn new() -> Self {
         Config {
-            listen: "3000".to_string(),
+            listen: vec![],
             index: "index.php".to_string(),
             pass: "".to_string(),
             deny_files: vec![],
@@ -27,6 +218,27 @@ impl Config {
             root: ".".to_string(),
             deny_directories: vec![],
             allow_directories: vec![],
+            health_check_interval: Duration::ZERO,
+            health_check_timeout: Duration::from_secs(2),
+            health_check_rise: 2,
+            health_check_fall: 3,
+            health_check_path: "".to_string(),
+            upstream_retries: 1,
+            client_max_body_size: 16 * 1024 * 1024,
+            keepalive_timeout: Duration::from_secs(75),
+            keepalive_requests: 100,
+            upstream_keepalive: 32,
+            upstream_keepalive_timeout: Duration::from_secs(60),
+            worker_threads: 128,
+            worker_queue: 1024,
+            worker_queue_full: QueueFull::Reject,
+            client_header_timeout: Duration::from_secs(60),
+            client_body_timeout: Duration::from_secs(60),
+            client_send_timeout: Duration::from_secs(60),
+            upstream_connect_timeout: Duration::from_secs(60),
+            upstream_read_timeout: Duration::from_secs(60),
+            cgi_timeout: Duration::from_secs(60),
+            shutdown_timeout: Duration::from_secs(30),
         }
     }
 
@@ -48,10 +260,19 @@ impl Config {
 
             match parts[0] {
                 "listen" => {
-                    if parts.len() != 2 {
+                    if parts.len() < 2 {
                         return Err(Error::new(ErrorKind::InvalidData, "Invalid listen directive"));
                     }
-                    self.listen = parts[1].to_string();
+                    let address = parse_listen_address(parts[1])
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Invalid listen address: {}", parts[1])))?;
+                    if self.listen.iter().any(|listen| listen.address == address) {
+                        return Err(Error::new(ErrorKind::InvalidData, format!("Duplicate listen address: {}", address)));
+                    }
+                    let mut listen = Listen::new(address.clone());
+                    for param in &parts[2..] {
+                        listen.set_param(param)?;
+                    }
+                    self.listen.push(listen);
                 }
                 "index" => {
                     if parts.len() != 2 {
@@ -82,7 +303,7 @@ impl Config {
                         return Err(Error::new(ErrorKind::InvalidData, "Invalid alb_algo directive"));
                     }
                     match parts[1] {
-                        "none" | "roundrobin" | "leastconn" | "source" | "off" => {
+                        "none" | "roundrobin" | "weighted" | "leastconn" | "source" | "off" => {
                             self.lb_algo = parts[1].to_string();
                         }
                         _ => {
@@ -97,7 +318,15 @@ impl Config {
                     if parts.len() < 2 {
                         return Err(Error::new(ErrorKind::InvalidData, "Invalid servers directive"));
                     }
-                    self.servers.extend(parts[1..].iter().map(|&s| s.to_string()));
+                    for part in &parts[1..] {
+                        match (part.split_once('='), self.servers.last_mut()) {
+                            (Some((key, value)), Some(server)) => server.set_param(key, value)?,
+                            (Some(_), None) => {
+                                return Err(Error::new(ErrorKind::InvalidData, "Server parameter without a server"));
+                            }
+                            (None, _) => self.servers.push(UpstreamServer::new(part)),
+                        }
+                    }
                 },
                 "root" => {
                     if parts.len() != 2 {
@@ -117,6 +346,117 @@ impl Config {
                     }
                     self.allow_directories.extend(parts[1..].iter().map(|&s| s.to_string()));
                 },
+                "health_check_interval" | "health_check_timeout" => {
+                    let duration = match parts.len() {
+                        2 => parse_duration(parts[1]),
+                        _ => None,
+                    };
+                    let duration = duration.ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidData, format!("Invalid {} directive", parts[0]))
+                    })?;
+                    if parts[0] == "health_check_interval" {
+                        self.health_check_interval = duration;
+                    } else {
+                        self.health_check_timeout = duration;
+                    }
+                },
+                "health_check_rise" | "health_check_fall" => {
+                    let count = match parts.len() {
+                        2 => parts[1].parse::<u32>().ok().filter(|c| *c > 0),
+                        _ => None,
+                    };
+                    let count = count.ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidData, format!("Invalid {} directive", parts[0]))
+                    })?;
+                    if parts[0] == "health_check_rise" {
+                        self.health_check_rise = count;
+                    } else {
+                        self.health_check_fall = count;
+                    }
+                },
+                "health_check_path" => {
+                    if parts.len() != 2 || !parts[1].starts_with('/') {
+                        return Err(Error::new(ErrorKind::InvalidData, "Invalid health_check_path directive"));
+                    }
+                    self.health_check_path = parts[1].to_string();
+                },
+                "upstream_retries" => {
+                    if parts.len() != 2 {
+                        return Err(Error::new(ErrorKind::InvalidData, "Invalid upstream_retries directive"));
+                    }
+                    self.upstream_retries = parts[1].parse::<usize>()
+                        .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid upstream_retries directive"))?;
+                },
+                "client_max_body_size" => {
+                    self.client_max_body_size = match parts.get(1).and_then(|size| parse_size(size)) {
+                        Some(size) if parts.len() == 2 => size,
+                        _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid client_max_body_size directive")),
+                    };
+                },
+                "keepalive_timeout" => {
+                    self.keepalive_timeout = match parts.get(1).and_then(|value| parse_duration(value)) {
+                        Some(timeout) if parts.len() == 2 => timeout,
+                        _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid keepalive_timeout directive")),
+                    };
+                },
+                "keepalive_requests" => {
+                    self.keepalive_requests = match parts.get(1).and_then(|value| value.parse::<usize>().ok()) {
+                        Some(requests) if parts.len() == 2 && requests > 0 => requests,
+                        _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid keepalive_requests directive")),
+                    };
+                },
+                "upstream_keepalive" => {
+                    self.upstream_keepalive = match parts.get(1).and_then(|value| value.parse::<usize>().ok()) {
+                        Some(connections) if parts.len() == 2 => connections,
+                        _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid upstream_keepalive directive")),
+                    };
+                },
+                "upstream_keepalive_timeout" => {
+                    self.upstream_keepalive_timeout = match parts.get(1).and_then(|value| parse_duration(value)) {
+                        Some(timeout) if parts.len() == 2 => timeout,
+                        _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid upstream_keepalive_timeout directive")),
+                    };
+                },
+                "worker_threads" => {
+                    self.worker_threads = match parts.get(1).and_then(|value| value.parse::<usize>().ok()) {
+                        Some(threads) if parts.len() == 2 && threads > 0 => threads,
+                        _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid worker_threads directive")),
+                    };
+                },
+                "worker_queue" => {
+                    self.worker_queue = match parts.get(1).and_then(|value| value.parse::<usize>().ok()) {
+                        Some(size) if parts.len() == 2 && size > 0 => size,
+                        _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid worker_queue directive")),
+                    };
+                },
+                "worker_queue_full" => {
+                    self.worker_queue_full = match (parts.get(1).copied(), parts.len()) {
+                        (Some("503"), 2) => QueueFull::Reject,
+                        (Some("wait"), 2) => QueueFull::Wait,
+                        _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid worker_queue_full directive, expected 503 or wait")),
+                    };
+                },
+                "client_header_timeout" | "client_body_timeout" | "client_send_timeout"
+                | "upstream_connect_timeout" | "upstream_read_timeout" | "cgi_timeout" => {
+                    let timeout = match parts.get(1).and_then(|value| parse_duration(value)) {
+                        Some(timeout) if parts.len() == 2 && !timeout.is_zero() => timeout,
+                        _ => return Err(Error::new(ErrorKind::InvalidData, format!("Invalid {} directive", parts[0]))),
+                    };
+                    match parts[0] {
+                        "client_header_timeout" => self.client_header_timeout = timeout,
+                        "client_body_timeout" => self.client_body_timeout = timeout,
+                        "client_send_timeout" => self.client_send_timeout = timeout,
+                        "upstream_connect_timeout" => self.upstream_connect_timeout = timeout,
+                        "upstream_read_timeout" => self.upstream_read_timeout = timeout,
+                        _ => self.cgi_timeout = timeout,
+                    }
+                },
+                "shutdown_timeout" => {
+                    self.shutdown_timeout = match parts.get(1).and_then(|value| parse_duration(value)) {
+                        Some(timeout) if parts.len() == 2 => timeout,
+                        _ => return Err(Error::new(ErrorKind::InvalidData, "Invalid shutdown_timeout directive")),
+                    };
+                },
                 _ => {
                     return Err(Error::new(
                         ErrorKind::InvalidData,
@@ -130,8 +470,12 @@ impl Config {
     }
 
     // Helper methods to access the configuration
-    pub fn listen(&self) -> &str {
-        &self.listen
+    // Every listen line, or 127.0.0.1:3000 without any
+    pub fn listen(&self) -> Vec<Listen> {
+        if self.listen.is_empty() {
+            return vec![Listen::new(SocketAddr::from(([127, 0, 0, 1], 3000)))];
+        }
+        self.listen.clone()
     }
 
     pub fn index(&self) -> &str {
@@ -155,6 +499,10 @@ impl Config {
     }
 
     pub fn servers(&self) -> Vec<String> {
+        self.servers.iter().map(|s| s.address.clone()).collect()
+    }
+
+    pub fn upstreams(&self) -> Vec<UpstreamServer> {
         self.servers.clone()
     }
 
@@ -169,6 +517,104 @@ impl Config {
     pub fn allow_directories(&self) -> &[String] {
         &self.allow_directories
     }
+
+    // Zero disables active health checks
+    pub fn health_check_interval(&self) -> Duration {
+        self.health_check_interval
+    }
+
+    pub fn health_check_timeout(&self) -> Duration {
+        self.health_check_timeout
+    }
+
+    pub fn health_check_rise(&self) -> u32 {
+        self.health_check_rise
+    }
+
+    pub fn health_check_fall(&self) -> u32 {
+        self.health_check_fall
+    }
+
+    // Empty means a plain TCP connect is enough
+    pub fn health_check_path(&self) -> &str {
+        &self.health_check_path
+    }
+
+    // How many other servers a failed idempotent request may be retried on
+    pub fn upstream_retries(&self) -> usize {
+        self.upstream_retries
+    }
+
+    // Largest request body accepted, requests with bigger ones get 413. 0 lifts the limit.
+    pub fn client_max_body_size(&self) -> u64 {
+        self.client_max_body_size
+    }
+
+    // How long an idle client connection is kept open for its next request, 0 turns keep-alive off
+    pub fn keepalive_timeout(&self) -> Duration {
+        self.keepalive_timeout
+    }
+
+    // Requests served on one client connection before it gets closed
+    pub fn keepalive_requests(&self) -> usize {
+        self.keepalive_requests
+    }
+
+    // Idle connections kept open to each upstream server, 0 turns upstream keep-alive off
+    pub fn upstream_keepalive(&self) -> usize {
+        self.upstream_keepalive
+    }
+
+    pub fn upstream_keepalive_timeout(&self) -> Duration {
+        self.upstream_keepalive_timeout
+    }
+
+    // Threads serving requests, and how many complete requests may wait for one
+    pub fn worker_threads(&self) -> usize {
+        self.worker_threads
+    }
+
+    pub fn worker_queue(&self) -> usize {
+        self.worker_queue
+    }
+
+    pub fn worker_queue_full(&self) -> QueueFull {
+        self.worker_queue_full
+    }
+
+    // Time for the client to send the whole request head
+    pub fn client_header_timeout(&self) -> Duration {
+        self.client_header_timeout
+    }
+
+    // Time allowed between two reads of the request body
+    pub fn client_body_timeout(&self) -> Duration {
+        self.client_body_timeout
+    }
+
+    // Time allowed for a write to the client to make progress
+    pub fn client_send_timeout(&self) -> Duration {
+        self.client_send_timeout
+    }
+
+    pub fn upstream_connect_timeout(&self) -> Duration {
+        self.upstream_connect_timeout
+    }
+
+    // Time allowed between two reads from the upstream, sending the request to it is bound by it too
+    pub fn upstream_read_timeout(&self) -> Duration {
+        self.upstream_read_timeout
+    }
+
+    // Time a CGI script gets to run before it is killed and the client gets a 504
+    pub fn cgi_timeout(&self) -> Duration {
+        self.cgi_timeout
+    }
+
+    // How long requests in flight get to finish once shutdown starts
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
+    }
 }
 
 #[cfg(test)]
@@ -205,7 +651,7 @@ allow_directories assets/images assets/css
         let mut config = Config::new();
         config.parse(temp_file.path().to_str().unwrap())?;
 
-        assert_eq!(config.listen(), "3000");
+        assert_eq!(config.listen(), [Listen::new(tcp("0.0.0.0:3000"))]);
         assert_eq!(config.index(), "index.php");
         assert_eq!(config.pass(), "/usr/bin/php");
         assert_eq!(config.deny_files(), &["index.html", "other.html"]);
@@ -218,4 +664,183 @@ allow_directories assets/images assets/css
 
         Ok(())
     }
+
+    fn parse_str(content: &str) -> io::Result<Config> {
+        let temp_file = NamedTempFile::new()?;
+        write(temp_file.path(), content)?;
+        let mut config = Config::new();
+        config.parse(temp_file.path().to_str().unwrap())?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_weighted_servers() -> io::Result<()> {
+        let config = parse_str("servers 127.0.0.1:3001 weight=3 127.0.0.1:3002\nservers 127.0.0.1:3003 weight=2\n")?;
+
+        assert_eq!(config.servers(), &["127.0.0.1:3001", "127.0.0.1:3002", "127.0.0.1:3003"]);
+        let weights: Vec<u32> = config.upstreams().iter().map(|s| s.weight).collect();
+        assert_eq!(weights, [3, 1, 2]);
+
+        assert!(parse_str("servers weight=3 127.0.0.1:3001\n").is_err());
+        assert!(parse_str("servers 127.0.0.1:3001 weight=0\n").is_err());
+        assert!(parse_str("servers 127.0.0.1:3001 weight=abc\n").is_err());
+        assert!(parse_str("servers 127.0.0.1:3001 color=blue\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_passive_health_parameters() -> io::Result<()> {
+        let config = parse_str("servers 127.0.0.1:3001 max_fails=3 fail_timeout=30s 127.0.0.1:3002\nupstream_retries 2\n")?;
+        let upstreams = config.upstreams();
+
+        assert_eq!((upstreams[0].max_fails, upstreams[0].fail_timeout), (3, Duration::from_secs(30)));
+        assert_eq!((upstreams[1].max_fails, upstreams[1].fail_timeout), (1, Duration::from_secs(10)));
+        assert_eq!(config.upstream_retries(), 2);
+        assert_eq!(Config::new().upstream_retries(), 1);
+
+        assert!(parse_str("servers 127.0.0.1:3001 max_fails=-1\n").is_err());
+        assert!(parse_str("servers 127.0.0.1:3001 fail_timeout=forever\n").is_err());
+        assert!(parse_str("upstream_retries many\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_health_check_directives() -> io::Result<()> {
+        let config = parse_str("health_check_interval 5\nhealth_check_timeout 500ms\nhealth_check_rise 1\nhealth_check_fall 4\nhealth_check_path /health\n")?;
+
+        assert_eq!(config.health_check_interval(), Duration::from_secs(5));
+        assert_eq!(config.health_check_timeout(), Duration::from_millis(500));
+        assert_eq!(config.health_check_rise(), 1);
+        assert_eq!(config.health_check_fall(), 4);
+        assert_eq!(config.health_check_path(), "/health");
+
+        assert_eq!(Config::new().health_check_interval(), Duration::ZERO);
+        assert!(parse_str("health_check_interval soon\n").is_err());
+        assert!(parse_str("health_check_interval 400000000000000000m\n").is_err());
+        assert!(parse_str("health_check_rise 0\n").is_err());
+        assert!(parse_str("health_check_path health\n").is_err());
+
+        Ok(())
+    }
+
+    fn tcp(address: &str) -> ListenAddress {
+        ListenAddress::Tcp(address.parse().unwrap())
+    }
+
+    #[test]
+    fn test_listen_directives() -> io::Result<()> {
+        let config = parse_str("listen 8080\nlisten *:80\nlisten [::]:80 backlog=64 ipv6only=off\nlisten 10.0.0.1:81\nlisten unix:/run/servw.sock\n")?;
+        let listen = config.listen();
+        assert_eq!(listen.len(), 5);
+        assert_eq!(listen[0], Listen::new(tcp("0.0.0.0:8080")));
+        assert_eq!(listen[1].address, tcp("0.0.0.0:80"));
+        assert_eq!(listen[2].address, tcp("[::]:80"));
+        assert_eq!((listen[2].backlog, listen[2].ipv6only), (64, false));
+        assert_eq!(listen[3].address, tcp("10.0.0.1:81"));
+        assert_eq!(listen[4].address, ListenAddress::Unix(PathBuf::from("/run/servw.sock")));
+        assert_eq!(listen[4].address.to_string(), "unix:/run/servw.sock");
+        assert_eq!(Config::new().listen(), [Listen::new(tcp("127.0.0.1:3000"))]);
+
+        assert!(parse_str("listen localhost:80\n").is_err());
+        assert!(parse_str("listen 127.0.0.1\n").is_err());
+        assert!(parse_str("listen 70000\n").is_err());
+        assert!(parse_str("listen [::1]\n").is_err());
+        assert!(parse_str("listen unix:\n").is_err());
+        assert!(parse_str("listen 80\nlisten *:80\n").is_err());
+        assert!(parse_str("listen unix:/a.sock\nlisten unix:/a.sock\n").is_err());
+        assert!(parse_str("listen 80 backlog=0\n").is_err());
+        assert!(parse_str("listen 80 ipv6only=off\n").is_err());
+        assert!(parse_str("listen unix:/a.sock ipv6only=off\n").is_err());
+        assert!(parse_str("listen 80 ssl\n").is_err());
+        assert!(parse_str("listen 80 default_server\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2m"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("2h"), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("400000000000000000m"), None);
+    }
+
+    #[test]
+    fn test_client_max_body_size() -> io::Result<()> {
+        assert_eq!(parse_str("client_max_body_size 512k\n")?.client_max_body_size(), 512 * 1024);
+        assert_eq!(Config::new().client_max_body_size(), 16 * 1024 * 1024);
+        assert_eq!(parse_str("client_max_body_size 0\n")?.client_max_body_size(), 0);
+
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("8M"), Some(8 * 1024 * 1024));
+        assert_eq!(parse_size("1g"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_size("lots"), None);
+        assert_eq!(parse_size("99999999999999999g"), None);
+        assert!(parse_str("client_max_body_size 1t\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keepalive_directives() -> io::Result<()> {
+        let config = parse_str("keepalive_timeout 5s\nkeepalive_requests 10\n")?;
+        assert_eq!(config.keepalive_timeout(), Duration::from_secs(5));
+        assert_eq!(config.keepalive_requests(), 10);
+        assert_eq!(parse_str("keepalive_timeout 0\n")?.keepalive_timeout(), Duration::ZERO);
+
+        assert!(parse_str("keepalive_timeout never\n").is_err());
+        assert!(parse_str("keepalive_requests 0\n").is_err());
+
+        let config = parse_str("upstream_keepalive 0\nupstream_keepalive_timeout 10s\n")?;
+        assert_eq!(config.upstream_keepalive(), 0);
+        assert_eq!(config.upstream_keepalive_timeout(), Duration::from_secs(10));
+        assert_eq!(Config::new().upstream_keepalive(), 32);
+        assert!(parse_str("upstream_keepalive -1\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_worker_directives() -> io::Result<()> {
+        let config = parse_str("worker_threads 8\nworker_queue 16\nworker_queue_full wait\n")?;
+        assert_eq!(config.worker_threads(), 8);
+        assert_eq!(config.worker_queue(), 16);
+        assert_eq!(config.worker_queue_full(), QueueFull::Wait);
+        assert_eq!(parse_str("worker_queue_full 503\n")?.worker_queue_full(), QueueFull::Reject);
+
+        assert!(parse_str("worker_threads 0\n").is_err());
+        assert!(parse_str("worker_queue 0\n").is_err());
+        assert!(parse_str("worker_queue_full drop\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_timeout_directives() -> io::Result<()> {
+        let config = parse_str(
+            "client_header_timeout 10s\nclient_body_timeout 20s\nclient_send_timeout 30s\n\
+            upstream_connect_timeout 500ms\nupstream_read_timeout 2m\ncgi_timeout 5s\n",
+        )?;
+        assert_eq!(config.client_header_timeout(), Duration::from_secs(10));
+        assert_eq!(config.client_body_timeout(), Duration::from_secs(20));
+        assert_eq!(config.client_send_timeout(), Duration::from_secs(30));
+        assert_eq!(config.upstream_connect_timeout(), Duration::from_millis(500));
+        assert_eq!(config.upstream_read_timeout(), Duration::from_secs(120));
+        assert_eq!(config.cgi_timeout(), Duration::from_secs(5));
+        assert_eq!(Config::new().client_header_timeout(), Duration::from_secs(60));
+
+        assert!(parse_str("client_header_timeout 0\n").is_err());
+        assert!(parse_str("upstream_read_timeout soon\n").is_err());
+
+        assert_eq!(parse_str("shutdown_timeout 5s\n")?.shutdown_timeout(), Duration::from_secs(5));
+        assert_eq!(parse_str("shutdown_timeout 0\n")?.shutdown_timeout(), Duration::ZERO);
+        assert_eq!(Config::new().shutdown_timeout(), Duration::from_secs(30));
+
+        Ok(())
+    }
 }
@@ -0,0 +1,569 @@
+use crate::config::Config;
+use crate::handlers::{simple_response, Handler};
+use crate::http_validator::{HttpError, HttpRequest, HttpValidator, Limits, Status};
+use crate::request_body::RequestBody;
+use crate::socket::Stream;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// How client connections are read and kept open
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionSettings {
+    pub limits: Limits,
+    // Zero closes every connection after its first response
+    pub keepalive_timeout: Duration,
+    pub keepalive_requests: usize,
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub send_timeout: Duration,
+}
+
+impl ConnectionSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            limits: Limits::from_config(config),
+            keepalive_timeout: config.keepalive_timeout(),
+            keepalive_requests: config.keepalive_requests(),
+            header_timeout: config.client_header_timeout(),
+            body_timeout: config.client_body_timeout(),
+            send_timeout: config.client_send_timeout(),
+        }
+    }
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self::from_config(&Config::new())
+    }
+}
+
+// Sits between a handler and the client. Once the response head has been written it swaps
+// whatever Connection header the handler set for the connection's own, and works out whether
+// the response is delimited well enough for another one to follow it.
+pub struct ResponseWriter<W: Write> {
+    inner: W,
+    head: Vec<u8>,
+    head_done: bool,
+    keep_alive: bool,
+    // Checked when the head goes out, a slow handler may still learn the server is closing
+    closing: Option<Arc<AtomicBool>>,
+    http10: bool,
+    head_request: bool,
+}
+
+impl<W: Write> ResponseWriter<W> {
+    fn new(inner: W, request: Option<&HttpRequest>, keep_alive: bool) -> Self {
+        Self {
+            inner,
+            head: Vec::new(),
+            head_done: false,
+            keep_alive,
+            closing: None,
+            http10: request.is_some_and(|r| r.version() == "HTTP/1.0"),
+            head_request: request.is_some_and(|r| r.method() == "HEAD"),
+        }
+    }
+
+    // For the response to the `served`th request on a connection. It stays open for another
+    // one if the request and the settings allow it, and `closing` wasn't set by the time the
+    // head goes out.
+    pub fn for_request(inner: W, request: &HttpRequest, served: usize, settings: &ConnectionSettings, closing: &Arc<AtomicBool>) -> Self {
+        let keep_alive = request.keep_alive()
+            && !settings.keepalive_timeout.is_zero()
+            && served < settings.keepalive_requests;
+        let mut out = Self::new(inner, Some(request), keep_alive);
+        out.closing = Some(closing.clone());
+        out
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    fn write_head(&mut self, head: &[u8]) -> io::Result<()> {
+        // Header values are passed on byte for byte, they needn't be UTF-8
+        let mut lines = head.split(|&b| b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .filter(|line| !line.is_empty());
+        let status_line = lines.next().unwrap_or_default();
+        let status = status_line.split(|&b| b == b' ').nth(1).unwrap_or_default();
+
+        let mut fields = Vec::new();
+        let mut delimited = self.head_request || status.starts_with(b"1") || status == b"204" || status == b"304";
+        for line in lines {
+            let colon = line.iter().position(|&b| b == b':').unwrap_or(line.len());
+            let (name, value) = (&line[..colon], line.get(colon + 1..).unwrap_or_default());
+            if name.eq_ignore_ascii_case(b"connection") || name.eq_ignore_ascii_case(b"keep-alive") {
+                continue;
+            }
+            if name.eq_ignore_ascii_case(b"content-length")
+                || (name.eq_ignore_ascii_case(b"transfer-encoding") && value.trim_ascii().to_ascii_lowercase().ends_with(b"chunked")) {
+                delimited = true;
+            }
+            fields.extend_from_slice(line);
+            fields.extend_from_slice(b"\r\n");
+        }
+
+        // Without a length the client can only tell where the body ends by the connection closing
+        self.keep_alive &= delimited && !self.closing.as_ref().is_some_and(|c| c.load(Ordering::SeqCst));
+        if !self.keep_alive {
+            fields.extend_from_slice(b"Connection: close\r\n");
+        } else if self.http10 {
+            fields.extend_from_slice(b"Connection: keep-alive\r\n");
+        }
+
+        self.inner.write_all(status_line)?;
+        self.inner.write_all(b"\r\n")?;
+        self.inner.write_all(&fields)?;
+        self.inner.write_all(b"\r\n")
+    }
+
+    // Flushes the response, returns whether the connection can carry another one
+    pub fn finish(&mut self) -> io::Result<bool> {
+        if !self.head_done {
+            // Not a response we understand, pass it on and give up on the connection
+            let head = std::mem::take(&mut self.head);
+            self.inner.write_all(&head)?;
+            self.keep_alive = false;
+        }
+        self.inner.flush()?;
+        Ok(self.keep_alive)
+    }
+}
+
+impl<W: Write> Write for ResponseWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.head_done {
+            return self.inner.write(buf);
+        }
+
+        self.head.extend_from_slice(buf);
+        let end = self.head.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4)
+            .or_else(|| self.head.windows(2).position(|w| w == b"\n\n").map(|i| i + 2));
+        if let Some(end) = end {
+            let head = std::mem::take(&mut self.head);
+            self.head_done = true;
+            self.write_head(&head[..end])?;
+            self.inner.write_all(&head[end..])?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// What socket reads and writes fail with once their timeout expires
+pub fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    Head,
+    Body,
+}
+
+// How long the client has to send the next part of a request. An idle connection gets
+// client_header_timeout, or keepalive_timeout after a response, to start one. The whole head
+// then has to arrive within client_header_timeout, and the body may not stall for longer
+// than client_body_timeout between two reads.
+pub struct ReadTimer {
+    deadline: Instant,
+    phase: Phase,
+}
+
+impl ReadTimer {
+    pub fn idle(timeout: Duration) -> Self {
+        Self {
+            deadline: Instant::now() + timeout,
+            phase: Phase::Idle,
+        }
+    }
+
+    // Moves the deadline along with what the validator has seen so far
+    pub fn update(&mut self, validator: &HttpValidator, settings: &ConnectionSettings) {
+        if validator.reading_body() {
+            self.phase = Phase::Body;
+            self.deadline = Instant::now() + settings.body_timeout;
+        } else if self.phase == Phase::Idle && validator.in_progress() {
+            self.phase = Phase::Head;
+            self.deadline = Instant::now() + settings.header_timeout;
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    // What to answer once the deadline passed, nothing when no request was started
+    pub fn expired(&self) -> Option<HttpError> {
+        (self.phase != Phase::Idle).then_some(HttpError::RequestTimeout)
+    }
+}
+
+// Reads until a request is ready for its handler, see `Status::Complete`. None means the
+// client closed the connection, or left it idle for too long, before starting another one.
+fn read_request(
+    stream: &Stream,
+    validator: &mut HttpValidator,
+    timer: &mut ReadTimer,
+    settings: &ConnectionSettings,
+) -> Result<Option<HttpRequest>, HttpError> {
+    let mut buffer = [0; 16 * 1024];
+    let mut status = validator.feed(&[])?;
+    timer.update(validator, settings);
+
+    while status == Status::Incomplete {
+        let remaining = timer.deadline().saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return timer.expired().map_or(Ok(None), Err);
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        let n = match (&*stream).read(&mut buffer) {
+            Ok(n) => n,
+            // Checked against the deadline on the next round
+            Err(e) if is_timeout(&e) || e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if n == 0 {
+            if !validator.in_progress() {
+                return Ok(None);
+            }
+            return Err(HttpError::BadRequest("connection closed mid-request"));
+        }
+        status = validator.feed(&buffer[..n])?;
+        timer.update(validator, settings);
+    }
+
+    Ok(Some(validator.get_request()))
+}
+
+// Answers a request that failed to parse, the connection is closed afterwards
+pub fn reject(out: impl Write, error: &HttpError) -> io::Result<()> {
+    println!("Rejecting request: {}", error);
+    let mut out = ResponseWriter::new(out, None, false);
+    out.write_all(simple_response(error.status().unwrap_or(400)).as_bytes())?;
+    out.finish().map(|_| ())
+}
+
+// Has the handler answer the `served`th request on a connection, with the rest of its body
+// read from the client through `validator` as the handler asks for it. Returns whether the
+// connection stays open for another request, never once `closing` is set.
+pub fn respond(
+    stream: &Stream,
+    handler: &dyn Handler,
+    request: &HttpRequest,
+    validator: &mut HttpValidator,
+    served: usize,
+    settings: &ConnectionSettings,
+    closing: &Arc<AtomicBool>,
+) -> io::Result<bool> {
+    let mut out = ResponseWriter::for_request(stream, request, served, settings, closing);
+
+    // The body may not stall for longer than client_body_timeout between two reads
+    stream.set_read_timeout(Some(settings.body_timeout))?;
+    let mut source = stream;
+    let mut body = RequestBody::new(validator, Some(&mut source));
+    handler.handle(request, &mut body, &mut out)?;
+    let keep_alive = out.finish()?;
+    // Whatever the handler left of the body has to go before the next request can be read
+    if !body.finish() {
+        linger(stream);
+        return Ok(false);
+    }
+    Ok(keep_alive)
+}
+
+// How long a connection is drained before closing it on a client still sending
+const LINGER_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Closing a socket with unread data in it resets the connection, which can take the response
+// with it before the client got to read it. Our side is shut down first, and whatever the
+// client still sends is read and dropped for a moment, see RFC 9112 section 9.6.
+fn linger(stream: &Stream) {
+    let deadline = Instant::now() + LINGER_TIMEOUT;
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+    let mut buffer = [0; 16 * 1024];
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()).filter(|d| !d.is_zero()) {
+        if stream.set_read_timeout(Some(remaining)).is_err() {
+            return;
+        }
+        match (&*stream).read(&mut buffer) {
+            Ok(0) => return,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(_) => return,
+        }
+    }
+}
+
+// Serves requests off a client connection until either side wants it closed. Every request
+// is parsed and validated here once, malformed ones never reach a handler and get the
+// matching error response instead. Pipelined requests are answered one after the other,
+// in the order they came in.
+pub fn handle_connection(stream: &Stream, handler: &dyn Handler, settings: &ConnectionSettings) -> io::Result<()> {
+    let mut validator = HttpValidator::with_limits(settings.limits);
+    let (remote_addr, local_addr) = (stream.peer_addr(), stream.local_addr());
+    let mut timer = ReadTimer::idle(settings.header_timeout);
+    let mut served = 0;
+    stream.set_write_timeout(Some(settings.send_timeout))?;
+
+    loop {
+        let request = match read_request(stream, &mut validator, &mut timer, settings) {
+            Ok(Some(request)) => request.with_addresses(remote_addr, local_addr),
+            // Closed or left idle without sending anything
+            Ok(None) => return Ok(()),
+            Err(HttpError::Io(e)) => return Err(e),
+            Err(e) => return reject(stream, &e),
+        };
+        served += 1;
+
+        if !respond(stream, handler, &request, &mut validator, served, settings, &Arc::default())? {
+            return Ok(());
+        }
+        // Between requests the client gets keepalive_timeout to start the next one
+        timer = ReadTimer::idle(settings.keepalive_timeout);
+    }
+}
+
+// Answers a connection with an error without reading its request, it's closed afterwards
+pub fn refuse(out: impl Write, status: u16) -> io::Result<()> {
+    let mut out = ResponseWriter::new(out, None, false);
+    out.write_all(simple_response(status).as_bytes())?;
+    out.finish().map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+
+    // Answers with what it was handed, to show what made it through. The body of /skip is
+    // left unread.
+    struct Echo;
+
+    impl Handler for Echo {
+        fn handle(&self, request: &HttpRequest, body: &mut RequestBody, out: &mut dyn Write) -> io::Result<()> {
+            let mut length = 0;
+            if request.path() != "/skip" {
+                match io::copy(body, &mut io::sink()) {
+                    Ok(n) => length = n,
+                    Err(e) => return crate::request_body::reject_body(e, out),
+                }
+            }
+            let body = format!("{} {} {:?} {}", request.method(), request.path(), request.remote_addr().map(|a| a.ip()), length);
+            if request.path() == "/unframed" {
+                return write!(out, "HTTP/1.1 200 OK\r\nConnection: keep-alive\r\n\r\n{}", body);
+            }
+            write!(out, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        }
+    }
+
+    fn serve(settings: ConnectionSettings) -> (std::net::SocketAddr, std::thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(&stream.into(), &Echo, &settings).unwrap();
+        });
+        (addr, server)
+    }
+
+    // Sends the request, then reads until the server closes the connection
+    fn exchange_with(request: &[u8], settings: ConnectionSettings) -> String {
+        let (addr, server) = serve(settings);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+        response
+    }
+
+    fn exchange(request: &[u8]) -> String {
+        exchange_with(request, ConnectionSettings::default())
+    }
+
+    #[test]
+    fn test_valid_request_reaches_handler() {
+        let response = exchange(b"POST /form?a=1 HTTP/1.1\r\nHost: test\r\nContent-Length: 3\r\n\r\nabc");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("POST /form Some(127.0.0.1) 3"));
+        // The handler's own Connection header doesn't make it out
+        assert!(!response.contains("Connection"));
+    }
+
+    #[test]
+    fn test_malformed_requests_are_rejected() {
+        let response = exchange(b"GET / HTTP/1.1\r\nBad header\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        let response = exchange(b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        let response = exchange(b"POST / HTTP/1.1\r\nHost: test\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        // Cut off mid-request
+        let response = exchange(b"GET / HTTP/1.1\r\nHost: te");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let settings = ConnectionSettings { limits: Limits { max_body: 2, ..Limits::default() }, ..Default::default() };
+        let response = exchange_with(b"POST / HTTP/1.1\r\nHost: test\r\nContent-Length: 3\r\n\r\nabc", settings);
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    }
+
+    #[test]
+    fn test_silent_client() {
+        assert_eq!(exchange(b""), "");
+    }
+
+    #[test]
+    fn test_pipelined_requests_answered_in_order() {
+        let response = exchange(b"GET /a HTTP/1.1\r\nHost: t\r\n\r\nPOST /b HTTP/1.1\r\nHost: t\r\nContent-Length: 2\r\n\r\nhiGET /c HTTP/1.1\r\nHost: t\r\n\r\n");
+        let bodies: Vec<&str> = response.split("HTTP/1.1 200 OK").skip(1)
+            .map(|r| r.split("\r\n\r\n").nth(1).unwrap())
+            .collect();
+        assert_eq!(bodies, ["GET /a Some(127.0.0.1) 0", "POST /b Some(127.0.0.1) 2", "GET /c Some(127.0.0.1) 0"]);
+    }
+
+    #[test]
+    fn test_large_bodies_are_streamed() {
+        let mut request = format!("PUT /big HTTP/1.1\r\nHost: t\r\nContent-Length: {}\r\n\r\n", 1 << 20).into_bytes();
+        request.extend(vec![b'x'; 1 << 20]);
+        // Skipped over for the next request
+        request.extend(b"PUT /skip HTTP/1.1\r\nHost: t\r\nContent-Length: 100000\r\n\r\n");
+        request.extend(vec![b'y'; 100000]);
+        request.extend(b"GET /after HTTP/1.1\r\nHost: t\r\n\r\n");
+
+        let settings = ConnectionSettings { limits: Limits { max_body: u64::MAX, ..Limits::default() }, ..Default::default() };
+        let response = exchange_with(&request, settings);
+        let bodies: Vec<&str> = response.split("HTTP/1.1 200 OK").skip(1)
+            .map(|r| r.split("\r\n\r\n").nth(1).unwrap())
+            .collect();
+        assert_eq!(bodies, ["PUT /big Some(127.0.0.1) 1048576", "PUT /skip Some(127.0.0.1) 0", "GET /after Some(127.0.0.1) 0"]);
+    }
+
+    #[test]
+    fn test_body_limit_while_streaming() {
+        let mut request = b"PUT / HTTP/1.1\r\nHost: t\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        for _ in 0..3 {
+            request.extend(b"c350\r\n");
+            request.extend(vec![b'x'; 50000]);
+            request.extend(b"\r\n");
+        }
+        request.extend(b"0\r\n\r\n");
+
+        let settings = ConnectionSettings { limits: Limits { max_body: 100000, ..Limits::default() }, ..Default::default() };
+        let response = exchange_with(&request, settings);
+        assert!(response.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    }
+
+    #[test]
+    fn test_connection_close_is_honored() {
+        // The second request is never answered
+        let response = exchange(b"GET /a HTTP/1.1\r\nHost: t\r\nConnection: close\r\n\r\nGET /b HTTP/1.1\r\nHost: t\r\n\r\n");
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(response.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_http10_semantics() {
+        let response = exchange(b"GET /a HTTP/1.0\r\n\r\nGET /b HTTP/1.0\r\n\r\n");
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(response.contains("Connection: close\r\n"));
+
+        let response = exchange(b"GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /b HTTP/1.0\r\n\r\n");
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        assert_eq!(response.matches("Connection: keep-alive\r\n").count(), 1);
+    }
+
+    #[test]
+    fn test_unframed_response_closes_connection() {
+        let response = exchange(b"GET /unframed HTTP/1.1\r\nHost: t\r\n\r\nGET /b HTTP/1.1\r\nHost: t\r\n\r\n");
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(!response.contains("keep-alive"));
+    }
+
+    #[test]
+    fn test_keepalive_requests_limit() {
+        let settings = ConnectionSettings { keepalive_requests: 2, ..Default::default() };
+        let request = b"GET / HTTP/1.1\r\nHost: t\r\n\r\n".repeat(3);
+        let response = exchange_with(&request, settings);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        assert_eq!(response.matches("Connection: close\r\n").count(), 1);
+
+        let settings = ConnectionSettings { keepalive_timeout: Duration::ZERO, ..Default::default() };
+        let response = exchange_with(&request, settings);
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 1);
+    }
+
+    #[test]
+    fn test_idle_connection_times_out() {
+        let settings = ConnectionSettings { keepalive_timeout: Duration::from_millis(100), ..Default::default() };
+        let (addr, server) = serve(settings);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: t\r\n\r\n").unwrap();
+
+        // Without shutting down our side, the server gives up on us after the idle timeout
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_refuse() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        refuse(&stream, 503).unwrap();
+        drop(stream);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+    }
+
+    // Sends `request` and waits, without closing our side, for whatever the server answers
+    fn stall(request: &[u8], settings: ConnectionSettings) -> String {
+        let (addr, server) = serve(settings);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+        response
+    }
+
+    #[test]
+    fn test_slow_clients_time_out() {
+        let settings = ConnectionSettings {
+            header_timeout: Duration::from_millis(100),
+            body_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        assert!(stall(b"GET / HTTP/1.1\r\nHost:", settings).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        let body = b"POST / HTTP/1.1\r\nHost: t\r\nContent-Length: 10\r\n\r\nabc";
+        assert!(stall(body, settings).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        // Also once the handler is reading the body
+        let mut body = b"POST / HTTP/1.1\r\nHost: t\r\nContent-Length: 200000\r\n\r\n".to_vec();
+        body.extend(vec![b'x'; 100000]);
+        assert!(stall(&body, settings).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        // Nothing to answer when nothing was sent
+        assert_eq!(stall(b"", settings), "");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,1152 @@
+use crate::config::Config;
+use crate::connection::{self, ConnectionSettings, ReadTimer, ResponseWriter};
+use crate::handlers::{Exchange, ExchangeIo, Handler, Output, Progress};
+use crate::http_validator::{HttpError, HttpRequest, HttpValidator, Status};
+use crate::request_body::RequestBody;
+use crate::socket::{Listener, Stream};
+use crate::workers::{QueueFull, WorkerPool};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const WAKER: Token = Token(0);
+// Listeners take the tokens from here on, in the order they were given, clients the ones after
+const FIRST_LISTENER: usize = 1;
+// Most of a response left waiting for a slow client before its exchange stops producing more
+const OUTBOX_LIMIT: usize = 256 * 1024;
+
+// A client connection, parked on the event loop until a request is ready for its handler
+struct Client {
+    stream: Stream,
+    validator: HttpValidator,
+    served: usize,
+    timer: ReadTimer,
+}
+
+struct Job {
+    client: Client,
+    request: HttpRequest,
+}
+
+// Asks a running event loop to shut down, from any thread
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+// Deadlines of the connections on the loop, soonest first. Each token has one entry that
+// counts, a sooner deadline queues a new one while a later deadline waits for the current
+// entry to come due, so connections moving their deadline on every read don't pile up
+// entries. Entries of connections gone by then are dropped as they come due.
+#[derive(Default)]
+struct Timers {
+    queue: BinaryHeap<Reverse<(Instant, Token)>>,
+    queued: HashMap<Token, Instant>,
+}
+
+impl Timers {
+    fn schedule(&mut self, token: Token, deadline: Instant) {
+        if self.queued.get(&token).is_none_or(|&queued| deadline < queued) {
+            self.queued.insert(token, deadline);
+            self.queue.push(Reverse((deadline, token)));
+        }
+    }
+
+    fn next(&self) -> Option<Instant> {
+        self.queue.peek().map(|Reverse((deadline, _))| *deadline)
+    }
+
+    // Takes the tokens whose entry came due, their connections may have moved on since
+    fn due(&mut self, now: Instant) -> Vec<Token> {
+        let mut due = Vec::new();
+        while let Some(&Reverse((deadline, token))) = self.queue.peek() {
+            if deadline > now {
+                break;
+            }
+            self.queue.pop();
+            if self.queued.get(&token) == Some(&deadline) {
+                self.queued.remove(&token);
+                due.push(token);
+            }
+        }
+        due
+    }
+}
+
+// Response bytes waiting for the client socket to take them
+#[derive(Default)]
+struct Outbox {
+    data: Vec<u8>,
+    sent: usize,
+}
+
+impl Outbox {
+    fn is_empty(&self) -> bool {
+        self.sent == self.data.len()
+    }
+
+    // Writes whatever the socket takes without blocking, returns whether any of it went out
+    fn send(&mut self, stream: &Stream) -> io::Result<bool> {
+        let start = self.sent;
+        while !self.is_empty() {
+            match (&*stream).write(&self.data[self.sent..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.sent += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let progressed = self.sent > start;
+        if self.is_empty() || self.sent >= OUTBOX_LIMIT {
+            self.data.drain(..self.sent);
+            self.sent = 0;
+        }
+        Ok(progressed)
+    }
+}
+
+impl Write for Outbox {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output for ResponseWriter<Outbox> {
+    fn is_full(&self) -> bool {
+        self.get_ref().data.len() - self.get_ref().sent >= OUTBOX_LIMIT
+    }
+}
+
+// The client socket as an exchange reads the request body from it
+struct BodySource<'a> {
+    stream: &'a Stream,
+    received: &'a mut Instant,
+    // Past client_body_timeout, a client with nothing more to send is too slow
+    expired: bool,
+}
+
+impl Read for BodySource<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match (&*self.stream).read(buf) {
+            Ok(n) => {
+                *self.received = Instant::now();
+                Ok(n)
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock && self.expired => Err(io::ErrorKind::TimedOut.into()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// An error response on its way out, the connection is closed once the client took all of it
+struct Closing {
+    stream: Stream,
+    out: Outbox,
+    sent: Instant,
+}
+
+// A request answered on the loop itself, see `Handler::exchange`. The response collects in
+// an outbox and goes to the client as its socket takes it.
+struct Proxied {
+    client: Client,
+    // None once the response is complete
+    exchange: Option<Box<dyn Exchange>>,
+    out: ResponseWriter<Outbox>,
+    // The exchange's own socket goes under this token
+    upstream: Token,
+    waiting: Progress,
+    // When the client last sent some of the body, and last took some of the response
+    received: Instant,
+    sent: Instant,
+    body_expired: bool,
+    keep_alive: bool,
+}
+
+impl Proxied {
+    // Sends what the client takes and has the exchange go on as long as there's room for more
+    // of the response. True once all of it went out.
+    fn advance(&mut self, registry: &Registry) -> io::Result<bool> {
+        loop {
+            self.send()?;
+            let Some(exchange) = self.exchange.as_mut() else {
+                return Ok(self.out.get_ref().is_empty());
+            };
+            if self.out.is_full() {
+                self.waiting = Progress::Output;
+                return Ok(false);
+            }
+
+            let mut source = BodySource { stream: &self.client.stream, received: &mut self.received, expired: self.body_expired };
+            let mut body = RequestBody::nonblocking(&mut self.client.validator, Some(&mut source));
+            let mut io = ExchangeIo { registry, token: self.upstream, body: &mut body, out: &mut self.out };
+            self.waiting = exchange.advance(&mut io)?;
+            match self.waiting {
+                Progress::Done => {
+                    self.exchange = None;
+                    self.keep_alive = self.out.finish()?;
+                }
+                Progress::Output => {}
+                Progress::Upstream(_) | Progress::Body => {
+                    self.send()?;
+                    return Ok(false);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        // The client isn't kept waiting on while there's nothing for it
+        if self.out.get_mut().send(&self.client.stream)? || self.out.get_ref().is_empty() {
+            self.sent = Instant::now();
+        }
+        Ok(())
+    }
+
+    // The client gets client_body_timeout to send more of the body and client_send_timeout to
+    // take more of the response, the exchange sets its own deadline for its upstream
+    fn deadline(&self, settings: &ConnectionSettings) -> Instant {
+        let waiting = match self.waiting {
+            Progress::Upstream(deadline) => Some(deadline),
+            Progress::Body => Some(self.received + settings.body_timeout),
+            Progress::Output | Progress::Done => None,
+        };
+        waiting.into_iter().chain(self.send_deadline(settings)).min().unwrap_or_else(Instant::now)
+    }
+
+    fn send_deadline(&self, settings: &ConnectionSettings) -> Option<Instant> {
+        (!self.out.get_ref().is_empty()).then(|| self.sent + settings.send_timeout)
+    }
+}
+
+// Multiplexes every client connection on one thread. Idle keep-alive connections and
+// requests still coming in only cost a parked socket. Handlers that answer through an
+// exchange, the proxy, never take a worker: the loop drives the exchange along with its
+// upstream socket and writes the response as the client takes it, so long polls and slow
+// clients cost no more than their sockets. Other handlers, static files and scripts, run on
+// a worker once the head and the start of the body have been read. The worker writes the
+// response and reads bodies too large to wait for as its handler asks, and the connection
+// comes back to the loop after the response.
+pub struct EventLoop {
+    poll: Poll,
+    // Gone once shutting down
+    listeners: Vec<Listener>,
+    clients: HashMap<Token, Client>,
+    // Requests answered on the loop by their client's token, and the client's token for each
+    // of their upstream tokens
+    exchanges: HashMap<Token, Proxied>,
+    upstreams: HashMap<Token, Token>,
+    closing: HashMap<Token, Closing>,
+    timers: Timers,
+    next_token: usize,
+    settings: ConnectionSettings,
+    handler: Arc<dyn Handler>,
+    workers: WorkerPool<Job>,
+    // Requests waiting for room in a full worker queue, with `worker_queue_full wait`. Parked
+    // clients aren't read until they're all queued, the ones that sent something meanwhile
+    // are read then.
+    pending: VecDeque<Job>,
+    unread: Vec<Token>,
+    // Connections the workers are done with for now
+    returned: Receiver<Client>,
+    // Requests handed to workers and not answered yet
+    in_flight: Arc<AtomicUsize>,
+    shutdown: Shutdown,
+    grace_period: Duration,
+    // Set once shutting down, requests still running by then are abandoned
+    grace_deadline: Option<Instant>,
+}
+
+// Reads whatever the client sent so far, None until a request is ready for its handler
+fn read_request(client: &mut Client, settings: &ConnectionSettings) -> Result<Option<HttpRequest>, HttpError> {
+    let mut buffer = [0; 16 * 1024];
+    loop {
+        let n = match (&client.stream).read(&mut buffer) {
+            Ok(0) if !client.validator.in_progress() => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(0) => return Err(HttpError::BadRequest("connection closed mid-request")),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let status = client.validator.feed(&buffer[..n])?;
+        client.timer.update(&client.validator, settings);
+        if status == Status::Complete {
+            return Ok(Some(client.validator.get_request()));
+        }
+    }
+}
+
+// Runs on a worker. Answers the request, then any pipelined ones that already came in, and
+// hands the connection back if it stays open. Connections are closed after the response once
+// shutdown was requested.
+fn serve(job: Job, handler: &dyn Handler, settings: &ConnectionSettings, shutdown: &Shutdown) -> Option<Client> {
+    let Job { mut client, mut request } = job;
+    client.stream.set_nonblocking(false).ok()?;
+    let (remote_addr, local_addr) = (client.stream.peer_addr(), client.stream.local_addr());
+
+    loop {
+        client.served += 1;
+        let current = request.with_addresses(remote_addr, local_addr);
+        match connection::respond(&client.stream, handler, &current, &mut client.validator, client.served, settings, &shutdown.requested) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => {
+                println!("Connection error: {}", e);
+                return None;
+            }
+        }
+
+        request = match client.validator.feed(&[]) {
+            Ok(Status::Complete) => client.validator.get_request(),
+            Ok(Status::Incomplete) => break,
+            Err(e) => {
+                let _ = connection::reject(&client.stream, &e);
+                return None;
+            }
+        };
+    }
+
+    client.stream.set_nonblocking(true).ok()?;
+    // The next request may have started coming in already
+    client.timer = ReadTimer::idle(settings.keepalive_timeout);
+    client.timer.update(&client.validator, settings);
+    Some(client)
+}
+
+impl EventLoop {
+    pub fn new(listeners: Vec<Listener>, handler: Box<dyn Handler>, config: &Config) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        for (i, listener) in listeners.iter().enumerate() {
+            listener.set_nonblocking(true)?;
+            poll.registry().register(&mut SourceFd(&listener.as_raw_fd()), Token(FIRST_LISTENER + i), Interest::READABLE)?;
+        }
+
+        let shutdown = Shutdown {
+            requested: Arc::new(AtomicBool::new(false)),
+            waker: Arc::new(Waker::new(poll.registry(), WAKER)?),
+        };
+        let (sender, returned) = mpsc::channel();
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let settings = ConnectionSettings::from_config(config);
+        let handler: Arc<dyn Handler> = Arc::from(handler);
+        let workers = {
+            let handler = handler.clone();
+            let shutdown = shutdown.clone();
+            let in_flight = in_flight.clone();
+            WorkerPool::from_config(config, move |job: Job| {
+                if let Some(client) = serve(job, handler.as_ref(), &settings, &shutdown) {
+                    let _ = sender.send(client);
+                }
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                let _ = shutdown.waker.wake();
+            })
+        };
+
+        Ok(Self {
+            poll,
+            next_token: FIRST_LISTENER + listeners.len(),
+            listeners,
+            clients: HashMap::new(),
+            exchanges: HashMap::new(),
+            upstreams: HashMap::new(),
+            closing: HashMap::new(),
+            timers: Timers::default(),
+            settings,
+            handler,
+            workers,
+            pending: VecDeque::new(),
+            unread: Vec::new(),
+            returned,
+            in_flight,
+            shutdown,
+            grace_period: config.shutdown_timeout(),
+            grace_deadline: None,
+        })
+    }
+
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    // Serves clients until shutdown is requested and the requests in flight are done, or the
+    // grace period is over
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            let timeout = self.timers.next()
+                .into_iter()
+                .chain(self.grace_deadline)
+                .min()
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => {
+                        while let Ok(client) = self.returned.try_recv() {
+                            self.park(client);
+                        }
+                    }
+                    Token(i) if i >= FIRST_LISTENER && i < FIRST_LISTENER + self.listeners.len() => self.accept(i - FIRST_LISTENER),
+                    token if self.clients.contains_key(&token) => {
+                        if self.pending.is_empty() {
+                            self.read(token);
+                        } else {
+                            self.unread.push(token);
+                        }
+                    }
+                    token if self.closing.contains_key(&token) => self.flush(token),
+                    // An exchange's client or upstream
+                    token => self.step(self.upstreams.get(&token).copied().unwrap_or(token)),
+                }
+            }
+            self.submit_pending();
+            self.expire();
+
+            if self.shutdown.is_requested() && self.grace_deadline.is_none() {
+                self.drain();
+            }
+            if let Some(deadline) = self.grace_deadline {
+                let in_flight = self.in_flight.load(Ordering::SeqCst) + self.exchanges.len() + self.closing.len();
+                if in_flight == 0 && self.clients.is_empty() {
+                    println!("All requests finished");
+                    return Ok(());
+                }
+                if deadline <= Instant::now() {
+                    println!("Grace period over, abandoning {} requests", in_flight + self.clients.len());
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Stops accepting connections and closes idle ones. Requests that already started
+    // coming in are still read and answered.
+    fn drain(&mut self) {
+        println!("Shutting down, giving requests in flight {:?} to finish", self.grace_period);
+        self.grace_deadline = Some(Instant::now() + self.grace_period);
+        for listener in self.listeners.drain(..) {
+            let _ = self.poll.registry().deregister(&mut SourceFd(&listener.as_raw_fd()));
+        }
+
+        let idle: Vec<Token> = self.clients.iter()
+            .filter(|(_, client)| !client.validator.in_progress())
+            .map(|(token, _)| *token)
+            .collect();
+        for token in idle {
+            self.unpark(token);
+        }
+    }
+
+    fn accept(&mut self, index: usize) {
+        loop {
+            let accepted = match self.listeners.get(index) {
+                Some(listener) => listener.accept(),
+                None => return,
+            };
+            match accepted {
+                Ok(stream) => {
+                    if stream.set_nonblocking(true).is_ok() && stream.set_write_timeout(Some(self.settings.send_timeout)).is_ok() {
+                        self.park(Client {
+                            stream,
+                            validator: HttpValidator::with_limits(self.settings.limits),
+                            served: 0,
+                            timer: ReadTimer::idle(self.settings.header_timeout),
+                        });
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    println!("Accept error: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn token(&mut self) -> Token {
+        self.next_token += 1;
+        Token(self.next_token - 1)
+    }
+
+    fn park(&mut self, client: Client) {
+        // Answered and done with while shutting down
+        if self.grace_deadline.is_some() && !client.validator.in_progress() {
+            return;
+        }
+        let token = self.token();
+        // Registering reports the socket readable right away if something already came in
+        match self.poll.registry().register(&mut SourceFd(&client.stream.as_raw_fd()), token, Interest::READABLE) {
+            Ok(()) => {
+                self.timers.schedule(token, client.timer.deadline());
+                self.clients.insert(token, client);
+            }
+            Err(e) => println!("Connection error: {}", e),
+        }
+    }
+
+    fn unpark(&mut self, token: Token) -> Option<Client> {
+        let client = self.clients.remove(&token)?;
+        let _ = self.poll.registry().deregister(&mut SourceFd(&client.stream.as_raw_fd()));
+        Some(client)
+    }
+
+    fn read(&mut self, token: Token) {
+        let Some(client) = self.clients.get_mut(&token) else {
+            return;
+        };
+
+        match read_request(client, &self.settings) {
+            Ok(None) => self.timers.schedule(token, client.timer.deadline()),
+            Ok(Some(request)) => {
+                if let Some(client) = self.unpark(token) {
+                    self.dispatch(client, request);
+                }
+            }
+            // Closed or reset, nothing to answer
+            Err(HttpError::Io(_)) => {
+                self.unpark(token);
+            }
+            Err(e) => {
+                if let Some(client) = self.unpark(token) {
+                    self.reject(client.stream, &e);
+                }
+            }
+        }
+    }
+
+    fn dispatch(&mut self, client: Client, request: HttpRequest) {
+        let request = request.with_addresses(client.stream.peer_addr(), client.stream.local_addr());
+        if let Some(exchange) = self.handler.exchange(&request) {
+            self.start(client, &request, exchange);
+            return;
+        }
+
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let job = Job { client, request };
+        // Waiting requests go first
+        if !self.pending.is_empty() {
+            self.pending.push_back(job);
+            return;
+        }
+        // Blocking here would hold up every connection on the loop, not just new requests
+        if let Err(job) = self.workers.try_submit(job) {
+            if self.workers.when_full() == QueueFull::Wait {
+                self.pending.push_back(job);
+                return;
+            }
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            println!("Worker queue full, refusing request");
+            let mut out = Outbox::default();
+            let _ = connection::refuse(&mut out, 503);
+            self.close_after(job.client.stream, out);
+        }
+    }
+
+    // Queues waiting requests as workers free up spots, then reads what the clients sent
+    // meanwhile
+    fn submit_pending(&mut self) {
+        while let Some(job) = self.pending.pop_front() {
+            if let Err(job) = self.workers.try_submit(job) {
+                self.pending.push_front(job);
+                return;
+            }
+        }
+        for token in std::mem::take(&mut self.unread) {
+            self.read(token);
+        }
+    }
+
+    fn reject(&mut self, stream: Stream, error: &HttpError) {
+        let mut out = Outbox::default();
+        let _ = connection::reject(&mut out, error);
+        self.close_after(stream, out);
+    }
+
+    // Sends `out` as the client takes it, then closes the connection
+    fn close_after(&mut self, stream: Stream, out: Outbox) {
+        let token = self.token();
+        if let Err(e) = self.poll.registry().register(&mut SourceFd(&stream.as_raw_fd()), token, Interest::WRITABLE) {
+            println!("Connection error: {}", e);
+            return;
+        }
+        self.closing.insert(token, Closing { stream, out, sent: Instant::now() });
+        self.flush(token);
+    }
+
+    fn flush(&mut self, token: Token) {
+        let Some(closing) = self.closing.get_mut(&token) else {
+            return;
+        };
+        match closing.out.send(&closing.stream) {
+            Ok(_) if closing.out.is_empty() => {
+                let _ = closing.stream.shutdown(std::net::Shutdown::Write);
+            }
+            Ok(progressed) => {
+                if progressed {
+                    closing.sent = Instant::now();
+                }
+                self.timers.schedule(token, closing.sent + self.settings.send_timeout);
+                return;
+            }
+            Err(_) => {}
+        }
+        self.remove_closing(token);
+    }
+
+    fn remove_closing(&mut self, token: Token) {
+        if let Some(closing) = self.closing.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut SourceFd(&closing.stream.as_raw_fd()));
+        }
+    }
+
+    // Answers the request on the loop, the client socket is watched for the body and for room
+    // to write the response
+    fn start(&mut self, mut client: Client, request: &HttpRequest, exchange: Box<dyn Exchange>) {
+        let token = self.token();
+        let upstream = self.token();
+        let interest = Interest::READABLE | Interest::WRITABLE;
+        if let Err(e) = self.poll.registry().register(&mut SourceFd(&client.stream.as_raw_fd()), token, interest) {
+            println!("Connection error: {}", e);
+            return;
+        }
+
+        client.served += 1;
+        let out = ResponseWriter::for_request(Outbox::default(), request, client.served, &self.settings, &self.shutdown.requested);
+        let now = Instant::now();
+        self.exchanges.insert(token, Proxied {
+            client,
+            exchange: Some(exchange),
+            out,
+            upstream,
+            waiting: Progress::Body,
+            received: now,
+            sent: now,
+            body_expired: false,
+            keep_alive: false,
+        });
+        self.upstreams.insert(upstream, token);
+        self.step(token);
+    }
+
+    fn step(&mut self, token: Token) {
+        let Some(proxied) = self.exchanges.get_mut(&token) else {
+            return;
+        };
+        match proxied.advance(self.poll.registry()) {
+            Ok(false) => self.timers.schedule(token, proxied.deadline(&self.settings)),
+            Ok(true) => self.finish(token),
+            Err(e) => {
+                println!("Connection error: {}", e);
+                self.remove_exchange(token);
+            }
+        }
+    }
+
+    // Takes an exchange off the loop, dropping it closes its upstream connection
+    fn remove_exchange(&mut self, token: Token) -> Option<Proxied> {
+        let proxied = self.exchanges.remove(&token)?;
+        self.upstreams.remove(&proxied.upstream);
+        let _ = self.poll.registry().deregister(&mut SourceFd(&proxied.client.stream.as_raw_fd()));
+        Some(proxied)
+    }
+
+    // The response went out, the connection goes back to waiting for the next request
+    fn finish(&mut self, token: Token) {
+        let Some(Proxied { mut client, keep_alive, .. }) = self.remove_exchange(token) else {
+            return;
+        };
+        // Whatever the exchange left of the body has to go before the next request can be
+        // read. Only what already came in is dropped, waiting for the rest would hold up the
+        // loop, so the connection is closed instead.
+        let mut source = &client.stream;
+        if !RequestBody::nonblocking(&mut client.validator, Some(&mut source)).finish() {
+            let _ = client.stream.shutdown(std::net::Shutdown::Write);
+            return;
+        }
+        if !keep_alive {
+            return;
+        }
+
+        client.timer = ReadTimer::idle(self.settings.keepalive_timeout);
+        client.timer.update(&client.validator, &self.settings);
+        match client.validator.feed(&[]) {
+            Ok(Status::Complete) => {
+                let request = client.validator.get_request();
+                self.dispatch(client, request);
+            }
+            Ok(Status::Incomplete) => self.park(client),
+            Err(e) => self.reject(client.stream, &e),
+        }
+    }
+
+    // Closes connections that sat idle for too long, answers 408 to ones that were too slow
+    // sending their request, and lets exchanges know their time is up
+    fn expire(&mut self) {
+        let now = Instant::now();
+        for token in self.timers.due(now) {
+            if let Some(closing) = self.closing.get(&token) {
+                let deadline = closing.sent + self.settings.send_timeout;
+                if deadline > now {
+                    self.timers.schedule(token, deadline);
+                } else {
+                    self.remove_closing(token);
+                }
+                continue;
+            }
+            let Some(client) = self.clients.get(&token) else {
+                self.expire_exchange(token, now);
+                continue;
+            };
+            // Moved on since it was queued
+            if client.timer.deadline() > now {
+                self.timers.schedule(token, client.timer.deadline());
+                continue;
+            }
+            let Some(client) = self.unpark(token) else {
+                continue;
+            };
+            if let Some(e) = client.timer.expired() {
+                self.reject(client.stream, &e);
+            }
+        }
+    }
+
+    fn expire_exchange(&mut self, token: Token, now: Instant) {
+        let Some(proxied) = self.exchanges.get_mut(&token) else {
+            return;
+        };
+        let deadline = proxied.deadline(&self.settings);
+        if deadline > now {
+            self.timers.schedule(token, deadline);
+            return;
+        }
+        if proxied.send_deadline(&self.settings).is_some_and(|deadline| deadline <= now) {
+            println!("Connection error: client stopped taking the response");
+            self.remove_exchange(token);
+            return;
+        }
+        // The exchange answers 408 once reading the body fails, and times out its upstream itself
+        proxied.body_expired = proxied.waiting == Progress::Body;
+        self.step(token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::ServerHandler;
+    use crate::health::HealthRegistry;
+    use crate::lbs::RoundRobin;
+    use crate::request_body::RequestBody;
+    use crate::upstream_pool::UpstreamPool;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::time::Duration;
+    use tempfile::{NamedTempFile, TempDir};
+
+    // Answers with the path, slowly for /slow
+    struct Paths;
+
+    impl Handler for Paths {
+        fn handle(&self, request: &HttpRequest, _body: &mut RequestBody, out: &mut dyn Write) -> io::Result<()> {
+            if request.path() == "/slow" {
+                std::thread::sleep(Duration::from_millis(300));
+            }
+            write!(out, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", request.path().len(), request.path())
+        }
+    }
+
+    fn config(directives: &str) -> Config {
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), directives).unwrap();
+        let mut config = Config::new();
+        config.parse(file.path().to_str().unwrap()).unwrap();
+        config
+    }
+
+    // Runs an event loop, the thread ends when run() returns
+    fn launch(directives: &str) -> (SocketAddr, Shutdown, std::thread::JoinHandle<io::Result<()>>) {
+        launch_with(Box::new(Paths), &config(directives))
+    }
+
+    fn launch_with(handler: Box<dyn Handler>, config: &Config) -> (SocketAddr, Shutdown, std::thread::JoinHandle<io::Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut event_loop = EventLoop::new(vec![listener.into()], handler, config).unwrap();
+        let shutdown = event_loop.shutdown_handle();
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = sender.send(event_loop.run());
+        });
+        let finished = std::thread::spawn(move || receiver.recv().unwrap());
+        (addr, shutdown, finished)
+    }
+
+    fn start(directives: &str) -> SocketAddr {
+        launch(directives).0
+    }
+
+    // Proxies to `upstream` with the given directives
+    fn start_proxy(upstream: &str, directives: &str) -> SocketAddr {
+        let config = config(&format!("servers {}\n{}", upstream, directives));
+        let lb = Box::new(RoundRobin::new(config.servers()));
+        let health = Arc::new(HealthRegistry::from_config(&config));
+        let pool = Arc::new(UpstreamPool::from_config(&config));
+        let handler = ServerHandler::new(config.clone(), Arc::new(std::sync::Mutex::new(lb)), health, pool);
+        launch_with(Box::new(handler), &config).0
+    }
+
+    // Answers each request after `delay`, on a thread of its own, with `size` bytes or with the
+    // length of the request body when `size` is 0
+    fn upstream(delay: Duration, size: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(&stream);
+                    let mut length = 0;
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                        if let Some(value) = line.strip_prefix("Content-Length: ") {
+                            length = value.trim().parse().unwrap();
+                        }
+                        line.clear();
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+                    std::thread::sleep(delay);
+                    let body = if size > 0 { "x".repeat(size) } else { length.to_string() };
+                    let head = format!("HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n", body.len());
+                    let _ = (&stream).write_all((head + &body).as_bytes());
+                });
+            }
+        });
+        address
+    }
+
+    // Reads one response with a Content-Length, or until the server closes the connection
+    fn read_response(stream: &mut impl Read) -> String {
+        let mut response = Vec::new();
+        let mut byte = [0; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte).unwrap() == 0 {
+                return String::from_utf8(response).unwrap();
+            }
+            response.push(byte[0]);
+        }
+        let head = String::from_utf8(response.clone()).unwrap();
+        let length: usize = head.lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .map_or(0, |length| length.parse().unwrap());
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        head + &String::from_utf8(body).unwrap()
+    }
+
+    fn request(path: &str) -> Vec<u8> {
+        format!("GET {} HTTP/1.1\r\nHost: a\r\n\r\n", path).into_bytes()
+    }
+
+    #[test]
+    fn test_keep_alive_and_pipelining() {
+        let addr = start("");
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&request("/one")).unwrap();
+        assert!(read_response(&mut stream).ends_with("\r\n\r\n/one"));
+
+        // Split across writes, then two at once
+        let second = request("/two");
+        stream.write_all(&second[..10]).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        stream.write_all(&second[10..]).unwrap();
+        assert!(read_response(&mut stream).ends_with("/two"));
+
+        stream.write_all(&[request("/three"), request("/four")].concat()).unwrap();
+        assert!(read_response(&mut stream).ends_with("/three"));
+        assert!(read_response(&mut stream).ends_with("/four"));
+    }
+
+    #[test]
+    fn test_malformed_request() {
+        let addr = start("");
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_idle_connections_do_not_take_workers() {
+        let addr = start("worker_threads 1\n");
+        // Idle, half-sent and kept-alive connections
+        let mut idle: Vec<TcpStream> = (0..20).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for stream in idle.iter_mut().take(10) {
+            stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        }
+        let mut kept = TcpStream::connect(addr).unwrap();
+        kept.write_all(&request("/kept")).unwrap();
+        assert!(read_response(&mut kept).ends_with("/kept"));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&request("/busy")).unwrap();
+        assert!(read_response(&mut stream).ends_with("/busy"));
+    }
+
+    #[test]
+    fn test_queue_full() {
+        let addr = start("worker_threads 1\nworker_queue 1\n");
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.write_all(&request("/slow")).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        let mut queued = TcpStream::connect(addr).unwrap();
+        queued.write_all(&request("/queued")).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let mut refused = TcpStream::connect(addr).unwrap();
+        refused.write_all(&request("/refused")).unwrap();
+        assert!(read_response(&mut refused).starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        assert!(read_response(&mut slow).ends_with("/slow"));
+        assert!(read_response(&mut queued).ends_with("/queued"));
+    }
+
+    #[test]
+    fn test_queue_full_wait_keeps_loop_running() {
+        let addr = start("worker_threads 1\nworker_queue 1\nworker_queue_full wait\nclient_header_timeout 100ms\n");
+        let started = Instant::now();
+        let mut streams: Vec<TcpStream> = ["/slow", "/queued", "/waiting"].iter().map(|path| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&request(path)).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+            stream
+        }).collect();
+
+        // Timers still run while the queue is full
+        let mut silent = TcpStream::connect(addr).unwrap();
+        assert_eq!(silent.read(&mut [0; 1]).unwrap(), 0);
+        assert!(started.elapsed() < Duration::from_millis(280));
+
+        for (stream, path) in streams.iter_mut().zip(["/slow", "/queued", "/waiting"]) {
+            assert!(read_response(stream).ends_with(path));
+        }
+    }
+
+    #[test]
+    fn test_idle_connection_times_out() {
+        let addr = start("keepalive_timeout 100ms\n");
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&request("/")).unwrap();
+        read_response(&mut stream);
+
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let started = Instant::now();
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_slowloris() {
+        let addr = start("client_header_timeout 100ms\nclient_body_timeout 100ms\n");
+        let mut head = TcpStream::connect(addr).unwrap();
+        head.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        let mut body = TcpStream::connect(addr).unwrap();
+        body.write_all(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\n\r\nab").unwrap();
+        let silent = TcpStream::connect(addr).unwrap();
+
+        assert!(read_response(&mut head).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(read_response(&mut body).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert_eq!(read_response(&mut { silent }), "");
+    }
+
+    #[test]
+    fn test_shutdown_lets_requests_in_flight_finish() {
+        let (addr, shutdown, finished) = launch("");
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(&request("/")).unwrap();
+        read_response(&mut idle);
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.write_all(&request("/slow")).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        shutdown.request();
+        let response = read_response(&mut slow);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Connection: close\r\n"));
+        assert!(response.ends_with("/slow"));
+        // Idle connections are closed and new ones refused
+        assert_eq!(read_response(&mut idle), "");
+        finished.join().unwrap().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_grace_period() {
+        let (addr, shutdown, finished) = launch("shutdown_timeout 100ms\n");
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.write_all(&request("/slow")).unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        shutdown.request();
+        // run() returns before the slow request is done
+        while !finished.is_finished() {
+            assert!(started.elapsed() < Duration::from_millis(250));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        finished.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_multiple_listeners() {
+        let listeners: Vec<TcpListener> = (0..2).map(|_| TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        let addrs: Vec<SocketAddr> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        let listeners = listeners.into_iter().map(Listener::from).collect();
+        let mut event_loop = EventLoop::new(listeners, Box::new(Paths), &Config::new()).unwrap();
+        let shutdown = event_loop.shutdown_handle();
+        let running = std::thread::spawn(move || event_loop.run());
+
+        for (i, addr) in addrs.iter().enumerate() {
+            let path = format!("/{}", i);
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&request(&path)).unwrap();
+            assert!(read_response(&mut stream).ends_with(&path));
+        }
+
+        // Shutting down closes every one of them
+        shutdown.request();
+        running.join().unwrap().unwrap();
+        assert!(addrs.iter().all(|addr| TcpStream::connect(addr).is_err()));
+    }
+
+    #[test]
+    fn test_unix_listener() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("servw.sock");
+        let listeners = vec![UnixListener::bind(&path).unwrap().into(), TcpListener::bind("127.0.0.1:0").unwrap().into()];
+        let mut event_loop = EventLoop::new(listeners, Box::new(Paths), &Config::new()).unwrap();
+        let shutdown = event_loop.shutdown_handle();
+        let running = std::thread::spawn(move || event_loop.run());
+
+        // Kept alive like any other connection
+        let mut stream = UnixStream::connect(&path).unwrap();
+        for path in ["/a", "/b"] {
+            stream.write_all(&request(path)).unwrap();
+            assert!(read_response(&mut stream).ends_with(path));
+        }
+
+        shutdown.request();
+        running.join().unwrap().unwrap();
+        assert!(UnixStream::connect(&path).is_err());
+    }
+
+    #[test]
+    fn test_timers() {
+        let mut timers = Timers::default();
+        let now = Instant::now();
+        let (a, b) = (Token(1), Token(2));
+        timers.schedule(a, now + Duration::from_secs(2));
+        timers.schedule(b, now + Duration::from_secs(3));
+        // Later deadlines wait for the queued entry, sooner ones replace it
+        timers.schedule(a, now + Duration::from_secs(5));
+        timers.schedule(b, now + Duration::from_secs(1));
+        assert_eq!(timers.next(), Some(now + Duration::from_secs(1)));
+
+        assert!(timers.due(now).is_empty());
+        assert_eq!(timers.due(now + Duration::from_secs(2)), vec![b, a]);
+        // The replaced entry of b is dropped as it comes due
+        assert!(timers.due(now + Duration::from_secs(10)).is_empty());
+        assert_eq!(timers.next(), None);
+    }
+
+    #[test]
+    fn test_proxied_requests_do_not_take_workers() {
+        let addr = start_proxy(&upstream(Duration::from_millis(300), 2), "worker_threads 1\n");
+        let started = Instant::now();
+        let mut streams: Vec<TcpStream> = (0..4).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for stream in streams.iter_mut() {
+            stream.write_all(&request("/")).unwrap();
+        }
+        for stream in streams.iter_mut() {
+            assert!(read_response(stream).ends_with("\r\n\r\nxx"));
+        }
+        // One after the other on the single worker they'd take 1.2s
+        assert!(started.elapsed() < Duration::from_millis(900));
+    }
+
+    #[test]
+    fn test_proxied_body_sent_in_pieces() {
+        let addr = start_proxy(&upstream(Duration::ZERO, 0), "");
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let body = vec![b'a'; 100_000];
+        stream.write_all(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 100000\r\n\r\n").unwrap();
+        for piece in body.chunks(30_000) {
+            stream.write_all(piece).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(read_response(&mut stream).ends_with("\r\n\r\n100000"));
+
+        // Kept alive for the next one
+        stream.write_all(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\nabc").unwrap();
+        assert!(read_response(&mut stream).ends_with("\r\n\r\n3"));
+    }
+
+    #[test]
+    fn test_proxied_response_waits_for_slow_client() {
+        let size = 4 * 1024 * 1024;
+        let addr = start_proxy(&upstream(Duration::ZERO, size), "");
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&request("/")).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        let response = read_response(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(response.split("\r\n\r\n").nth(1).unwrap().len(), size);
+    }
+
+    #[test]
+    fn test_proxied_client_too_slow_sending_body() {
+        let addr = start_proxy(&upstream(Duration::ZERO, 0), "client_body_timeout 100ms\n");
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 100000\r\n\r\n").unwrap();
+        stream.write_all(&[b'a'; 40_000]).unwrap();
+        let started = Instant::now();
+        assert!(read_response(&mut stream).starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -1,29 +1,643 @@
-use crate::handlers::Handler;
-use std::net::TcpStream;
+use crate::chunked::ChunkedEncoder;
+use crate::connection::is_timeout;
+use crate::handlers::{reason_phrase, simple_response, Handler};
+use crate::http_validator::{Headers, HttpRequest};
+use crate::request_body::{reject_body, RequestBody, SpooledBody};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Error, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+// Script output held back before any of it goes to the client. Responses that fit are sent
+// with a Content-Length, longer ones as the script produces them. The header block has to fit.
+const CGI_BUFFER: usize = 64 * 1024;
 
 pub struct CgiHandler {
     config: crate::config::Config
 }
 
+// Where a request path landed inside the document root
+#[derive(Debug, PartialEq)]
+pub struct ScriptTarget {
+    pub script_name: String,
+    pub script_filename: PathBuf,
+    pub path_info: String,
+}
+
+// Maps a request path onto a file under root. The first path segment that names a
+// regular file is the script, anything after it becomes PATH_INFO. Directory paths
+// resolve to the configured index file.
+pub fn resolve_script(root: &str, index: &str, path: &str) -> Option<ScriptTarget> {
+    let decoded = percent_decode(path)?;
+    let segments: Vec<&str> = decoded.split('/').filter(|s| !s.is_empty()).collect();
+    if segments.contains(&"..") {
+        return None;
+    }
+
+    let mut filename = PathBuf::from(root);
+    let mut script_name = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        filename.push(segment);
+        script_name.push('/');
+        script_name.push_str(segment);
+
+        if filename.is_file() {
+            let path_info = segments[i + 1..].iter().map(|s| format!("/{}", s)).collect::<String>();
+            return Some(ScriptTarget { script_name, script_filename: filename, path_info });
+        }
+        if !filename.is_dir() {
+            return None;
+        }
+    }
+
+    filename.push(index);
+    if !filename.is_file() {
+        return None;
+    }
+    script_name.push('/');
+    script_name.push_str(index);
+    Some(ScriptTarget { script_name, script_filename: filename, path_info: String::new() })
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Builds the RFC 3875 meta-variables for a request with a body of `content_length` bytes
+pub fn cgi_env(config: &crate::config::Config, request: &HttpRequest, target: &ScriptTarget, content_length: u64) -> Vec<(String, OsString)> {
+    let mut env: Vec<(String, OsString)> = vec![
+        ("GATEWAY_INTERFACE".to_string(), "CGI/1.1".into()),
+        ("SERVER_SOFTWARE".to_string(), "servw".into()),
+        ("SERVER_PROTOCOL".to_string(), request.version().into()),
+        ("REQUEST_METHOD".to_string(), request.method().into()),
+        ("REQUEST_URI".to_string(), request.target().into()),
+        ("QUERY_STRING".to_string(), request.query().unwrap_or_default().into()),
+        ("DOCUMENT_ROOT".to_string(), config.root().into()),
+        ("SCRIPT_NAME".to_string(), target.script_name.clone().into()),
+        ("SCRIPT_FILENAME".to_string(), target.script_filename.clone().into()),
+        ("PATH_INFO".to_string(), target.path_info.clone().into()),
+        ("CONTENT_LENGTH".to_string(), content_length.to_string().into()),
+        // php-cgi refuses to run without this when force-cgi-redirect is on
+        ("REDIRECT_STATUS".to_string(), "200".into()),
+    ];
+
+    if !target.path_info.is_empty() {
+        let translated = Path::new(config.root()).join(target.path_info.trim_start_matches('/'));
+        env.push(("PATH_TRANSLATED".to_string(), translated.into()));
+    }
+    if let Some(content_type) = request.headers().get_raw("content-type") {
+        env.push(("CONTENT_TYPE".to_string(), OsStr::from_bytes(content_type).into()));
+    }
+    if let Some(addr) = request.remote_addr() {
+        env.push(("REMOTE_ADDR".to_string(), addr.ip().to_string().into()));
+        env.push(("REMOTE_PORT".to_string(), addr.port().to_string().into()));
+    }
+    if let Some(addr) = request.local_addr() {
+        env.push(("SERVER_ADDR".to_string(), addr.ip().to_string().into()));
+        env.push(("SERVER_PORT".to_string(), addr.port().to_string().into()));
+    }
+    let server_name = request.header("host")
+        .map(|host| host.rsplit_once(':').map_or(host, |(name, _)| name).to_string())
+        .or_else(|| request.local_addr().map(|addr| addr.ip().to_string()))
+        .unwrap_or_default();
+    env.push(("SERVER_NAME".to_string(), server_name.into()));
+
+    for (name, value) in request.headers().iter() {
+        // X_Foo would end up as the same variable as X-Foo, letting one pass for the other
+        if name.contains('_') {
+            continue;
+        }
+        let name = name.to_uppercase().replace('-', "_");
+        // These already have their own meta-variables. HTTP_PROXY is taken by scripts as the
+        // proxy to use for their own requests (httpoxy, CVE-2016-5385).
+        if name == "CONTENT_LENGTH" || name == "CONTENT_TYPE" || name == "PROXY" {
+            continue;
+        }
+        let key = format!("HTTP_{}", name);
+        // Passed on byte for byte, header values needn't be UTF-8
+        let value = OsStr::from_bytes(value);
+        match env.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, existing)) => {
+                existing.push(", ");
+                existing.push(value);
+            }
+            None => env.push((key, value.to_owned())),
+        }
+    }
+
+    env
+}
+
+// Turns the script's `Status:`/header/body output into an HTTP response
+pub fn cgi_output_to_response(output: &[u8]) -> Option<Vec<u8>> {
+    let (head, body) = split_cgi_output(output)?;
+    let (status, headers) = cgi_head(head)?;
+    let mut response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: {}\r\n\r\n",
+        status,
+        headers,
+        body.len()
+    ).into_bytes();
+    response.extend_from_slice(body);
+    Some(response)
+}
+
+// The status and header fields of the script's header block, without the framing headers
+fn cgi_head(head: &[u8]) -> Option<(String, String)> {
+    let head = std::str::from_utf8(head).ok()?;
+
+    let mut status: Option<String> = None;
+    let mut has_location = false;
+    let mut headers = String::new();
+    for line in head.lines() {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("status") {
+            status = Some(value.to_string());
+            continue;
+        }
+        // servw sets the framing headers itself
+        if name.eq_ignore_ascii_case("content-length")
+            || name.eq_ignore_ascii_case("connection")
+            || name.eq_ignore_ascii_case("transfer-encoding") {
+            continue;
+        }
+        if name.eq_ignore_ascii_case("location") {
+            has_location = true;
+        }
+        headers.push_str(&format!("{}: {}\r\n", name.trim(), value));
+    }
+
+    let status = match status {
+        Some(status) => match status.split_once(' ') {
+            Some(_) => status,
+            None => format!("{} {}", status, reason_phrase(status.parse().ok()?)),
+        },
+        None if has_location => "302 Found".to_string(),
+        None => "200 OK".to_string(),
+    };
+    Some((status, headers))
+}
+
+fn split_cgi_output(output: &[u8]) -> Option<(&[u8], &[u8])> {
+    for i in 0..output.len() {
+        if output[i..].starts_with(b"\r\n\r\n") {
+            return Some((&output[..i], &output[i + 4..]));
+        }
+        if output[i..].starts_with(b"\n\n") {
+            return Some((&output[..i], &output[i + 2..]));
+        }
+    }
+    None
+}
+
+fn malformed() -> Error {
+    Error::new(io::ErrorKind::InvalidData, "malformed CGI output")
+}
+
+// Turns script output into the response as it's written, for CGI and FastCGI alike. Output
+// that ends within CGI_BUFFER goes out with a Content-Length. Past that the head goes out,
+// and the body follows chunked, or until the connection closes for HTTP/1.0 clients.
+pub struct CgiResponse<'a> {
+    out: &'a mut dyn Write,
+    buffered: Vec<u8>,
+    started: bool,
+    chunk: bool,
+    head_request: bool,
+}
+
+impl<'a> CgiResponse<'a> {
+    pub fn new(request: &HttpRequest, out: &'a mut dyn Write) -> Self {
+        Self {
+            out,
+            buffered: Vec::new(),
+            started: false,
+            chunk: request.version() != "HTTP/1.0",
+            head_request: request.method() == "HEAD",
+        }
+    }
+
+    // Whether any of the response went out. Until then the client can still be sent an error
+    // response, after that a failure leaves the connection broken.
+    pub fn started(&self) -> bool {
+        self.started
+    }
+
+    // Ends the response once the script is done. Fails with InvalidData, before anything was
+    // written, if the output isn't a CGI response.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.started {
+            if self.chunk && !self.head_request {
+                ChunkedEncoder::new(&mut *self.out).finish(&Headers::new())?;
+            }
+            return Ok(());
+        }
+        let mut response = cgi_output_to_response(&self.buffered).ok_or_else(malformed)?;
+        if self.head_request {
+            let body = split_cgi_output(&self.buffered).map_or(0, |(_, body)| body.len());
+            response.truncate(response.len() - body);
+        }
+        self.started = true;
+        self.out.write_all(&response)
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        let buffered = std::mem::take(&mut self.buffered);
+        let (head, body) = split_cgi_output(&buffered).ok_or_else(malformed)?;
+        let (status, headers) = cgi_head(head).ok_or_else(malformed)?;
+        let framing = if self.chunk { "Transfer-Encoding: chunked\r\n" } else { "" };
+        self.started = true;
+        write!(self.out, "HTTP/1.1 {}\r\n{}{}\r\n", status, headers, framing)?;
+        self.write_body(body)
+    }
+
+    fn write_body(&mut self, data: &[u8]) -> io::Result<()> {
+        match (self.head_request, self.chunk) {
+            (true, _) => Ok(()),
+            (false, true) => ChunkedEncoder::new(&mut *self.out).write_all(data),
+            (false, false) => self.out.write_all(data),
+        }
+    }
+}
+
+impl Write for CgiResponse<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.started {
+            self.write_body(buf)?;
+        } else {
+            self.buffered.extend_from_slice(buf);
+            if self.buffered.len() > CGI_BUFFER {
+                self.start()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
 impl CgiHandler {
     pub fn new(config: crate::config::Config) -> Self {
         Self {
             config
         }
     }
+
+    fn run(&self, target: &ScriptTarget, env: Vec<(String, OsString)>, mut body: SpooledBody, response: &mut CgiResponse) -> io::Result<()> {
+        // Without a pass interpreter the script is executed directly
+        let mut command = if self.config.pass().is_empty() {
+            Command::new(&target.script_filename)
+        } else {
+            let mut command = Command::new(self.config.pass());
+            command.arg(&target.script_filename);
+            command
+        };
+
+        command.env_clear()
+            .env("PATH", std::env::var("PATH").unwrap_or_default())
+            .envs(env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Its own group, so a timeout also takes down whatever the script started
+            .process_group(0);
+        if let Some(dir) = target.script_filename.parent() {
+            command.current_dir(dir);
+        }
+
+        let deadline = Instant::now() + self.config.cgi_timeout();
+        let timed_out = || Error::new(io::ErrorKind::TimedOut, format!("CGI script ran longer than {:?}", self.config.cgi_timeout()));
+        let mut child = command.spawn()?;
+
+        // Feed stdin and drain stdout and stderr from other threads, so a script writing a lot
+        // before reading can't deadlock us and we can give up on it at the deadline
+        let mut stdin = child.stdin.take().unwrap();
+        std::thread::spawn(move || {
+            let _ = io::copy(&mut body, &mut stdin);
+        });
+        if let Some(mut stderr) = child.stderr.take() {
+            std::thread::spawn(move || {
+                let mut output = [0; 8 * 1024];
+                while let Ok(n @ 1..) = stderr.read(&mut output) {
+                    println!("CGI stderr: {}", String::from_utf8_lossy(&output[..n]));
+                }
+            });
+        }
+        // Only a few reads wait in the channel, past that the script waits for the client
+        let (sender, receiver) = mpsc::sync_channel(4);
+        let mut stdout = child.stdout.take().unwrap();
+        std::thread::spawn(move || {
+            let mut output = vec![0; 64 * 1024];
+            loop {
+                let read = match stdout.read(&mut output) {
+                    Ok(0) => return,
+                    Ok(n) => Ok(output[..n].to_vec()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = read.is_err();
+                if sender.send(read).is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        let mut received = false;
+        loop {
+            let read = match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(read) => read,
+                Err(RecvTimeoutError::Disconnected) => break,
+                Err(RecvTimeoutError::Timeout) => {
+                    kill_group(&mut child);
+                    return Err(timed_out());
+                }
+            };
+            received = true;
+            if let Err(e) = read.and_then(|output| response.write_all(&output)) {
+                kill_group(&mut child);
+                return Err(e);
+            }
+        }
+
+        let Some(status) = wait_until(&mut child, deadline)? else {
+            kill_group(&mut child);
+            return Err(timed_out());
+        };
+        if !received && !status.success() {
+            return Err(Error::other(format!("CGI script exited with {}", status)));
+        }
+        Ok(())
+    }
+}
+
+// Waits for the child to exit, None when it is still running at the deadline
+fn wait_until(child: &mut Child, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+// The child isn't reaped yet, so its pid still names its group and can't have been reused
+fn kill_group(child: &mut Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.wait();
 }
 
 impl Handler for CgiHandler {
-    fn handle(&self, _stream: &TcpStream) -> String {
-        println!("Handling CGI request");
-        // print config
-        println!("Config: {:?}", self.config);
-        "HTTP/1.1 200 OK\r\n\
-        Content-Type: text/plain\r\n\
-        Content-Length: 12\r\n\
-        Connection: close\r\n\
-        \r\n\
-        Hello World!\n\
-        ".to_string()
+    fn handle(&self, request: &HttpRequest, body: &mut RequestBody, out: &mut dyn Write) -> io::Result<()> {
+        let target = match resolve_script(self.config.root(), self.config.index(), request.path()) {
+            Some(target) => target,
+            None => return out.write_all(simple_response(404).as_bytes()),
+        };
+
+        // CONTENT_LENGTH has to be known up front, chunked bodies included
+        let body = match body.spool() {
+            Ok(body) => body,
+            Err(e) => return reject_body(e, out),
+        };
+        let env = cgi_env(&self.config, request, &target, body.len());
+        let mut response = CgiResponse::new(request, out);
+        let result = self.run(&target, env, body, &mut response).and_then(|()| response.finish());
+        let started = response.started();
+        match result {
+            Ok(()) => Ok(()),
+            // Part of the response went out already
+            Err(e) if started => Err(e),
+            Err(e) => {
+                println!("CGI error from {}: {}", target.script_filename.display(), e);
+                out.write_all(simple_response(if is_timeout(&e) { 504 } else { 502 }).as_bytes())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::http_validator::HttpValidator;
+    use std::fs;
+    use tempfile::{tempdir, NamedTempFile};
+
+    fn config_for(root: &Path, pass: &str) -> Config {
+        let file = NamedTempFile::new().unwrap();
+        let mut content = format!("root {}\nindex index.sh\n", root.display());
+        if !pass.is_empty() {
+            content.push_str(&format!("pass {}\n", pass));
+        }
+        fs::write(file.path(), content).unwrap();
+        let mut config = Config::new();
+        config.parse(file.path().to_str().unwrap()).unwrap();
+        config
+    }
+
+    #[test]
+    fn test_resolve_script_with_path_info() {
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("app")).unwrap();
+        fs::write(root.path().join("app/run.sh"), "").unwrap();
+
+        let target = resolve_script(root.path().to_str().unwrap(), "index.sh", "/app/run.sh/users/42").unwrap();
+        assert_eq!(target.script_name, "/app/run.sh");
+        assert_eq!(target.script_filename, root.path().join("app/run.sh"));
+        assert_eq!(target.path_info, "/users/42");
+    }
+
+    #[test]
+    fn test_resolve_script_index_and_missing() {
+        let root = tempdir().unwrap();
+        fs::write(root.path().join("index.sh"), "").unwrap();
+        let root_str = root.path().to_str().unwrap();
+
+        let target = resolve_script(root_str, "index.sh", "/").unwrap();
+        assert_eq!(target.script_name, "/index.sh");
+        assert_eq!(target.path_info, "");
+
+        assert_eq!(resolve_script(root_str, "index.sh", "/missing.sh"), None);
+        assert_eq!(resolve_script(root_str, "index.sh", "/../index.sh"), None);
+        assert_eq!(resolve_script(root_str, "index.sh", "/%2e%2e/index.sh"), None);
+    }
+
+    #[test]
+    fn test_cgi_output_to_response() {
+        let response = String::from_utf8(cgi_output_to_response(b"Status: 404\r\nContent-Type: text/plain\r\n\r\nnope").unwrap()).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(response.contains("Content-Type: text/plain\r\n"));
+        assert!(response.ends_with("Content-Length: 4\r\n\r\nnope"));
+
+        let response = cgi_output_to_response(b"Location: /login\n\n").unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 302 Found\r\n"));
+
+        // Bodies pass through untouched, even when they aren't UTF-8
+        let response = cgi_output_to_response(b"Content-Type: image/png\r\n\r\n\x89PNG\xff\x00").unwrap();
+        assert!(response.ends_with(b"Content-Length: 6\r\n\r\n\x89PNG\xff\x00"));
+
+        assert_eq!(cgi_output_to_response(b"no headers here"), None);
+    }
+
+    #[test]
+    fn test_cgi_env() {
+        let root = tempdir().unwrap();
+        let config = config_for(root.path(), "");
+        let request = HttpRequest::parse(
+            b"POST /run.sh/extra?a=1&b=2 HTTP/1.1\r\nHost: example.com:8080\r\nContent-Type: text/plain\r\n\
+            X-Forwarded-For: 10.0.0.1\r\nContent-Length: 5\r\n\r\nhello",
+        ).unwrap().with_addresses(Some("192.168.1.5:50000".parse().unwrap()), None);
+        let target = ScriptTarget {
+            script_name: "/run.sh".to_string(),
+            script_filename: root.path().join("run.sh"),
+            path_info: "/extra".to_string(),
+        };
+
+        let env = cgi_env(&config, &request, &target, 5);
+        let get = |key: &str| env.iter().find(|(k, _)| k == key).and_then(|(_, v)| v.to_str());
+        assert_eq!(get("REQUEST_METHOD"), Some("POST"));
+        assert_eq!(get("QUERY_STRING"), Some("a=1&b=2"));
+        assert_eq!(get("PATH_INFO"), Some("/extra"));
+        assert_eq!(get("CONTENT_LENGTH"), Some("5"));
+        assert_eq!(get("CONTENT_TYPE"), Some("text/plain"));
+        assert_eq!(get("REMOTE_ADDR"), Some("192.168.1.5"));
+        assert_eq!(get("SERVER_NAME"), Some("example.com"));
+        assert_eq!(get("HTTP_X_FORWARDED_FOR"), Some("10.0.0.1"));
+        assert_eq!(get("HTTP_CONTENT_TYPE"), None);
+    }
+
+    #[test]
+    fn test_cgi_env_keeps_header_bytes() {
+        let root = tempdir().unwrap();
+        let config = config_for(root.path(), "");
+        let request = HttpRequest::parse(b"GET / HTTP/1.1\r\nHost: a\r\nX-Name: caf\xe9\r\n\r\n").unwrap();
+        let target = ScriptTarget {
+            script_name: "/run.sh".to_string(),
+            script_filename: root.path().join("run.sh"),
+            path_info: String::new(),
+        };
+
+        let env = cgi_env(&config, &request, &target, 0);
+        let value = env.iter().find(|(k, _)| k == "HTTP_X_NAME").map(|(_, v)| v.as_bytes());
+        assert_eq!(value, Some(&b"caf\xe9"[..]));
+    }
+
+    #[test]
+    fn test_cgi_env_skips_proxy_and_underscore_headers() {
+        let root = tempdir().unwrap();
+        let config = config_for(root.path(), "");
+        let request = HttpRequest::parse(
+            b"GET / HTTP/1.1\r\nHost: a\r\nProxy: http://evil:8080\r\nX-Real-IP: 10.0.0.1\r\nX_Real_IP: 6.6.6.6\r\n\r\n",
+        ).unwrap();
+        let target = ScriptTarget {
+            script_name: "/run.sh".to_string(),
+            script_filename: root.path().join("run.sh"),
+            path_info: String::new(),
+        };
+
+        let env = cgi_env(&config, &request, &target, 0);
+        let get = |key: &str| env.iter().find(|(k, _)| k == key).and_then(|(_, v)| v.to_str());
+        assert_eq!(get("HTTP_PROXY"), None);
+        assert_eq!(get("HTTP_X_REAL_IP"), Some("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_handle_runs_script() {
+        let root = tempdir().unwrap();
+        fs::write(
+            root.path().join("index.sh"),
+            "printf 'Status: 201 Created\\r\\nContent-Type: text/plain\\r\\n\\r\\n'\n\
+            printf '%s %s %s ' \"$REQUEST_METHOD\" \"$QUERY_STRING\" \"$CONTENT_LENGTH\"\n\
+            cat\n",
+        ).unwrap();
+        let handler = CgiHandler::new(config_for(root.path(), "/bin/sh"));
+
+        // Chunked bodies get a CONTENT_LENGTH too
+        let mut validator = HttpValidator::new();
+        validator.feed(b"POST /?x=1 HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhe\r\n3\r\nllo\r\n0\r\n\r\n").unwrap();
+        let request = validator.get_request();
+        let mut response = Vec::new();
+        handler.handle(&request, &mut RequestBody::new(&mut validator, None), &mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 201 Created\r\n"));
+        assert!(response.ends_with("\r\n\r\nPOST x=1 5 hello"));
+    }
+
+    #[test]
+    fn test_long_output_is_streamed() {
+        let root = tempdir().unwrap();
+        fs::write(
+            root.path().join("index.sh"),
+            "printf 'Content-Type: text/plain\\r\\n\\r\\n'\nhead -c 300000 /dev/zero | tr '\\0' x\n",
+        ).unwrap();
+        let handler = CgiHandler::new(config_for(root.path(), "/bin/sh"));
+
+        let request = HttpRequest::parse(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        handler.handle(&request, &mut RequestBody::empty(), &mut response).unwrap();
+        let (head, body) = split_cgi_output(&response).unwrap();
+        assert!(head.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(head.ends_with(b"Transfer-Encoding: chunked"));
+        let mut decoded = Vec::new();
+        crate::chunked::ChunkedDecoder::new(body).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, vec![b'x'; 300_000]);
+
+        // HTTP/1.0 clients get it until the connection closes
+        let request = HttpRequest::parse(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        handler.handle(&request, &mut RequestBody::empty(), &mut response).unwrap();
+        let (head, body) = split_cgi_output(&response).unwrap();
+        assert!(!head.windows(17).any(|w| w == b"Transfer-Encoding"));
+        assert_eq!(body, vec![b'x'; 300_000]);
+    }
+
+    #[test]
+    fn test_hanging_script_is_killed() {
+        let root = tempdir().unwrap();
+        let pid_file = root.path().join("pid");
+        // The background sleep holds stdout open as well
+        fs::write(root.path().join("index.sh"), "sleep 30 &\necho $! > pid\nsleep 30\n").unwrap();
+        let file = NamedTempFile::new().unwrap();
+        fs::write(file.path(), format!("root {}\nindex index.sh\npass /bin/sh\ncgi_timeout 200ms\n", root.path().display())).unwrap();
+        let mut config = Config::new();
+        config.parse(file.path().to_str().unwrap()).unwrap();
+        let handler = CgiHandler::new(config);
+
+        let started = Instant::now();
+        let request = HttpRequest::parse(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = Vec::new();
+        handler.handle(&request, &mut RequestBody::empty(), &mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 504 "));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let pid: libc::pid_t = fs::read_to_string(pid_file).unwrap().trim().parse().unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        // Gone, or dead and waiting for init to reap it
+        let state = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+        assert!(state.is_empty() || state.contains(") Z "));
     }
 }
@@ -0,0 +1,82 @@
+use crate::request_body::RequestBody;
+use mio::{Events, Poll, Registry, Token};
+use std::io::{self, Write};
+use std::time::Instant;
+
+// What an exchange is waiting for before it can go on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Progress {
+    // Its own socket, until the deadline
+    Upstream(Instant),
+    // More of the request body
+    Body,
+    // The client taking the response written so far, see `Output::is_full`
+    Output,
+    // The response is complete
+    Done,
+}
+
+// Where an exchange writes its response. Writes always take everything they're given, once
+// the output is full the exchange stops producing more until the client caught up.
+pub trait Output: Write {
+    fn is_full(&self) -> bool;
+}
+
+// What an exchange works with each time it's advanced
+pub struct ExchangeIo<'a, 'b> {
+    // Its own socket goes in here under `token`, for reading and writing both
+    pub registry: &'a Registry,
+    pub token: Token,
+    pub body: &'a mut RequestBody<'b>,
+    pub out: &'a mut dyn Output,
+}
+
+// A response put together without blocking, for handlers that spend most of a request
+// waiting on another server. `advance` gets as far as the sockets allow and says what it's
+// waiting for, then it's called again once that may be ready or the deadline passed. Reading
+// the body fails with WouldBlock when the client has nothing more to send yet. Errors mean
+// the client connection is broken, like those of `Handler::handle`.
+pub trait Exchange: Send {
+    fn advance(&mut self, io: &mut ExchangeIo) -> io::Result<Progress>;
+}
+
+// A blocking client, which takes everything as it's written
+struct Direct<'a>(&'a mut dyn Write);
+
+impl Write for Direct<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Output for Direct<'_> {
+    fn is_full(&self) -> bool {
+        false
+    }
+}
+
+// Runs an exchange to the end on the calling thread, for requests answered through
+// `Handler::handle`. The body and the response are read and written blocking, only the
+// exchange's own socket is waited on.
+pub fn drive(exchange: &mut dyn Exchange, body: &mut RequestBody, out: &mut dyn Write) -> io::Result<()> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(4);
+    let mut out = Direct(out);
+    loop {
+        let mut io = ExchangeIo { registry: poll.registry(), token: Token(0), body: &mut *body, out: &mut out };
+        let deadline = match exchange.advance(&mut io)? {
+            Progress::Done => return Ok(()),
+            Progress::Upstream(deadline) => deadline,
+            // Blocking reads and writes leave nothing to wait for
+            Progress::Body | Progress::Output => continue,
+        };
+        match poll.poll(&mut events, Some(deadline.saturating_duration_since(Instant::now()))) {
+            Err(e) if e.kind() != io::ErrorKind::Interrupted => return Err(e),
+            _ => {}
+        }
+    }
+}
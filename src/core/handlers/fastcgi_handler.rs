@@ -0,0 +1,376 @@
+use crate::connection::is_timeout;
+use crate::handlers::{cgi_env, resolve_script, simple_response, CgiResponse, Handler};
+use crate::http_validator::HttpRequest;
+use crate::request_body::{reject_body, RequestBody};
+use crate::socket::Stream;
+use std::ffi::OsString;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+
+const FCGI_VERSION_1: u8 = 1;
+const FCGI_BEGIN_REQUEST: u8 = 1;
+const FCGI_END_REQUEST: u8 = 3;
+const FCGI_PARAMS: u8 = 4;
+const FCGI_STDIN: u8 = 5;
+const FCGI_STDOUT: u8 = 6;
+const FCGI_STDERR: u8 = 7;
+const FCGI_RESPONDER: u16 = 1;
+const FCGI_REQUEST_COMPLETE: u8 = 0;
+const FCGI_MAX_CONTENT: usize = 65535;
+
+// We only ever run one request per connection
+const REQUEST_ID: u16 = 1;
+
+pub struct FastCgiHandler {
+    config: crate::config::Config
+}
+
+// `pass` values that name a FastCGI socket rather than a CGI interpreter
+pub fn is_fastcgi_address(pass: &str) -> bool {
+    pass.starts_with("unix:") || (!pass.starts_with('/') && pass.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()))
+}
+
+fn write_record<W: Write>(out: &mut W, record_type: u8, content: &[u8]) -> io::Result<()> {
+    let padding = (8 - content.len() % 8) % 8;
+    let header = [
+        FCGI_VERSION_1,
+        record_type,
+        (REQUEST_ID >> 8) as u8,
+        REQUEST_ID as u8,
+        (content.len() >> 8) as u8,
+        content.len() as u8,
+        padding as u8,
+        0,
+    ];
+    out.write_all(&header)?;
+    out.write_all(content)?;
+    out.write_all(&[0; 8][..padding])
+}
+
+// Writes content split into as many records as needed, followed by the empty record closing the stream
+fn write_stream<W: Write>(out: &mut W, record_type: u8, content: &[u8]) -> io::Result<()> {
+    for chunk in content.chunks(FCGI_MAX_CONTENT) {
+        write_record(out, record_type, chunk)?;
+    }
+    write_record(out, record_type, &[])
+}
+
+// Like write_stream, for content that doesn't have to fit in memory
+fn copy_stream<W: Write>(out: &mut W, record_type: u8, content: &mut dyn Read) -> io::Result<()> {
+    let mut chunk = vec![0; FCGI_MAX_CONTENT];
+    loop {
+        let n = content.read(&mut chunk)?;
+        if n == 0 {
+            return write_record(out, record_type, &[]);
+        }
+        write_record(out, record_type, &chunk[..n])?;
+    }
+}
+
+fn read_record<R: Read>(input: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 8];
+    input.read_exact(&mut header)?;
+    if header[0] != FCGI_VERSION_1 {
+        return Err(Error::new(ErrorKind::InvalidData, "Unsupported FastCGI version"));
+    }
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let mut content = vec![0; length + header[6] as usize];
+    input.read_exact(&mut content)?;
+    content.truncate(length);
+    Ok((header[1], content))
+}
+
+fn encode_length(out: &mut Vec<u8>, length: usize) {
+    if length < 128 {
+        out.push(length as u8);
+    } else {
+        out.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+fn encode_params(params: &[(String, OsString)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (name, value) in params {
+        encode_length(&mut out, name.len());
+        encode_length(&mut out, value.len());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+    out
+}
+
+impl FastCgiHandler {
+    pub fn new(config: crate::config::Config) -> Self {
+        Self {
+            config
+        }
+    }
+
+    // Passes STDOUT records on to `response` as they arrive
+    fn run(&self, params: Vec<(String, OsString)>, body: &mut dyn Read, response: &mut CgiResponse) -> io::Result<()> {
+        let mut stream = Stream::connect(self.config.pass(), self.config.upstream_connect_timeout())?;
+        // Every read and write gives up after upstream_read_timeout
+        stream.set_read_timeout(Some(self.config.upstream_read_timeout()))?;
+        stream.set_write_timeout(Some(self.config.upstream_read_timeout()))?;
+
+        let mut begin = Vec::with_capacity(8);
+        begin.extend_from_slice(&FCGI_RESPONDER.to_be_bytes());
+        begin.extend_from_slice(&[0; 6]);
+        write_record(&mut stream, FCGI_BEGIN_REQUEST, &begin)?;
+        write_stream(&mut stream, FCGI_PARAMS, &encode_params(&params))?;
+        copy_stream(&mut stream, FCGI_STDIN, body)?;
+        stream.flush()?;
+
+        loop {
+            let (record_type, content) = read_record(&mut stream)?;
+            match record_type {
+                FCGI_STDOUT => response.write_all(&content)?,
+                FCGI_STDERR => println!("FastCGI stderr: {}", String::from_utf8_lossy(&content)),
+                FCGI_END_REQUEST => {
+                    if content.len() >= 5 && content[4] != FCGI_REQUEST_COMPLETE {
+                        return Err(Error::other(format!("FastCGI request rejected with status {}", content[4])));
+                    }
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Handler for FastCgiHandler {
+    fn handle(&self, request: &HttpRequest, body: &mut RequestBody, out: &mut dyn Write) -> io::Result<()> {
+        let target = match resolve_script(self.config.root(), self.config.index(), request.path()) {
+            Some(target) => target,
+            None => return out.write_all(simple_response(404).as_bytes()),
+        };
+
+        // CONTENT_LENGTH has to be known up front, chunked bodies included
+        let mut body = match body.spool() {
+            Ok(body) => body,
+            Err(e) => return reject_body(e, out),
+        };
+        let params = cgi_env(&self.config, request, &target, body.len());
+        let mut response = CgiResponse::new(request, out);
+        let result = self.run(params, &mut body, &mut response).and_then(|()| response.finish());
+        let started = response.started();
+        match result {
+            Ok(()) => Ok(()),
+            // Part of the response went out already
+            Err(e) if started => Err(e),
+            Err(e) => {
+                println!("FastCGI error for {}: {}", target.script_filename.display(), e);
+                out.write_all(simple_response(if is_timeout(&e) { 504 } else { 502 }).as_bytes())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::http_validator::HttpValidator;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use tempfile::{tempdir, NamedTempFile};
+
+    fn decode_length(input: &[u8], pos: &mut usize) -> usize {
+        if input[*pos] < 128 {
+            *pos += 1;
+            input[*pos - 1] as usize
+        } else {
+            let length = u32::from_be_bytes([input[*pos] & 0x7f, input[*pos + 1], input[*pos + 2], input[*pos + 3]]);
+            *pos += 4;
+            length as usize
+        }
+    }
+
+    fn decode_params(input: &[u8]) -> Vec<(String, String)> {
+        let mut params = Vec::new();
+        let mut pos = 0;
+        while pos < input.len() {
+            let name_len = decode_length(input, &mut pos);
+            let value_len = decode_length(input, &mut pos);
+            let name = String::from_utf8(input[pos..pos + name_len].to_vec()).unwrap();
+            pos += name_len;
+            let value = String::from_utf8(input[pos..pos + value_len].to_vec()).unwrap();
+            pos += value_len;
+            params.push((name, value));
+        }
+        params
+    }
+
+    // A minimal responder that echoes a few params and the request body back as the script output
+    fn respond<S: Read + Write>(mut stream: S) {
+        let (record_type, _) = read_record(&mut stream).unwrap();
+        assert_eq!(record_type, FCGI_BEGIN_REQUEST);
+
+        let mut params = Vec::new();
+        loop {
+            let (record_type, content) = read_record(&mut stream).unwrap();
+            assert_eq!(record_type, FCGI_PARAMS);
+            if content.is_empty() {
+                break;
+            }
+            params.extend(content);
+        }
+        let params = decode_params(&params);
+        let get = |key: &str| params.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone()).unwrap_or_default();
+
+        let mut stdin = Vec::new();
+        loop {
+            let (record_type, content) = read_record(&mut stream).unwrap();
+            assert_eq!(record_type, FCGI_STDIN);
+            if content.is_empty() {
+                break;
+            }
+            stdin.extend(content);
+        }
+
+        let mut output = format!(
+            "Status: 202 Accepted\r\nContent-Type: text/plain\r\n\r\n{} {} {} ",
+            get("REQUEST_METHOD"),
+            get("SCRIPT_NAME"),
+            get("QUERY_STRING")
+        ).into_bytes();
+        output.extend(stdin);
+        write_stream(&mut stream, FCGI_STDOUT, &output).unwrap();
+        write_record(&mut stream, FCGI_STDERR, b"a warning").unwrap();
+        write_record(&mut stream, FCGI_END_REQUEST, &[0, 0, 0, 0, FCGI_REQUEST_COMPLETE, 0, 0, 0]).unwrap();
+    }
+
+    fn roundtrip(pass: &str, request: &[u8]) -> String {
+        roundtrip_with(pass, "", request)
+    }
+
+    fn roundtrip_with(pass: &str, directives: &str, request: &[u8]) -> String {
+        let root = tempdir().unwrap();
+        fs::write(root.path().join("index.php"), "").unwrap();
+        let file = NamedTempFile::new().unwrap();
+        fs::write(file.path(), format!("root {}\npass {}\n{}", root.path().display(), pass, directives)).unwrap();
+        let mut config = Config::new();
+        config.parse(file.path().to_str().unwrap()).unwrap();
+        let handler = FastCgiHandler::new(config);
+
+        let mut validator = HttpValidator::new();
+        validator.feed(request).unwrap();
+        let request = validator.get_request();
+        let mut response = Vec::new();
+        handler.handle(&request, &mut RequestBody::new(&mut validator, None), &mut response).unwrap();
+        String::from_utf8(response).unwrap()
+    }
+
+    #[test]
+    fn test_is_fastcgi_address() {
+        assert!(is_fastcgi_address("127.0.0.1:9000"));
+        assert!(is_fastcgi_address("localhost:9000"));
+        assert!(is_fastcgi_address("unix:/run/php-fpm.sock"));
+        assert!(!is_fastcgi_address("/usr/bin/php-cgi"));
+        assert!(!is_fastcgi_address(""));
+    }
+
+    #[test]
+    fn test_params_encoding() {
+        let long_value = "x".repeat(300);
+        let params = vec![
+            ("SCRIPT_NAME".to_string(), "/index.php".into()),
+            ("HTTP_COOKIE".to_string(), long_value.clone().into()),
+        ];
+        let encoded = encode_params(&params);
+        assert_eq!(encoded[0], 11);
+        assert_eq!(decode_params(&encoded), [
+            ("SCRIPT_NAME".to_string(), "/index.php".to_string()),
+            ("HTTP_COOKIE".to_string(), long_value),
+        ]);
+    }
+
+    #[test]
+    fn test_large_stream_is_split_into_records() {
+        let mut out = Vec::new();
+        write_stream(&mut out, FCGI_STDIN, &vec![7; FCGI_MAX_CONTENT + 10]).unwrap();
+
+        let mut input = out.as_slice();
+        assert_eq!(read_record(&mut input).unwrap().1.len(), FCGI_MAX_CONTENT);
+        assert_eq!(read_record(&mut input).unwrap().1.len(), 10);
+        assert_eq!(read_record(&mut input).unwrap(), (FCGI_STDIN, vec![]));
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn test_handle_over_tcp() {
+        let responder = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pass = responder.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || respond(responder.accept().unwrap().0));
+
+        let response = roundtrip(&pass, b"POST /?id=7 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\nping");
+        server.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 202 Accepted\r\n"));
+        assert!(response.ends_with("\r\n\r\nPOST /index.php id=7 ping"));
+    }
+
+    #[test]
+    fn test_handle_over_unix_socket() {
+        let dir = tempdir().unwrap();
+        let socket = dir.path().join("php-fpm.sock");
+        let responder = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let server = std::thread::spawn(move || respond(responder.accept().unwrap().0));
+
+        let response = roundtrip(&format!("unix:{}", socket.display()), b"GET /index.php HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        server.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 202 Accepted\r\n"));
+        assert!(response.ends_with("\r\n\r\nGET /index.php  "));
+    }
+
+    #[test]
+    fn test_large_output_is_streamed() {
+        let responder = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pass = responder.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = responder.accept().unwrap();
+            while read_record(&mut stream).unwrap() != (FCGI_STDIN, vec![]) {}
+            write_record(&mut stream, FCGI_STDOUT, b"Content-Type: text/plain\r\n\r\n").unwrap();
+            for _ in 0..10 {
+                write_record(&mut stream, FCGI_STDOUT, &[b'x'; 30_000]).unwrap();
+            }
+            write_record(&mut stream, FCGI_END_REQUEST, &[0, 0, 0, 0, FCGI_REQUEST_COMPLETE, 0, 0, 0]).unwrap();
+        });
+
+        let response = roundtrip(&pass, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        server.join().unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.ends_with("Transfer-Encoding: chunked"));
+        let mut decoded = String::new();
+        crate::chunked::ChunkedDecoder::new(body.as_bytes()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "x".repeat(300_000));
+    }
+
+    #[test]
+    fn test_unreachable_backend_is_bad_gateway() {
+        let unused = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pass = unused.local_addr().unwrap().to_string();
+        drop(unused);
+
+        let response = roundtrip(&pass, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+    }
+
+    #[test]
+    fn test_slow_fastcgi_server_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        // Takes the request and never answers
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read_to_end(&mut Vec::new());
+        });
+
+        let started = std::time::Instant::now();
+        let response = roundtrip_with(&address, "upstream_read_timeout 100ms\n", b"GET / HTTP/1.1\r\nHost: t\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -1,5 +1,76 @@
-use std::net::TcpStream;
+use crate::handlers::Exchange;
+use crate::http_validator::HttpRequest;
+use crate::request_body::RequestBody;
+use std::io::{self, Write};
+use std::sync::Arc;
 
+// Handlers get a request whose head was already read and validated, and write the response
+// straight to the client, so response bodies go out byte-for-byte and don't have to fit in
+// memory. The request body is read from `body` as the handler needs it, and doesn't either.
+// An error means the connection is broken and nothing more can be sent on it, responses for
+// failures the handler could deal with, a bad request body among them, are written by the
+// handler itself. The Connection header is left to the connection, which knows whether it
+// stays open.
 pub trait Handler: Send + Sync {
-    fn handle(&self, stream: &TcpStream) -> String;
+    fn handle(&self, request: &HttpRequest, body: &mut RequestBody, out: &mut dyn Write) -> io::Result<()>;
+
+    // Handlers that mostly wait on other servers can answer without a thread of their own, the
+    // event loop drives the exchange as its sockets become ready. None has `handle` answer the
+    // request on a worker instead.
+    fn exchange(&self, _request: &HttpRequest) -> Option<Box<dyn Exchange>> {
+        None
+    }
+}
+
+// Lets a handler be shared with whoever needs to reach it after handing it over
+impl<H: Handler + ?Sized> Handler for Arc<H> {
+    fn handle(&self, request: &HttpRequest, body: &mut RequestBody, out: &mut dyn Write) -> io::Result<()> {
+        self.as_ref().handle(request, body, out)
+    }
+
+    fn exchange(&self, request: &HttpRequest) -> Option<Box<dyn Exchange>> {
+        self.as_ref().exchange(request)
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+// Builds a small plain text response for errors generated by servw itself
+pub fn simple_response(status: u16) -> String {
+    let reason = reason_phrase(status);
+    let body = format!("{} {}\n", status, reason);
+    format!(
+        "HTTP/1.1 {} {}\r\n\
+        Content-Length: {}\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        {}",
+        status,
+        reason,
+        body.len(),
+        body
+    )
 }
@@ -1,7 +1,15 @@
 mod handler;
+mod exchange;
 mod server_handler;
 mod cgi_handler;
+mod fastcgi_handler;
+mod static_file_handler;
+mod reloadable_handler;
 
 pub use handler::*;
+pub use exchange::*;
 pub use server_handler::*;
-pub use cgi_handler::*;
\ No newline at end of file
+pub use cgi_handler::*;
+pub use fastcgi_handler::*;
+pub use static_file_handler::*;
+pub use reloadable_handler::*;
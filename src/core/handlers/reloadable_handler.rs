@@ -0,0 +1,100 @@
+use crate::handlers::{Exchange, Handler};
+use crate::http_validator::HttpRequest;
+use crate::request_body::RequestBody;
+use std::io::{self, Write};
+use std::sync::{Arc, RwLock};
+
+// Passes requests to the current generation of handlers, built from one version of the config.
+// Swapping in a new generation only affects requests that start afterwards, requests already
+// in flight hold on to the generation they started with and finish on it.
+pub struct ReloadableHandler {
+    current: RwLock<Arc<dyn Handler>>,
+}
+
+impl ReloadableHandler {
+    pub fn new(handler: Box<dyn Handler>) -> Self {
+        Self {
+            current: RwLock::new(Arc::from(handler)),
+        }
+    }
+
+    pub fn swap(&self, handler: Box<dyn Handler>) {
+        let old = std::mem::replace(&mut *self.current.write().unwrap_or_else(|e| e.into_inner()), Arc::from(handler));
+        // The old generation goes away with its last request, not while holding the lock
+        drop(old);
+    }
+
+    fn current(&self) -> Arc<dyn Handler> {
+        self.current.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl Handler for ReloadableHandler {
+    fn handle(&self, request: &HttpRequest, body: &mut RequestBody, out: &mut dyn Write) -> io::Result<()> {
+        self.current().handle(request, body, out)
+    }
+
+    fn exchange(&self, request: &HttpRequest) -> Option<Box<dyn Exchange>> {
+        self.current().exchange(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_validator::{HttpValidator, Status};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::sync::Mutex;
+
+    struct Generation {
+        name: &'static str,
+        // Holds the request until told to go on
+        gate: Option<Mutex<Receiver<()>>>,
+        started: Sender<()>,
+    }
+
+    impl Handler for Generation {
+        fn handle(&self, _request: &HttpRequest, _body: &mut RequestBody, out: &mut dyn Write) -> io::Result<()> {
+            let _ = self.started.send(());
+            if let Some(gate) = &self.gate {
+                gate.lock().unwrap().recv().unwrap();
+            }
+            write!(out, "{}", self.name)
+        }
+    }
+
+    fn request() -> HttpRequest {
+        let mut validator = HttpValidator::new();
+        assert_eq!(validator.feed(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").unwrap(), Status::Complete);
+        validator.get_request()
+    }
+
+    #[test]
+    fn test_in_flight_requests_finish_on_old_generation() {
+        let (release, gate) = channel();
+        let (started, starts) = channel();
+        let handler = Arc::new(ReloadableHandler::new(Box::new(Generation {
+            name: "old",
+            gate: Some(Mutex::new(gate)),
+            started: started.clone(),
+        })));
+
+        let in_flight = {
+            let handler = handler.clone();
+            std::thread::spawn(move || {
+                let mut out = Vec::new();
+                handler.handle(&request(), &mut RequestBody::empty(), &mut out).unwrap();
+                out
+            })
+        };
+        starts.recv().unwrap();
+
+        handler.swap(Box::new(Generation { name: "new", gate: None, started }));
+        let mut out = Vec::new();
+        handler.handle(&request(), &mut RequestBody::empty(), &mut out).unwrap();
+        assert_eq!(out, b"new");
+
+        release.send(()).unwrap();
+        assert_eq!(in_flight.join().unwrap(), b"old");
+    }
+}
@@ -1,96 +1,1500 @@
-use crate::core::lbs::LoadBalancer;
-use crate::handlers::Handler;
-use std::net::TcpStream;
-use std::sync::Mutex;
+use crate::core::lbs::{ConnectionLease, RequestContext, SharedLoadBalancer};
+use crate::health::HealthRegistry;
+use crate::chunked::{self, ChunkedEncoder, ChunkedParser};
+use crate::handlers::{drive, simple_response, Exchange, ExchangeIo, Handler, Output, Progress};
+use crate::http_validator::{Headers, HttpRequest, BODY_BUFFER};
+use crate::connection::is_timeout;
+use crate::request_body::{reject_body, RequestBody};
+use crate::socket::{self, Peer, Stream};
+use crate::upstream_pool::UpstreamPool;
+use mio::unix::SourceFd;
+use mio::Interest;
+use std::collections::VecDeque;
 use std::sync::Arc;
-use std::io::{Read, Write};
-use std::io::BufReader;
-use std::io::BufRead;
+use std::io::{self, Read, Write};
+use std::os::fd::AsRawFd;
+use std::time::Instant;
 
+#[derive(Clone)]
 pub struct ServerHandler {
-    #[allow(dead_code)]
-    lb: Arc<Mutex<Box<dyn LoadBalancer>>>,
+    config: Arc<crate::config::Config>,
+    lb: SharedLoadBalancer,
+    health: Arc<HealthRegistry>,
+    pool: Arc<UpstreamPool>,
 }
 
-impl ServerHandler {
-    pub fn new(lb: Arc<Mutex<Box<dyn LoadBalancer>>>) -> ServerHandler {
-        ServerHandler { lb }
+// Why talking to an upstream failed, and whether it may already have seen the request
+struct UpstreamError {
+    request_sent: bool,
+    // Whether any of the response came back before the error
+    answered: bool,
+    error: io::Error,
+}
+
+impl UpstreamError {
+    // Not all of the request reached the upstream, so it can't have acted on it
+    fn before_send(error: io::Error) -> Self {
+        UpstreamError { request_sent: false, answered: false, error }
+    }
+
+    fn after_send(error: io::Error) -> Self {
+        UpstreamError { request_sent: true, answered: true, error }
+    }
+
+    // The connection failed while sending the request or before a byte of response arrived
+    fn unanswered(error: io::Error) -> Self {
+        UpstreamError { request_sent: true, answered: false, error }
+    }
+
+    // What a pooled connection the upstream closed while it sat idle looks like. That is no
+    // sign of trouble with the server. It looks just the same as an upstream that acted on
+    // the request and went down before answering, though.
+    fn stale(&self) -> bool {
+        !self.answered && !self.timed_out()
+    }
+
+    // Slow rather than broken, the client gets a 504 instead of a 502
+    fn timed_out(&self) -> bool {
+        is_timeout(&self.error)
     }
 }
 
-impl Handler for ServerHandler {
-    fn handle(&self, tcp_stream: &TcpStream) -> String {
-        let selected_server = self.lb.lock().unwrap().select_server().unwrap();
+fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "upstream timed out")
+}
+
+// How an upstream response body is delimited on the wire, see RFC 9112 section 6.3
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum BodyFraming {
+    None,
+    Length(u64),
+    Chunked,
+    // The body ends when the upstream closes the connection
+    UntilClose,
+}
+
+// Chunked has to be the last transfer coding applied
+fn is_chunked(transfer_encoding: &[u8]) -> bool {
+    transfer_encoding.rsplit(|&b| b == b',').next().unwrap_or_default().trim_ascii().eq_ignore_ascii_case(b"chunked")
+}
+
+// Connection options and framing that only concern one hop, see RFC 9110 section 7.6.1
+const HOP_BY_HOP: [&str; 8] = [
+    "connection", "keep-alive", "proxy-connection", "te", "trailer", "transfer-encoding", "upgrade", "content-length",
+];
+
+// How the request body goes upstream
+enum RequestFraming<'a> {
+    // Read in full, along with the trailers that came after it
+    Buffered(&'a [u8], &'a Headers),
+    // Passed on as it comes in, with the client's Content-Length or chunked when it had none
+    Streamed(Option<u64>),
+}
+
+// Rebuilds the request for the upstream. It always goes out as HTTP/1.1 so the connection can
+// be kept alive, unless `keep_alive` is off. A buffered body was decoded on the way in, so it
+// goes out with a Content-Length, or chunked again when the client sent trailers along with it.
+// A streamed body isn't part of what's returned, only the head announcing it.
+fn upstream_request(request: &HttpRequest, server: &str, keep_alive: bool, body: RequestFraming) -> Vec<u8> {
+    let mut head = format!("{} {} HTTP/1.1\r\n", request.method(), request.target()).into_bytes();
+    // HTTP/1.0 clients may leave it out, HTTP/1.1 requires it. A Unix socket path is no host name.
+    if !request.headers().contains("host") {
+        let host = if socket::unix_path(server).is_some() { "localhost" } else { server };
+        head.extend_from_slice(format!("Host: {}\r\n", host).as_bytes());
+    }
+    for (name, value) in request.headers().iter() {
+        let hop_by_hop = HOP_BY_HOP.iter().any(|h| h.eq_ignore_ascii_case(name))
+            || request.headers().has_token("connection", name);
+        if !hop_by_hop {
+            // Values go on as the client sent them, they needn't be UTF-8
+            head.extend_from_slice(name.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value);
+            head.extend_from_slice(b"\r\n");
+        }
+    }
+    // Persistent is the default in HTTP/1.1
+    let connection: &[u8] = if keep_alive { b"" } else { b"Connection: close\r\n" };
+
+    match body {
+        RequestFraming::Buffered(body, trailers) if trailers.is_empty() => {
+            // Some upstreams insist on a length for these, even an empty one
+            if !body.is_empty() || matches!(request.method(), "POST" | "PUT" | "PATCH") {
+                head.extend_from_slice(format!("Content-Length: {}\r\n", body.len()).as_bytes());
+            }
+            head.extend_from_slice(connection);
+            head.extend_from_slice(b"\r\n");
+            head.extend_from_slice(body);
+            head
+        }
+        RequestFraming::Buffered(body, trailers) => {
+            head.extend_from_slice(b"Transfer-Encoding: chunked\r\n");
+            head.extend_from_slice(connection);
+            head.extend_from_slice(b"\r\n");
+            let mut encoder = ChunkedEncoder::new(head);
+            // Writing to a Vec can't fail
+            let _ = encoder.write_all(body);
+            encoder.finish(trailers).unwrap_or_default()
+        }
+        RequestFraming::Streamed(length) => {
+            match length {
+                Some(length) => head.extend_from_slice(format!("Content-Length: {}\r\n", length).as_bytes()),
+                None => head.extend_from_slice(b"Transfer-Encoding: chunked\r\n"),
+            }
+            head.extend_from_slice(connection);
+            head.extend_from_slice(b"\r\n");
+            head
+        }
+    }
+}
+
+// Adds part of a streamed body the way it goes upstream, as a chunk when it's chunked
+fn push_body(data: &mut Vec<u8>, bytes: &[u8], chunked: bool) {
+    if chunked {
+        // Writing to a Vec can't fail
+        let _ = ChunkedEncoder::new(data).write_all(bytes);
+    } else {
+        data.extend_from_slice(bytes);
+    }
+}
+
+// Drops a header from a response head, and adds a line right before the blank line ending it
+fn rewrite_head(head: &[u8], remove: &str, add: Option<&str>) -> Vec<u8> {
+    let mut lines: Vec<&[u8]> = head.split_inclusive(|&b| b == b'\n').collect();
+    let end = lines.pop().unwrap_or(b"\r\n");
+    let prefix = format!("{}:", remove);
+    lines.retain(|line| line.len() < prefix.len() || !line[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes()));
+
+    let mut rewritten = lines.concat();
+    if let Some(line) = add {
+        rewritten.extend(line.as_bytes());
+        rewritten.extend(b"\r\n");
+    }
+    rewritten.extend(end);
+    rewritten
+}
+
+// Requests that are safe to send twice, see RFC 9110 section 9.2.2
+pub fn is_idempotent(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS" | "TRACE" | "PUT" | "DELETE")
+}
+
+// How the response body is passed on to the client
+enum Relay {
+    // This many bytes left
+    Length(u64),
+    // Framing and all, or just the payload for HTTP/1.0 clients, which don't understand chunks
+    Chunked { parser: ChunkedParser, decode: bool },
+    // Until the upstream closes the connection, chunked for the client when it's true
+    UntilClose(bool),
+}
+
+impl Relay {
+    // Sends the client what came in of the body, returns whether the body is complete. Anything
+    // past its end is left in `input`.
+    fn pass_on(&mut self, input: &mut Vec<u8>, out: &mut dyn Output) -> io::Result<bool> {
+        let mut used = 0;
+        let done = loop {
+            let rest = &input[used..];
+            match self {
+                Relay::Length(left) => {
+                    let n = (rest.len() as u64).min(*left) as usize;
+                    out.write_all(&rest[..n])?;
+                    *left -= n as u64;
+                    used += n;
+                    break *left == 0;
+                }
+                Relay::Chunked { parser, decode } => {
+                    if parser.is_done() {
+                        break true;
+                    }
+                    let step = parser.parse(rest)?;
+                    let n = match step {
+                        chunked::Step::Incomplete => break false,
+                        chunked::Step::Framing(n) | chunked::Step::Data(n) | chunked::Step::Done(n) => n,
+                    };
+                    if !*decode {
+                        chunked::pass_step(step, &rest[..n], out)?;
+                    } else if let chunked::Step::Data(_) = step {
+                        out.write_all(&rest[..n])?;
+                    }
+                    used += n;
+                }
+                Relay::UntilClose(chunk) => {
+                    if *chunk && !rest.is_empty() {
+                        ChunkedEncoder::new(&mut *out).write_all(rest)?;
+                    } else {
+                        out.write_all(rest)?;
+                    }
+                    used = input.len();
+                    break false;
+                }
+            }
+        };
+        input.drain(..used);
+        Ok(done)
+    }
+
+    // The upstream closed the connection, which only ends a body running until close
+    fn close(&self, out: &mut dyn Output) -> io::Result<()> {
+        match self {
+            Relay::UntilClose(true) => ChunkedEncoder::new(out).finish(&Headers::new()).map(drop),
+            Relay::UntilClose(false) => Ok(()),
+            _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed the connection mid-body")),
+        }
+    }
+}
+
+// Most an upstream may send as a response head, status line and header fields together
+const MAX_RESPONSE_HEAD: usize = 64 * 1024;
+
+fn invalid_response(message: &str) -> UpstreamError {
+    UpstreamError::after_send(io::Error::new(io::ErrorKind::InvalidData, message.to_string()))
+}
+
+// Splits a head into its lines, without line endings or the blank line ending it
+fn head_lines(head: &[u8]) -> impl Iterator<Item = &[u8]> {
+    head.split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+}
+
+fn split_field(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    let colon = line.iter().position(|&b| b == b':')?;
+    Some((&line[..colon], line[colon + 1..].trim_ascii()))
+}
+
+fn status(head: &[u8]) -> &[u8] {
+    head.split(|&b| b == b' ').nth(1).unwrap_or_default()
+}
+
+// Where a head ends, right after the blank line following the header fields
+fn head_end(input: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    while let Some(i) = input[start..].iter().position(|&b| b == b'\n') {
+        let line = &input[start..=start + i];
+        start += i + 1;
+        if line == b"\r\n" || line == b"\n" {
+            return Some(start);
+        }
+    }
+    None
+}
+
+// Parses the response head at the start of what the upstream sent so far, and returns its
+// length and how the body is framed, None until the whole head is in. Header values may carry
+// any bytes, only the framing headers are interpreted, and a Content-Length that isn't a number
+// fails the response rather than leaving the body to run until the connection closes.
+fn parse_response_head(input: &[u8]) -> Result<Option<(usize, BodyFraming)>, UpstreamError> {
+    let end = match head_end(&input[..input.len().min(MAX_RESPONSE_HEAD)]) {
+        Some(end) => end,
+        None if input.len() >= MAX_RESPONSE_HEAD => return Err(invalid_response("upstream response head too large")),
+        None => return Ok(None),
+    };
+
+    let mut lines = head_lines(&input[..end]);
+    let status_line = lines.next().unwrap_or_default();
+    let status = status(status_line);
+    if !status_line.starts_with(b"HTTP/1.") || status.len() != 3 || !status.iter().all(u8::is_ascii_digit) {
+        return Err(invalid_response("malformed upstream status line"));
+    }
+
+    let mut content_length = None;
+    let mut chunked = false;
+    for line in lines {
+        let (name, value) = split_field(line).ok_or_else(|| invalid_response("malformed upstream header field"))?;
+        if name.eq_ignore_ascii_case(b"content-length") {
+            // Repeated or listed values have to agree, see RFC 9110 section 8.6
+            for value in value.split(|&b| b == b',').map(<[u8]>::trim_ascii) {
+                let length = std::str::from_utf8(value).ok()
+                    .filter(|value| !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()))
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .ok_or_else(|| invalid_response("invalid upstream Content-Length"))?;
+                if content_length.is_some_and(|content_length| content_length != length) {
+                    return Err(invalid_response("conflicting upstream Content-Length values"));
+                }
+                content_length = Some(length);
+            }
+        } else if name.eq_ignore_ascii_case(b"transfer-encoding") {
+            chunked = is_chunked(value);
+        }
+    }
+
+    // Transfer-Encoding overrides Content-Length
+    let framing = match (chunked, content_length) {
+        (true, _) => BodyFraming::Chunked,
+        (false, Some(length)) => BodyFraming::Length(length),
+        (false, None) => BodyFraming::UntilClose,
+    };
+    Ok(Some((end, framing)))
+}
+
+// Whether the upstream leaves the connection open after this response, see RFC 9112 section 9.3
+fn upstream_keeps_alive(head: &[u8]) -> bool {
+    let mut lines = head_lines(head);
+    let http11 = lines.next().unwrap_or_default().starts_with(b"HTTP/1.1 ");
+    let mut close = false;
+    let mut keep_alive = false;
+    for (name, value) in lines.filter_map(split_field) {
+        if name.eq_ignore_ascii_case(b"connection") {
+            for token in value.split(|&b| b == b',').map(<[u8]>::trim_ascii) {
+                close |= token.eq_ignore_ascii_case(b"close");
+                keep_alive |= token.eq_ignore_ascii_case(b"keep-alive");
+            }
+        }
+    }
+    !close && (http11 || keep_alive)
+}
+
+// The upstream socket is watched for reading and writing both, whichever the exchange waits on
+fn register(upstream: &Stream, io: &ExchangeIo) -> io::Result<()> {
+    io.registry.register(&mut SourceFd(&upstream.as_raw_fd()), io.token, Interest::READABLE | Interest::WRITABLE)
+}
+
+// Reads up to BODY_BUFFER of the body
+fn read_ahead(buffered: &mut Vec<u8>, body: &mut RequestBody) -> io::Result<()> {
+    let mut chunk = [0; 16 * 1024];
+    while buffered.len() < BODY_BUFFER {
+        let room = (BODY_BUFFER - buffered.len()).min(chunk.len());
+        match body.read(&mut chunk[..room]) {
+            Ok(0) => break,
+            Ok(n) => buffered.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// What a connection to the upstream is up to
+enum Phase {
+    // About to send over an idle connection from the pool
+    Pooled(Stream),
+    // Trying the server's addresses one after the other, `current` is being connected
+    Connecting { peers: VecDeque<Peer>, current: Option<Stream>, error: Option<io::Error> },
+    // `data` is the request, or the part of a streamed body that came in last, sent up to `written`
+    Sending { upstream: Stream, data: Vec<u8>, written: usize },
+    Receiving { upstream: Stream, input: Vec<u8> },
+    Relaying { upstream: Stream, input: Vec<u8>, relay: Relay },
+}
+
+// Tries every address of `server`
+fn connecting(server: &str) -> Phase {
+    match socket::resolve(server) {
+        Ok(peers) => Phase::Connecting { peers: peers.into(), current: None, error: None },
+        Err(e) => Phase::Connecting { peers: VecDeque::new(), current: None, error: Some(e) },
+    }
+}
+
+// Where a step of an attempt left it
+enum Outcome {
+    // On to the next phase right away
+    Next(Phase),
+    Wait(Phase, Progress),
+    Failed(UpstreamError),
+    // The response went to the client, or one was written in its place
+    Answered,
+}
+
+// One go at a server the balancer picked. Holding the lease until the response is sent,
+// dropping it tells the balancer we're done.
+struct Attempt {
+    lease: ConnectionLease,
+    // The connection came from the pool rather than being made for this request
+    pooled: bool,
+    // When the upstream has taken too long, moved along whenever it makes progress
+    deadline: Instant,
+    // Whether the deadline was running the last time around, rather than the exchange waiting on the client
+    waiting_upstream: bool,
+    // Sending a streamed body failed, the upstream may still have answered, with a 413 say
+    write_error: Option<io::Error>,
+    // Whether the upstream takes another request on the connection after this response
+    keep_alive: bool,
+}
+
+impl Attempt {
+    fn new(lease: ConnectionLease, pooled: bool) -> Self {
+        Self {
+            lease,
+            pooled,
+            deadline: Instant::now(),
+            waiting_upstream: true,
+            write_error: None,
+            keep_alive: false,
+        }
+    }
+}
+
+// A request on its way through an upstream server, see `Exchange`. A body that fits in
+// BODY_BUFFER is held on to, and the request goes to another server after a failed attempt
+// as far as `is_idempotent` allows. A larger body is passed on as it comes in, over a new
+// connection since a pooled one might turn out closed after the body was used up. Once part of
+// it went upstream it can't be sent again, so the request only moves on to another server when
+// the connection couldn't be established.
+pub struct ProxyExchange {
+    proxy: ServerHandler,
+    request: HttpRequest,
+    context: RequestContext,
+    tried: Vec<String>,
+    // Servers the balancer may still be asked for
+    attempts: usize,
+    // Answered when every attempt failed, a timeout on the last one makes it a 504
+    status: u16,
+    // The start of the body, or all of it unless `streamed`
+    buffered: Vec<u8>,
+    read_ahead: bool,
+    streamed: bool,
+    // The whole streamed body went upstream
+    body_sent: bool,
+    current: Option<(Attempt, Phase)>,
+}
+
+impl ProxyExchange {
+    pub fn new(proxy: ServerHandler, request: HttpRequest) -> Self {
+        Self {
+            attempts: proxy.config.upstream_retries() + 1,
+            context: RequestContext::from_request(&request),
+            proxy,
+            request,
+            tried: Vec::new(),
+            status: 502,
+            buffered: Vec::new(),
+            read_ahead: false,
+            streamed: false,
+            body_sent: false,
+            current: None,
+        }
+    }
+
+    // Picks a server and the way to reach it, an idle pooled connection when there is one and
+    // the body can go again
+    fn next_attempt(&mut self) -> Option<(Attempt, Phase)> {
+        if self.attempts == 0 {
+            return None;
+        }
+        self.attempts -= 1;
+        self.context.skip = self.proxy.health.down_servers();
+        self.context.skip.extend(self.tried.iter().cloned());
+        let lease = ConnectionLease::acquire(&self.proxy.lb, &self.context)?;
 
-        // Connect to the selected upstream server
-        let mut upstream = TcpStream::connect(selected_server.clone()).unwrap();
+        let pooled = if self.streamed { None } else { self.proxy.pool.get(lease.server()) };
+        Some(match pooled {
+            Some(upstream) => (Attempt::new(lease, true), Phase::Pooled(upstream)),
+            None => {
+                let phase = connecting(lease.server());
+                (Attempt::new(lease, false), phase)
+            }
+        })
+    }
 
-        // Forward the original request exactly as received
-        let mut reader = BufReader::new(tcp_stream);
-        let mut request = Vec::new();
+    // The request as it goes to `server`, with as much of the body as is at hand
+    fn request_data(&self, server: &str, body: &RequestBody) -> Vec<u8> {
+        let keep_alive = self.proxy.pool.is_enabled();
+        if !self.streamed {
+            return upstream_request(&self.request, server, keep_alive, RequestFraming::Buffered(&self.buffered, body.trailers()));
+        }
+        let mut data = upstream_request(&self.request, server, keep_alive, RequestFraming::Streamed(body.length()));
+        push_body(&mut data, &self.buffered, body.length().is_none());
+        data
+    }
 
-        // Read and forward headers
+    fn step(&mut self, attempt: &mut Attempt, phase: Phase, io: &mut ExchangeIo) -> io::Result<Outcome> {
+        // Time spent waiting on the client doesn't count against the upstream
+        if !attempt.waiting_upstream {
+            attempt.deadline = Instant::now() + self.proxy.config.upstream_read_timeout();
+        }
+        match phase {
+            Phase::Pooled(upstream) => Ok(match upstream.set_nonblocking(true).and_then(|()| register(&upstream, io)) {
+                Ok(()) => {
+                    let data = self.request_data(attempt.lease.server(), io.body);
+                    Outcome::Next(Phase::Sending { upstream, data, written: 0 })
+                }
+                Err(e) => Outcome::Failed(UpstreamError::before_send(e)),
+            }),
+            Phase::Connecting { peers, current, error } => Ok(self.connect(attempt, peers, current, error, io)),
+            Phase::Sending { upstream, data, written } => self.send(attempt, upstream, data, written, io),
+            Phase::Receiving { upstream, input } => self.receive(attempt, upstream, input, io),
+            Phase::Relaying { upstream, input, relay } => self.relay(attempt, upstream, input, relay, io),
+        }
+    }
+
+    fn connect(
+        &self,
+        attempt: &mut Attempt,
+        mut peers: VecDeque<Peer>,
+        mut current: Option<Stream>,
+        mut error: Option<io::Error>,
+        io: &mut ExchangeIo,
+    ) -> Outcome {
         loop {
-            let mut line = String::new();
-            match reader.read_line(&mut line) {
-                Ok(0) | Err(_) => break,
-                Ok(_) => {
-                    request.extend(line.as_bytes());
-                    if line.trim().is_empty() {
-                        break;
+            if let Some(upstream) = current.take() {
+                match upstream.is_connected() {
+                    Ok(true) => {
+                        let data = self.request_data(attempt.lease.server(), io.body);
+                        return Outcome::Next(Phase::Sending { upstream, data, written: 0 });
+                    }
+                    Ok(false) if Instant::now() < attempt.deadline => {
+                        return Outcome::Wait(Phase::Connecting { peers, current: Some(upstream), error }, Progress::Upstream(attempt.deadline));
                     }
+                    Ok(false) => error = Some(io::Error::new(io::ErrorKind::TimedOut, "connection timed out")),
+                    Err(e) => error = Some(e),
+                }
+            }
+
+            let Some(peer) = peers.pop_front() else {
+                let error = error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "no address to connect to"));
+                return Outcome::Failed(UpstreamError::before_send(error));
+            };
+            match Stream::connect_nonblocking(&peer).and_then(|upstream| register(&upstream, io).map(|()| upstream)) {
+                Ok(upstream) => {
+                    attempt.deadline = Instant::now() + self.proxy.config.upstream_connect_timeout();
+                    current = Some(upstream);
                 }
+                Err(e) => error = Some(e),
             }
         }
+    }
 
-        // Send request to upstream
-        upstream.write_all(&request).unwrap();
-        upstream.flush().unwrap();
+    // Writes the request, then the rest of a streamed body as the client sends it
+    fn send(&mut self, attempt: &mut Attempt, upstream: Stream, mut data: Vec<u8>, mut written: usize, io: &mut ExchangeIo) -> io::Result<Outcome> {
+        let mut chunk = [0; 16 * 1024];
+        loop {
+            while written < data.len() {
+                match (&upstream).write(&data[written..]) {
+                    Ok(0) => return Ok(self.write_failed(attempt, upstream, io::ErrorKind::WriteZero.into())),
+                    Ok(n) => {
+                        written += n;
+                        attempt.deadline = Instant::now() + self.proxy.config.upstream_read_timeout();
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        if Instant::now() >= attempt.deadline {
+                            return Ok(Outcome::Failed(UpstreamError::unanswered(timed_out())));
+                        }
+                        return Ok(Outcome::Wait(Phase::Sending { upstream, data, written }, Progress::Upstream(attempt.deadline)));
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Ok(self.write_failed(attempt, upstream, e)),
+                }
+            }
+            if !self.streamed || self.body_sent {
+                return Ok(Outcome::Next(Phase::Receiving { upstream, input: Vec::new() }));
+            }
 
-        // Read response headers first
-        let mut response = Vec::new();
-        let mut response_reader = BufReader::new(&upstream);
-        let mut content_length = None;
+            data.clear();
+            written = 0;
+            let chunked = io.body.length().is_none();
+            match io.body.read(&mut chunk) {
+                Ok(0) => {
+                    self.body_sent = true;
+                    if chunked {
+                        // Writing to a Vec can't fail
+                        let _ = ChunkedEncoder::new(&mut data).finish(io.body.trailers());
+                    }
+                }
+                Ok(n) => push_body(&mut data, &chunk[..n], chunked),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Outcome::Wait(Phase::Sending { upstream, data, written }, Progress::Body));
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // Nothing wrong with the upstream
+                Err(e) => {
+                    reject_body(e, io.out)?;
+                    return Ok(Outcome::Answered);
+                }
+            }
+        }
+    }
+
+    // The upstream may have answered a streamed body without waiting for the rest
+    fn write_failed(&self, attempt: &mut Attempt, upstream: Stream, error: io::Error) -> Outcome {
+        if !self.streamed {
+            return Outcome::Failed(UpstreamError::before_send(error));
+        }
+        attempt.write_error = Some(error);
+        Outcome::Next(Phase::Receiving { upstream, input: Vec::new() })
+    }
 
-        // Read headers and look for Content-Length
+    // Reads the response head and passes it on, the body follows
+    fn receive(&mut self, attempt: &mut Attempt, upstream: Stream, mut input: Vec<u8>, io: &mut ExchangeIo) -> io::Result<Outcome> {
+        // A failed write is what went wrong if no response came either
+        let failed = |attempt: &mut Attempt, error: UpstreamError| {
+            Outcome::Failed(attempt.write_error.take().map_or(error, UpstreamError::unanswered))
+        };
         loop {
-            let mut line = String::new();
-            match response_reader.read_line(&mut line) {
-                Ok(0) | Err(_) => break,
+            match parse_response_head(&input) {
+                Ok(Some((end, framing))) => {
+                    // Interim responses such as 100 Continue are for the upstream's client, which is us
+                    let status = status(&input[..end]);
+                    if status.starts_with(b"1") && status != b"101" {
+                        input.drain(..end);
+                        continue;
+                    }
+                    let head: Vec<u8> = input.drain(..end).collect();
+                    let relay = self.respond(attempt, &head, framing, io.out)?;
+                    return Ok(Outcome::Next(Phase::Relaying { upstream, input, relay }));
+                }
+                Ok(None) => {}
+                Err(e) => return Ok(failed(attempt, e)),
+            }
+
+            let start = input.len();
+            input.resize(start + 16 * 1024, 0);
+            let read = (&upstream).read(&mut input[start..]);
+            input.truncate(start + *read.as_ref().unwrap_or(&0));
+            let error = match read {
+                Ok(0) if input.is_empty() => UpstreamError::unanswered(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "upstream closed the connection without a response",
+                )),
+                Ok(0) => UpstreamError::after_send(io::Error::new(io::ErrorKind::UnexpectedEof, "upstream closed the connection mid-head")),
                 Ok(_) => {
-                    response.extend(line.as_bytes());
+                    attempt.deadline = Instant::now() + self.proxy.config.upstream_read_timeout();
+                    continue;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < attempt.deadline => {
+                    return Ok(Outcome::Wait(Phase::Receiving { upstream, input }, Progress::Upstream(attempt.deadline)));
+                }
+                Err(e) => {
+                    let e = if e.kind() == io::ErrorKind::WouldBlock { timed_out() } else { e };
+                    if input.is_empty() { UpstreamError::unanswered(e) } else { UpstreamError::after_send(e) }
+                }
+            };
+            return Ok(failed(attempt, error));
+        }
+    }
 
-                    // Check for Content-Length header
-                    if line.to_lowercase().starts_with("content-length:") {
-                        content_length = line.split(':').nth(1)
-                            .and_then(|s| s.trim().parse::<usize>().ok());
+    // Sends the response head to the client, and works out how the body follows it. Chunked
+    // bodies are decoded for HTTP/1.0 clients, and HTTP/1.1 bodies running until close get
+    // chunked so the end of the message is explicit.
+    fn respond(&self, attempt: &mut Attempt, head: &[u8], mut framing: BodyFraming, out: &mut dyn Output) -> io::Result<Relay> {
+        // HEAD responses and 1xx, 204 and 304 never have a body, whatever the headers say
+        let status = status(head);
+        if self.request.method() == "HEAD" || status.starts_with(b"1") || status == b"204" || status == b"304" {
+            framing = BodyFraming::None;
+        }
+        attempt.keep_alive = upstream_keeps_alive(head) && attempt.write_error.is_none();
+        self.proxy.health.record_success(attempt.lease.server());
+
+        let http10_client = self.request.version() == "HTTP/1.0";
+        Ok(match framing {
+            BodyFraming::None => {
+                out.write_all(head)?;
+                Relay::Length(0)
+            }
+            BodyFraming::Length(length) => {
+                out.write_all(head)?;
+                Relay::Length(length)
+            }
+            BodyFraming::Chunked => {
+                if http10_client {
+                    out.write_all(&rewrite_head(head, "transfer-encoding", None))?;
+                } else {
+                    out.write_all(head)?;
+                }
+                Relay::Chunked { parser: ChunkedParser::new(u64::MAX), decode: http10_client }
+            }
+            BodyFraming::UntilClose => {
+                attempt.keep_alive = false;
+                let chunk = !http10_client && head.starts_with(b"HTTP/1.1 ");
+                if chunk {
+                    out.write_all(&rewrite_head(head, "transfer-encoding", Some("Transfer-Encoding: chunked")))?;
+                } else {
+                    out.write_all(head)?;
+                }
+                Relay::UntilClose(chunk)
+            }
+        })
+    }
+
+    // Streams the body to the client without holding it in memory, and hands the connection
+    // to the pool once the whole response was read and it can be reused. Part of the response
+    // went out already, so failing from here on leaves the client connection broken.
+    fn relay(&self, attempt: &mut Attempt, upstream: Stream, mut input: Vec<u8>, mut relay: Relay, io: &mut ExchangeIo) -> io::Result<Outcome> {
+        loop {
+            if relay.pass_on(&mut input, io.out)? {
+                // Anything already buffered past the response wasn't asked for
+                let deregistered = io.registry.deregister(&mut SourceFd(&upstream.as_raw_fd())).is_ok();
+                if attempt.keep_alive && input.is_empty() && deregistered {
+                    self.proxy.pool.put(attempt.lease.server(), upstream);
+                }
+                return Ok(Outcome::Answered);
+            }
+            if io.out.is_full() {
+                return Ok(Outcome::Wait(Phase::Relaying { upstream, input, relay }, Progress::Output));
+            }
+
+            let start = input.len();
+            input.resize(start + 64 * 1024, 0);
+            let read = (&upstream).read(&mut input[start..]);
+            input.truncate(start + *read.as_ref().unwrap_or(&0));
+            match read {
+                Ok(0) => {
+                    relay.close(io.out)?;
+                    return Ok(Outcome::Answered);
+                }
+                Ok(_) => attempt.deadline = Instant::now() + self.proxy.config.upstream_read_timeout(),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= attempt.deadline {
+                        return Err(timed_out());
                     }
+                    return Ok(Outcome::Wait(Phase::Relaying { upstream, input, relay }, Progress::Upstream(attempt.deadline)));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Deals with an attempt that failed before any of the response went to the client. Returns
+    // whether the request is answered, with an error response, or goes again.
+    fn failed(&mut self, attempt: Attempt, e: UpstreamError, out: &mut dyn Output) -> io::Result<bool> {
+        let server = attempt.lease.server().to_string();
+        let method = self.request.method();
+        // The upstream may close an idle connection just as we pick it up. Writing the request
+        // fails then, and any request goes again on a new connection. A request that went out
+        // in full may have been acted on, so only idempotent ones go again after that.
+        if attempt.pooled && ((e.stale() && !e.request_sent) || (is_idempotent(method) && !e.timed_out())) {
+            println!("Pooled connection to {} failed: {}", server, e.error);
+            self.current = Some((Attempt::new(attempt.lease, false), connecting(&server)));
+            return Ok(false);
+        }
 
-                    // End of headers
-                    if line.trim().is_empty() {
-                        break;
+        println!("Upstream {} failed: {}", server, e.error);
+        self.proxy.health.record_failure(&server);
+        self.tried.push(server);
+        self.status = if e.timed_out() { 504 } else { 502 };
+        // The upstream may have acted on it already, so only idempotent requests get another
+        // go, and only as long as the body is at hand
+        let retry = !e.request_sent || (is_idempotent(method) && !self.streamed);
+        if !retry {
+            out.write_all(simple_response(self.status).as_bytes())?;
+        }
+        Ok(!retry)
+    }
+}
+
+impl Exchange for ProxyExchange {
+    fn advance(&mut self, io: &mut ExchangeIo) -> io::Result<Progress> {
+        // Bodies that fit in BODY_BUFFER are held on to, so the request can go again after a
+        // failed attempt
+        if !self.read_ahead {
+            match read_ahead(&mut self.buffered, io.body) {
+                Ok(()) => {
+                    self.read_ahead = true;
+                    self.streamed = !io.body.is_done();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Progress::Body),
+                Err(e) => {
+                    reject_body(e, io.out)?;
+                    return Ok(Progress::Done);
+                }
+            }
+        }
+
+        loop {
+            let (mut attempt, phase) = match self.current.take().or_else(|| self.next_attempt()) {
+                Some(current) => current,
+                None => {
+                    let status = if self.tried.is_empty() {
+                        println!("No upstream server available");
+                        503
+                    } else {
+                        self.status
+                    };
+                    io.out.write_all(simple_response(status).as_bytes())?;
+                    return Ok(Progress::Done);
+                }
+            };
+
+            match self.step(&mut attempt, phase, io)? {
+                Outcome::Next(phase) => self.current = Some((attempt, phase)),
+                Outcome::Wait(phase, progress) => {
+                    attempt.waiting_upstream = matches!(progress, Progress::Upstream(_));
+                    self.current = Some((attempt, phase));
+                    return Ok(progress);
+                }
+                Outcome::Answered => return Ok(Progress::Done),
+                Outcome::Failed(e) => {
+                    if self.failed(attempt, e, io.out)? {
+                        return Ok(Progress::Done);
                     }
                 }
             }
         }
+    }
+}
 
-        // If we have a Content-Length, read exactly that many bytes
-        if let Some(length) = content_length {
-            let mut body = vec![0; length];
-            response_reader.read_exact(&mut body).unwrap();
-            response.extend(body);
-        } else {
-            // If no Content-Length, read until connection closes
-            let mut buffer = [0; 4096];
-            loop {
-                match upstream.read(&mut buffer) {
-                    Ok(0) => break,
-                    Ok(n) => response.extend_from_slice(&buffer[..n]),
-                    Err(_) => break,
+impl ServerHandler {
+    pub fn new(
+        config: crate::config::Config,
+        lb: SharedLoadBalancer,
+        health: Arc<HealthRegistry>,
+        pool: Arc<UpstreamPool>,
+    ) -> ServerHandler {
+        ServerHandler { config: Arc::new(config), lb, health, pool }
+    }
+}
+
+impl Handler for ServerHandler {
+    fn handle(&self, request: &HttpRequest, body: &mut RequestBody, out: &mut dyn Write) -> io::Result<()> {
+        drive(&mut ProxyExchange::new(self.clone(), request.clone()), body, out)
+    }
+
+    fn exchange(&self, request: &HttpRequest) -> Option<Box<dyn Exchange>> {
+        Some(Box::new(ProxyExchange::new(self.clone(), request.clone())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunked::ChunkedDecoder;
+    use crate::config::Config;
+    use crate::connection::{handle_connection, ConnectionSettings};
+    use crate::lbs::{LoadBalancer, RoundRobin};
+    use crate::http_validator::{HttpValidator, Status};
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::UnixListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use tempfile::{NamedTempFile, TempDir};
+
+    type Balancer = fn(&Config) -> Box<dyn LoadBalancer>;
+
+    fn handler(servers: &[String], extra: &str) -> (ServerHandler, Arc<HealthRegistry>) {
+        handler_with(servers, extra, |config| Box::new(RoundRobin::new(config.servers())))
+    }
+
+    fn handler_with(servers: &[String], extra: &str, balancer: Balancer) -> (ServerHandler, Arc<HealthRegistry>) {
+        let file = NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), format!("servers {}\n{}", servers.join(" "), extra)).unwrap();
+        let mut config = Config::new();
+        config.parse(file.path().to_str().unwrap()).unwrap();
+
+        let lb = balancer(&config);
+        let health = Arc::new(HealthRegistry::from_config(&config));
+        let pool = Arc::new(UpstreamPool::from_config(&config));
+        (ServerHandler::new(config, Arc::new(Mutex::new(lb)), health.clone(), pool), health)
+    }
+
+    fn closed_port() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    // Answers every request with `ok`, counting the connections it served
+    fn backend(hits: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                hits.fetch_add(1, Ordering::SeqCst);
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
                 }
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+            }
+        });
+        address
+    }
+
+    // Like `backend`, but keeps each connection open for as many requests as the proxy sends
+    fn persistent(hits: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                hits.fetch_add(1, Ordering::SeqCst);
+                std::thread::spawn(move || answer_all(stream.into()));
             }
+        });
+        address
+    }
+
+    // `persistent` on a Unix domain socket in `dir`
+    fn persistent_unix(hits: Arc<AtomicUsize>, dir: &TempDir) -> String {
+        let path = dir.path().join("backend.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                hits.fetch_add(1, Ordering::SeqCst);
+                std::thread::spawn(move || answer_all(stream.into()));
+            }
+        });
+        format!("unix:{}", path.display())
+    }
+
+    // Answers `ok` to every request on the connection until the proxy closes it
+    fn answer_all(stream: Stream) {
+        let mut reader = BufReader::new(&stream);
+        loop {
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                line.clear();
+            }
+            if line.is_empty() {
+                break;
+            }
+            let _ = (&stream).write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
         }
+    }
+
+    // Accepts connections and reads requests without ever answering
+    fn silent() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                std::thread::spawn(move || stream.read_to_end(&mut Vec::new()));
+            }
+        });
+        address
+    }
+
+    // Accepts connections and hangs up without answering
+    fn hangs_up() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buffer = [0; 1024];
+                let _ = stream.read(&mut buffer);
+            }
+        });
+        address
+    }
+
+    // Answers the first request on each connection, then closes it when the next one arrives,
+    // like an upstream whose idle timeout ran out just as the proxy reused the connection
+    fn closes_when_reused(hits: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                hits.fetch_add(1, Ordering::SeqCst);
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                let _ = (&stream).write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+                let _ = reader.read_line(&mut line);
+            }
+        });
+        address
+    }
 
-        String::from_utf8_lossy(&response).to_string()
+    // Serves a single canned response, whatever the request
+    fn canned(response: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                line.clear();
+            }
+            let _ = stream.write_all(&response);
+        });
+        address
     }
-}
\ No newline at end of file
+
+    // Serves a single canned response and keeps the connection open afterwards
+    fn keeps_open(response: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                line.clear();
+            }
+            let _ = (&stream).write_all(&response);
+            // Until the proxy hangs up
+            let _ = reader.read_to_end(&mut Vec::new());
+        });
+        address
+    }
+
+    // Records the head and the next `length` bytes of body of a single request
+    fn recorder(length: usize) -> (String, std::sync::mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut head = String::new();
+            while reader.read_line(&mut head).is_ok_and(|n| n > 2) {}
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n");
+            sender.send((head, body)).unwrap();
+        });
+        (address, receiver)
+    }
+
+    // Head, decoded body and X-Sum trailer
+    type Recorded = (String, Vec<u8>, Option<String>);
+
+    // Records the head, the decoded chunked body and the X-Sum trailer of a single request
+    fn chunked_recorder() -> (String, std::sync::mpsc::Receiver<Recorded>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut head = String::new();
+            while reader.read_line(&mut head).is_ok_and(|n| n > 2) {}
+            let mut decoder = ChunkedDecoder::new(&mut reader);
+            let mut body = Vec::new();
+            decoder.read_to_end(&mut body).unwrap();
+            let sum = decoder.trailers().get("x-sum").map(str::to_string);
+            let _ = stream.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n");
+            sender.send((head, body, sum)).unwrap();
+        });
+        (address, receiver)
+    }
+
+    fn send_bytes(handler: &ServerHandler, request: &[u8]) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let request = request.to_vec();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&request).unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            response
+        });
+        let stream = listener.accept().unwrap().0.into();
+        handle_connection(&stream, handler, &ConnectionSettings::default()).unwrap();
+        drop(stream);
+        client.join().unwrap()
+    }
+
+    fn send(handler: &ServerHandler, request: &[u8]) -> String {
+        String::from_utf8(send_bytes(handler, request)).unwrap()
+    }
+
+    #[test]
+    fn test_retries_refused_connection_on_next_server() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let dead = closed_port();
+        let (handler, health) = handler(&[dead.clone(), backend(hits.clone())], "");
+
+        // Even a POST is safe to retry when the connection was never established
+        let response = send(&handler, b"POST /form HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("ok"));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert!(!health.is_up(&dead));
+
+        // The ejected server is skipped from now on
+        let response = send(&handler, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_idempotent_request_retried_after_hang_up() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (handler, _) = handler(&[hangs_up(), backend(hits.clone())], "");
+
+        let response = send(&handler, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_non_idempotent_request_not_retried_after_send() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (handler, _) = handler(&[hangs_up(), backend(hits.clone())], "");
+
+        let response = send(&handler, b"POST /pay HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"));
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+    }
+
+    fn binary_body() -> Vec<u8> {
+        // Plenty of invalid UTF-8 in there
+        (0..=255u8).rev().cycle().take(3 * 1024 * 1024).collect()
+    }
+
+    #[test]
+    fn test_binary_body_passes_through() {
+        let body = binary_body();
+        let mut response = format!("HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        response.extend(&body);
+        let (handler, _) = handler(&[canned(response.clone())], "");
+
+        assert_eq!(send_bytes(&handler, b"GET /big.png HTTP/1.1\r\nHost: test\r\n\r\n"), response);
+    }
+
+    #[test]
+    fn test_binary_body_without_length_passes_through() {
+        let mut response = b"HTTP/1.0 200 OK\r\nContent-Type: application/gzip\r\n\r\n".to_vec();
+        response.extend(binary_body());
+        let (handler, _) = handler(&[canned(response.clone())], "");
+
+        // The client can only find the end by the connection closing
+        let mut expected = b"HTTP/1.0 200 OK\r\nContent-Type: application/gzip\r\nConnection: close\r\n\r\n".to_vec();
+        expected.extend(binary_body());
+        assert_eq!(send_bytes(&handler, b"GET /archive.gz HTTP/1.1\r\nHost: test\r\n\r\n"), expected);
+    }
+
+    #[test]
+    fn test_bodyless_responses() {
+        let response = b"HTTP/1.1 304 Not Modified\r\nETag: \"x\"\r\n\r\n".to_vec();
+        let (handler, _) = handler(&[canned(response.clone())], "");
+        assert_eq!(send_bytes(&handler, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n"), response);
+    }
+
+    #[test]
+    fn test_non_utf8_header_values_pass_through() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Disposition: attachment; filename=\"caf\xe9.txt\"\r\nContent-Length: 2\r\n\r\nok".to_vec();
+        let (handler, _) = handler(&[canned(response.clone())], "");
+        assert_eq!(send_bytes(&handler, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n"), response);
+    }
+
+    #[test]
+    fn test_malformed_response_heads_rejected() {
+        let endless = [b"HTTP/1.1 200 OK\r\nX-Filler: ".to_vec(), vec![b'a'; 2 * MAX_RESPONSE_HEAD]].concat();
+        let heads = [
+            endless,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 12abc\r\n\r\nhello".to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: -1\r\n\r\nhello".to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5, 6\r\n\r\nhello".to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello".to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n".to_vec(),
+            b"SSH-2.0-OpenSSH\r\n\r\n".to_vec(),
+        ];
+        for head in heads {
+            let (handler, _) = handler(&[canned(head)], "");
+            let response = send(&handler, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n");
+            assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"), "{}", response);
+        }
+
+        // Agreeing values are only repetition
+        let (handler, _) = handler(&[canned(b"HTTP/1.1 200 OK\r\nContent-Length: 5, 5\r\n\r\nhello".to_vec())], "");
+        assert!(send(&handler, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n").ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_non_utf8_request_headers_forwarded_raw() {
+        let request = HttpRequest::parse(b"POST / HTTP/1.1\r\nHost: a\r\nX-Name: caf\xe9\r\n\r\n").unwrap();
+        let mut trailers = Headers::new();
+        trailers.append("X-Sum", b"\xff");
+        let forwarded = upstream_request(&request, "a:80", true, RequestFraming::Buffered(b"hi", &trailers));
+        let line = b"\r\nX-Name: caf\xe9\r\n";
+        assert!(forwarded.windows(line.len()).any(|w| w == line));
+        assert!(forwarded.ends_with(b"\r\n0\r\nX-Sum: \xff\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let (handler, _) = handler(&[closed_port(), closed_port(), closed_port()], "upstream_retries 1\n");
+        let response = send(&handler, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"));
+
+        // Both attempts ejected their server, only the third one is left
+        let response = send(&handler, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"));
+
+        // Everything is ejected now
+        let response = send(&handler, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+    }
+
+    #[test]
+    fn test_large_post_body_is_forwarded() {
+        let body: Vec<u8> = (0..=255u8).cycle().take(5 * 1024 * 1024 + 7).collect();
+        let (upstream, received) = recorder(body.len());
+        let (handler, _) = handler(&[upstream], "");
+
+        let mut request = format!("POST /upload HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        request.extend(&body);
+        let response = send(&handler, &request);
+        assert!(response.starts_with("HTTP/1.1 201 Created"));
+
+        let (head, forwarded) = received.recv().unwrap();
+        assert!(head.starts_with("POST /upload HTTP/1.1\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(forwarded == body);
+    }
+
+    #[test]
+    fn test_large_chunked_body_is_streamed() {
+        let body: Vec<u8> = (0..=255u8).cycle().take(3 * BODY_BUFFER + 5).collect();
+        let (upstream, received) = chunked_recorder();
+        let (handler, _) = handler(&[upstream], "");
+
+        let mut request = b"PUT /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+        let mut encoder = ChunkedEncoder::new(&mut request);
+        encoder.write_all(&body).unwrap();
+        let mut trailers = Headers::new();
+        trailers.append("X-Sum", b"7");
+        encoder.finish(&trailers).unwrap();
+        assert!(send(&handler, &request).starts_with("HTTP/1.1 201 Created"));
+
+        // Too long to wait for, so it goes on chunked as it comes in
+        let (head, forwarded, sum) = received.recv().unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!head.contains("Content-Length"));
+        assert!(forwarded == body);
+        assert_eq!(sum.as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn test_streamed_body_is_sent_once() {
+        let body = vec![b'x'; 2 * BODY_BUFFER];
+        let mut request = format!("PUT /doc HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        request.extend(&body);
+
+        // A server that can't be reached is skipped
+        let (upstream, received) = recorder(body.len());
+        let (failing_over, _) = handler(&[closed_port(), upstream], "");
+        assert!(send(&failing_over, &request).starts_with("HTTP/1.1 201 Created"));
+        assert!(received.recv().unwrap().1 == body);
+
+        // One that hangs up halfway isn't, the body is gone by then
+        let (upstream, received) = recorder(body.len());
+        let (hanging_up, _) = handler(&[hangs_up(), upstream], "");
+        assert!(send(&hanging_up, &request).starts_with("HTTP/1.1 502 Bad Gateway"));
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_chunked_post_body_is_forwarded() {
+        let (upstream, received) = recorder(11);
+        let (handler, _) = handler(&[upstream], "");
+
+        let request = b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\nConnection: keep-alive\r\n\r\n5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\n\r\n";
+        let response = send(&handler, request);
+        assert!(response.starts_with("HTTP/1.1 201 Created"));
+
+        // Decoded on the way in, the upstream gets a plain length
+        let (head, body) = received.recv().unwrap();
+        assert!(head.contains("Content-Length: 11\r\n"));
+        assert!(!head.contains("Transfer-Encoding"));
+        assert!(!head.contains("keep-alive"));
+        assert_eq!(body, b"hello world");
+    }
+
+    #[test]
+    fn test_trailers_are_forwarded() {
+        let forwarded = b"b\r\nhello world\r\n0\r\nX-Checksum: 42\r\n\r\n";
+        let (upstream, received) = recorder(forwarded.len());
+        let (handler, _) = handler(&[upstream], "");
+
+        let request = b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\nX-Checksum: 42\r\n\r\n";
+        assert!(send(&handler, request).starts_with("HTTP/1.1 201 Created"));
+
+        let (head, body) = received.recv().unwrap();
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert_eq!(body, forwarded);
+    }
+
+    #[test]
+    fn test_ambiguous_body_framing_rejected() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (handler, _) = handler(&[backend(hits.clone())], "");
+
+        let response = send(&handler, b"POST / HTTP/1.1\r\nHost: test\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        let response = send(&handler, b"POST / HTTP/1.1\r\nHost: test\r\nContent-Length: lots\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_put_with_body_retried_after_hang_up() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (handler, _) = handler(&[hangs_up(), backend(hits.clone())], "");
+
+        // A small body is held on to until the upstream answers, so it can be sent again
+        let response = send(&handler, b"PUT /doc HTTP/1.1\r\nHost: test\r\nContent-Length: 3\r\n\r\nabc");
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_chunked_response_on_open_connection() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\nX-Sum: 1\r\n\r\n".to_vec();
+        let (handler, _) = handler(&[keeps_open(response.clone())], "");
+        // Would hang if the proxy waited for the upstream to close
+        assert_eq!(send_bytes(&handler, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n"), response);
+    }
+
+    #[test]
+    fn test_chunked_response_decoded_for_http10_client() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Type: text/plain\r\n\r\n5\r\nhello\r\n0\r\n\r\n".to_vec();
+        let (handler, _) = handler(&[keeps_open(response)], "");
+        let response = send(&handler, b"GET / HTTP/1.0\r\n\r\n");
+        assert_eq!(response, "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\nhello");
+    }
+
+    #[test]
+    fn test_response_until_close_is_rechunked() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nhello world".to_vec();
+        let (handler, _) = handler(&[canned(response)], "");
+        let response = send(&handler, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n"));
+
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        let mut decoded = String::new();
+        ChunkedDecoder::new(body.as_bytes()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "hello world");
+    }
+
+    #[test]
+    fn test_response_until_close_kept_for_http10_client() {
+        let (handler, _) = handler(&[canned(b"HTTP/1.1 200 OK\r\n\r\nhello".to_vec())], "");
+        assert_eq!(send(&handler, b"GET / HTTP/1.0\r\n\r\n"), "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\nhello");
+    }
+
+    #[test]
+    fn test_reuses_upstream_connections_with_every_balancer() {
+        let balancers: [Balancer; 5] = [
+            |config| Box::new(crate::lbs::None::new(config.servers())),
+            |config| Box::new(RoundRobin::new(config.servers())),
+            |config| Box::new(crate::lbs::WeightedRoundRobin::new(config.upstreams())),
+            |config| Box::new(crate::lbs::LeastConn::new(config.servers())),
+            |config| Box::new(crate::lbs::Source::new(config.servers())),
+        ];
+        for balancer in balancers {
+            let hits = Arc::new(AtomicUsize::new(0));
+            let server = persistent(hits.clone());
+            let (handler, _) = handler_with(std::slice::from_ref(&server), "", balancer);
+
+            for _ in 0..3 {
+                assert!(send(&handler, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").ends_with("\r\n\r\nok"));
+            }
+            assert_eq!(hits.load(Ordering::SeqCst), 1);
+            assert_eq!(handler.pool.idle_count(&server), 1);
+        }
+    }
+
+    #[test]
+    fn test_unix_socket_upstreams() {
+        let dir = TempDir::new().unwrap();
+        let hits = Arc::new(AtomicUsize::new(0));
+        let server = persistent_unix(hits.clone(), &dir);
+        let (unix, health) = handler(std::slice::from_ref(&server), "");
+
+        for _ in 0..3 {
+            assert!(send(&unix, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").ends_with("\r\n\r\nok"));
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        assert_eq!(unix.pool.idle_count(&server), 1);
+        assert!(health.down_servers().is_empty());
+
+        let mut validator = HttpValidator::new();
+        assert_eq!(validator.feed(b"GET / HTTP/1.0\r\n\r\n").unwrap(), Status::Complete);
+        let request = String::from_utf8(upstream_request(&validator.get_request(), &server, true, RequestFraming::Buffered(b"", &Headers::new()))).unwrap();
+        assert!(request.contains("\r\nHost: localhost\r\n"));
+
+        // A socket nobody listens on counts as a failed server
+        let missing = format!("unix:{}", dir.path().join("missing.sock").display());
+        let (failing_over, health) = handler(&[missing.clone(), server], "");
+        for _ in 0..2 {
+            assert!(send(&failing_over, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").ends_with("\r\n\r\nok"));
+        }
+        assert_eq!(health.down_servers(), vec![missing]);
+    }
+
+    #[test]
+    fn test_replaces_closed_pooled_connection() {
+        // Closes every connection after one response
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (handler, health) = handler(&[backend(hits.clone())], "");
+
+        for _ in 0..3 {
+            assert!(send(&handler, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").ends_with("\r\n\r\nok"));
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert!(health.down_servers().is_empty());
+    }
+
+    #[test]
+    fn test_stale_pooled_connection_is_no_failure() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (handler, health) = handler(&[closes_when_reused(hits.clone())], "");
+
+        assert!(send(&handler, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").ends_with("\r\n\r\nok"));
+        let response = send(&handler, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert!(health.down_servers().is_empty());
+    }
+
+    #[test]
+    fn test_sent_post_not_resent_after_pooled_connection_closes() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (handler, _) = handler(&[closes_when_reused(hits.clone())], "");
+
+        assert!(send(&handler, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").ends_with("\r\n\r\nok"));
+        // All of it went out before the upstream closed, it may have been acted on
+        let response = send(&handler, b"POST /form HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\na=1");
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"), "{}", response);
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_does_not_pool_closing_connections() {
+        let server = canned(b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok".to_vec());
+        let (closing, _) = handler(std::slice::from_ref(&server), "");
+        assert!(send(&closing, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").ends_with("ok"));
+        assert_eq!(closing.pool.idle_count(&server), 0);
+
+        let server = keeps_open(b"HTTP/1.0 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec());
+        let (http10, _) = handler(std::slice::from_ref(&server), "");
+        assert!(send(&http10, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").ends_with("ok"));
+        assert_eq!(http10.pool.idle_count(&server), 0);
+    }
+
+    #[test]
+    fn test_upstream_keepalive_off() {
+        let (server, received) = recorder(0);
+        let (off, _) = handler(&[server], "upstream_keepalive 0\n");
+        send(&off, b"GET /a HTTP/1.0\r\n\r\n");
+        let (head, _) = received.recv().unwrap();
+        assert!(head.starts_with("GET /a HTTP/1.1\r\nHost: 127.0.0.1:"));
+        assert!(head.ends_with("Connection: close\r\n\r\n"));
+
+        let (server, received) = recorder(0);
+        let (on, _) = handler(&[server], "");
+        send(&on, b"GET /a HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        let (head, _) = received.recv().unwrap();
+        assert_eq!(head, "GET /a HTTP/1.1\r\nHost: a\r\n\r\n");
+    }
+
+    #[test]
+    fn test_slow_upstream_times_out() {
+        let (get, health) = handler(&[silent()], "upstream_read_timeout 100ms\n");
+        let started = std::time::Instant::now();
+        let response = send(&get, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(health.down_servers().len(), 1);
+
+        // Not retried, but still a timeout
+        let (post, _) = handler(&[silent(), silent()], "upstream_read_timeout 100ms\n");
+        let response = send(&post, b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\n\r\nhi");
+        assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"));
+    }
+}
@@ -0,0 +1,297 @@
+use crate::access::AccessControl;
+use crate::handlers::{resolve_script, simple_response, Handler};
+use crate::http_validator::HttpRequest;
+use crate::request_body::RequestBody;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Serves files under root, handing scripts over to the CGI or FastCGI handler
+pub struct StaticFileHandler {
+    config: crate::config::Config,
+    access: AccessControl,
+    scripts: Box<dyn Handler>,
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "txt" => "text/plain; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "wasm" => "application/wasm",
+        "mp4" => "video/mp4",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+// Formats a timestamp as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let seconds_of_day = secs % 86400;
+
+    // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+impl StaticFileHandler {
+    pub fn new(config: crate::config::Config, scripts: Box<dyn Handler>) -> Self {
+        Self {
+            access: AccessControl::new(&config),
+            config,
+            scripts,
+        }
+    }
+
+    // The index file and anything sharing its extension is executed rather than served
+    fn is_script(&self, path: &Path) -> bool {
+        let index = Path::new(self.config.index());
+        if path.file_name() == index.file_name() {
+            return true;
+        }
+        match index.extension() {
+            Some(extension) => path.extension() == Some(extension),
+            None => false,
+        }
+    }
+
+    fn serve_file(&self, request: &HttpRequest, path: &Path, out: &mut dyn Write) -> io::Result<()> {
+        if request.method() != "GET" && request.method() != "HEAD" {
+            return out.write_all(b"HTTP/1.1 405 Method Not Allowed\r\n\
+                Allow: GET, HEAD\r\n\
+                Content-Length: 0\r\n\
+                \r\n");
+        }
+
+        let opened = File::open(path).and_then(|file| file.metadata().map(|metadata| (file, metadata)));
+        let (mut file, metadata) = match opened {
+            Ok(opened) => opened,
+            Err(e) => {
+                // Missing files are the client's doing and not worth a line each
+                if e.kind() != io::ErrorKind::NotFound {
+                    eprintln!("Error reading {}: {}", path.display(), e);
+                }
+                return out.write_all(simple_response(404).as_bytes());
+            }
+        };
+        let last_modified = metadata.modified().map(http_date).ok();
+
+        if let (Some(since), Some(modified)) = (request.header("if-modified-since"), &last_modified) {
+            if since == modified {
+                let head = format!("HTTP/1.1 304 Not Modified\r\nLast-Modified: {}\r\n\r\n", modified);
+                return out.write_all(head.as_bytes());
+            }
+        }
+
+        let mut head = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+            content_type(path),
+            metadata.len()
+        );
+        if let Some(modified) = last_modified {
+            head.push_str(&format!("Last-Modified: {}\r\n", modified));
+        }
+        head.push_str("\r\n");
+        out.write_all(head.as_bytes())?;
+
+        if request.method() != "HEAD" {
+            io::copy(&mut file, out)?;
+        }
+        Ok(())
+    }
+}
+
+impl Handler for StaticFileHandler {
+    fn handle(&self, request: &HttpRequest, body: &mut RequestBody, out: &mut dyn Write) -> io::Result<()> {
+        let target = match resolve_script(self.config.root(), self.config.index(), request.path()) {
+            Some(target) => target,
+            None => return out.write_all(simple_response(404).as_bytes()),
+        };
+
+        if let Some(status) = self.access.check(&target.script_name).status() {
+            return out.write_all(simple_response(status).as_bytes());
+        }
+
+        if self.is_script(&target.script_filename) {
+            return self.scripts.handle(request, body, out);
+        }
+        // Only scripts get to have PATH_INFO
+        if !target.path_info.is_empty() {
+            return out.write_all(simple_response(404).as_bytes());
+        }
+        self.serve_file(request, &target.script_filename, out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::fs;
+    use tempfile::{tempdir, NamedTempFile, TempDir};
+
+    struct FakeScripts;
+
+    impl Handler for FakeScripts {
+        fn handle(&self, request: &HttpRequest, _body: &mut RequestBody, out: &mut dyn Write) -> io::Result<()> {
+            write!(out, "script {}", request.target())
+        }
+    }
+
+    fn respond(handler: &StaticFileHandler, request: &HttpRequest) -> Vec<u8> {
+        let mut out = Vec::new();
+        handler.handle(request, &mut RequestBody::empty(), &mut out).unwrap();
+        out
+    }
+
+    fn respond_str(handler: &StaticFileHandler, request: &HttpRequest) -> String {
+        String::from_utf8(respond(handler, request)).unwrap()
+    }
+
+    fn handler(root: &TempDir) -> StaticFileHandler {
+        let file = NamedTempFile::new().unwrap();
+        fs::write(
+            file.path(),
+            format!("root {}\nindex index.php\ndeny_files .env\ndeny_directories *\nallow_directories assets\n", root.path().display()),
+        ).unwrap();
+        let mut config = Config::new();
+        config.parse(file.path().to_str().unwrap()).unwrap();
+        StaticFileHandler::new(config, Box::new(FakeScripts))
+    }
+
+    fn request(method: &str, uri: &str) -> HttpRequest {
+        HttpRequest::parse(format!("{} {} HTTP/1.1\r\nHost: test\r\n\r\n", method, uri).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_http_date() {
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            http_date(UNIX_EPOCH + std::time::Duration::from_secs(784111777)),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+        assert_eq!(
+            http_date(UNIX_EPOCH + std::time::Duration::from_secs(951782400)),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn test_serves_static_file() {
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("assets")).unwrap();
+        fs::write(root.path().join("assets/app.css"), "body {}").unwrap();
+        let handler = handler(&root);
+
+        let response = respond_str(&handler, &request("GET", "/assets/app.css?v=2"));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/css; charset=utf-8\r\n"));
+        assert!(response.contains("Content-Length: 7\r\n"));
+        assert!(response.contains("Last-Modified: "));
+        assert!(response.ends_with("\r\n\r\nbody {}"));
+
+        let response = respond_str(&handler, &request("HEAD", "/assets/app.css"));
+        assert!(response.contains("Content-Length: 7\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        let response = respond_str(&handler, &request("POST", "/assets/app.css"));
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let root = tempdir().unwrap();
+        fs::write(root.path().join("a.txt"), "hi").unwrap();
+        let handler = handler(&root);
+        let modified = http_date(fs::metadata(root.path().join("a.txt")).unwrap().modified().unwrap());
+
+        let req = HttpRequest::parse(format!("GET /a.txt HTTP/1.1\r\nHost: test\r\nIf-Modified-Since: {}\r\n\r\n", modified).as_bytes()).unwrap();
+        assert!(respond_str(&handler, &req).starts_with("HTTP/1.1 304 Not Modified\r\n"));
+    }
+
+    #[test]
+    fn test_scripts_go_to_script_handler() {
+        let root = tempdir().unwrap();
+        fs::write(root.path().join("index.php"), "").unwrap();
+        fs::write(root.path().join("login.php"), "").unwrap();
+        fs::write(root.path().join("readme.txt"), "").unwrap();
+        let handler = handler(&root);
+
+        assert_eq!(respond_str(&handler, &request("GET", "/")), "script /");
+        assert_eq!(respond_str(&handler, &request("POST", "/login.php/step/2")), "script /login.php/step/2");
+        assert!(respond_str(&handler, &request("GET", "/readme.txt/extra")).starts_with("HTTP/1.1 404"));
+        assert!(respond_str(&handler, &request("GET", "/missing.css")).starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_binary_file_is_served_byte_for_byte() {
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("assets")).unwrap();
+        let image: Vec<u8> = (0..=255u8).cycle().take(100_000).collect();
+        fs::write(root.path().join("assets/logo.png"), &image).unwrap();
+        let handler = handler(&root);
+
+        let response = respond(&handler, &request("GET", "/assets/logo.png"));
+        let head_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let head = String::from_utf8(response[..head_end].to_vec()).unwrap();
+        assert!(head.contains("Content-Type: image/png\r\n"));
+        assert!(head.contains("Content-Length: 100000\r\n"));
+        assert_eq!(&response[head_end..], image.as_slice());
+    }
+
+    #[test]
+    fn test_access_rules_are_enforced() {
+        let root = tempdir().unwrap();
+        fs::create_dir(root.path().join("vendor")).unwrap();
+        fs::write(root.path().join("vendor/autoload.php"), "").unwrap();
+        fs::write(root.path().join(".env"), "SECRET=1").unwrap();
+        let handler = handler(&root);
+
+        assert!(respond_str(&handler, &request("GET", "/vendor/autoload.php")).starts_with("HTTP/1.1 403 Forbidden"));
+        assert!(respond_str(&handler, &request("GET", "/.env")).starts_with("HTTP/1.1 404 Not Found"));
+    }
+}
@@ -0,0 +1,296 @@
+use crate::config::{Config, UpstreamServer};
+use crate::socket::{self, Stream};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ServerHealth {
+    down: bool,
+    // Consecutive probe results pointing the other way than the current state
+    streak: u32,
+    // Failed proxy attempts counted since window_start
+    failures: u32,
+    window_start: Option<Instant>,
+    ejected_until: Option<Instant>,
+}
+
+impl ServerHealth {
+    fn is_up(&self, now: Instant) -> bool {
+        !self.down && self.ejected_until.is_none_or(|until| until <= now)
+    }
+}
+
+// Up/down state of every upstream, shared between the checker and the request handlers.
+// Active checks flip a server to down after `fall` failed probes in a row and back up after
+// `rise` good ones. Passively, `max_fails` failed requests within `fail_timeout` eject a
+// server for `fail_timeout`.
+pub struct HealthRegistry {
+    servers: Mutex<HashMap<String, ServerHealth>>,
+    rise: u32,
+    fall: u32,
+    upstreams: Vec<UpstreamServer>,
+}
+
+impl HealthRegistry {
+    pub fn new(rise: u32, fall: u32) -> Self {
+        Self {
+            servers: Mutex::new(HashMap::new()),
+            rise,
+            fall,
+            upstreams: vec![],
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            upstreams: config.upstreams(),
+            ..Self::new(config.health_check_rise(), config.health_check_fall())
+        }
+    }
+
+    fn limits(&self, server: &str) -> (u32, Duration) {
+        let upstream = self.upstreams.iter()
+            .find(|u| u.address == server)
+            .cloned()
+            .unwrap_or_else(|| UpstreamServer::new(server));
+        (upstream.max_fails, upstream.fail_timeout)
+    }
+
+    // A request to the server failed, e.g. the connection was refused or reset
+    pub fn record_failure(&self, server: &str) {
+        let (max_fails, fail_timeout) = self.limits(server);
+        if max_fails == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        let health = servers.entry(server.to_string()).or_default();
+        if health.window_start.is_none_or(|start| now.duration_since(start) > fail_timeout) {
+            health.window_start = Some(now);
+            health.failures = 0;
+        }
+
+        health.failures += 1;
+        if health.failures >= max_fails {
+            println!("Upstream {} failed {} times, ejecting it for {:?}", server, health.failures, fail_timeout);
+            health.ejected_until = Some(now + fail_timeout);
+            health.window_start = None;
+            health.failures = 0;
+        }
+    }
+
+    pub fn record_success(&self, server: &str) {
+        if let Some(health) = self.servers.lock().unwrap().get_mut(server) {
+            health.failures = 0;
+            health.window_start = None;
+        }
+    }
+
+    pub fn record(&self, server: &str, healthy: bool) {
+        let mut servers = self.servers.lock().unwrap();
+        let health = servers.entry(server.to_string()).or_default();
+
+        // The probe agrees with the current state
+        if healthy != health.down {
+            health.streak = 0;
+            return;
+        }
+
+        health.streak += 1;
+        let threshold = if health.down { self.rise } else { self.fall };
+        if health.streak >= threshold {
+            health.down = !health.down;
+            health.streak = 0;
+            println!("Upstream {} is {}", server, if health.down { "down" } else { "up" });
+        }
+    }
+
+    // Servers we know nothing about are assumed up
+    pub fn is_up(&self, server: &str) -> bool {
+        let now = Instant::now();
+        self.servers.lock().unwrap().get(server).is_none_or(|h| h.is_up(now))
+    }
+
+    pub fn down_servers(&self) -> Vec<String> {
+        let now = Instant::now();
+        self.servers.lock().unwrap().iter()
+            .filter(|(_, health)| !health.is_up(now))
+            .map(|(server, _)| server.clone())
+            .collect()
+    }
+}
+
+// Probes a server with a TCP connect, or with a GET request when path is set, in which
+// case anything but a 2xx or 3xx answer counts as a failure
+pub fn probe(server: &str, path: &str, timeout: Duration) -> bool {
+    let mut stream = match Stream::connect(server, timeout) {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    if path.is_empty() {
+        return true;
+    }
+
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    // A Unix socket path is no host name
+    let host = if socket::unix_path(server).is_some() { "localhost" } else { server };
+    let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: servw-health\r\nConnection: close\r\n\r\n", path, host);
+    if stream.write_all(request.as_bytes()).is_err() {
+        return false;
+    }
+
+    let mut status_line = String::new();
+    if BufReader::new(&stream).read_line(&mut status_line).is_err() {
+        return false;
+    }
+    matches!(status_line.split_whitespace().nth(1), Some(code) if code.starts_with('2') || code.starts_with('3'))
+}
+
+pub struct HealthChecker;
+
+impl HealthChecker {
+    // Starts probing every configured server in the background, unless health checks are
+    // disabled. Probing stops once nothing else holds on to the registry.
+    pub fn spawn(config: &Config, registry: Arc<HealthRegistry>) -> Option<JoinHandle<()>> {
+        let interval = config.health_check_interval();
+        if interval.is_zero() {
+            return None;
+        }
+
+        let mut servers = config.servers();
+        servers.sort();
+        servers.dedup();
+        let timeout = config.health_check_timeout();
+        let path = config.health_check_path().to_string();
+
+        let registry = Arc::downgrade(&registry);
+        Some(std::thread::spawn(move || {
+            while registry.strong_count() > 0 {
+                for server in &servers {
+                    let healthy = probe(server, &path, timeout);
+                    match registry.upgrade() {
+                        Some(registry) => registry.record(server, healthy),
+                        None => return,
+                    }
+                }
+                std::thread::sleep(interval);
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_fall_and_rise() {
+        let registry = HealthRegistry::new(2, 3);
+        assert!(registry.is_up("a"));
+
+        registry.record("a", false);
+        registry.record("a", false);
+        assert!(registry.is_up("a"));
+        registry.record("a", false);
+        assert!(!registry.is_up("a"));
+        assert_eq!(registry.down_servers(), ["a"]);
+
+        registry.record("a", true);
+        assert!(!registry.is_up("a"));
+        registry.record("a", true);
+        assert!(registry.is_up("a"));
+        assert!(registry.down_servers().is_empty());
+    }
+
+    #[test]
+    fn test_success_resets_failure_streak() {
+        let registry = HealthRegistry::new(1, 2);
+        registry.record("a", false);
+        registry.record("a", true);
+        registry.record("a", false);
+        assert!(registry.is_up("a"));
+    }
+
+    fn registry_with(max_fails: u32, fail_timeout: Duration) -> HealthRegistry {
+        HealthRegistry {
+            upstreams: vec![UpstreamServer { max_fails, fail_timeout, ..UpstreamServer::new("a") }],
+            ..HealthRegistry::new(2, 3)
+        }
+    }
+
+    #[test]
+    fn test_passive_ejection() {
+        let registry = registry_with(2, Duration::from_millis(100));
+        registry.record_failure("a");
+        assert!(registry.is_up("a"));
+        registry.record_failure("a");
+        assert!(!registry.is_up("a"));
+        assert_eq!(registry.down_servers(), ["a"]);
+
+        // Back in rotation once fail_timeout has passed
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(registry.is_up("a"));
+    }
+
+    #[test]
+    fn test_failures_outside_window_do_not_add_up() {
+        let registry = registry_with(2, Duration::from_millis(50));
+        registry.record_failure("a");
+        std::thread::sleep(Duration::from_millis(80));
+        registry.record_failure("a");
+        assert!(registry.is_up("a"));
+
+        registry.record_success("a");
+        registry.record_failure("a");
+        assert!(registry.is_up("a"));
+    }
+
+    #[test]
+    fn test_max_fails_zero_never_ejects() {
+        let registry = registry_with(0, Duration::from_secs(10));
+        for _ in 0..10 {
+            registry.record_failure("a");
+        }
+        assert!(registry.is_up("a"));
+
+        // Servers missing from the config get the defaults of a single failure
+        registry.record_failure("b");
+        assert!(!registry.is_up("b"));
+    }
+
+    #[test]
+    fn test_tcp_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        assert!(probe(&server, "", Duration::from_secs(1)));
+
+        drop(listener);
+        assert!(!probe(&server, "", Duration::from_secs(1)));
+    }
+
+    fn answer_once(status: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer);
+            let _ = stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes());
+        });
+        server
+    }
+
+    #[test]
+    fn test_http_probe() {
+        assert!(probe(&answer_once("200 OK"), "/health", Duration::from_secs(1)));
+        assert!(probe(&answer_once("301 Moved Permanently"), "/health", Duration::from_secs(1)));
+        assert!(!probe(&answer_once("503 Service Unavailable"), "/health", Duration::from_secs(1)));
+    }
+}
@@ -1,184 +1,842 @@
-use std::net::TcpStream;
-use std::io::Read;
-use std::collections::HashMap;
+use crate::chunked::{ChunkedError, ChunkedParser, Step};
+use crate::config::Config;
+use std::fmt;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+
+// Why a request couldn't be parsed, each maps to the status code to answer with
+#[derive(Debug)]
+pub enum HttpError {
+    BadRequest(&'static str),
+    PayloadTooLarge,
+    UriTooLong,
+    HeaderFieldsTooLarge,
+    VersionNotSupported,
+    // The client took too long sending the request
+    RequestTimeout,
+    // Reading from the client failed, there's nobody left to answer
+    Io(io::Error),
+}
+
+impl HttpError {
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            HttpError::BadRequest(_) => Some(400),
+            HttpError::PayloadTooLarge => Some(413),
+            HttpError::UriTooLong => Some(414),
+            HttpError::HeaderFieldsTooLarge => Some(431),
+            HttpError::VersionNotSupported => Some(505),
+            HttpError::RequestTimeout => Some(408),
+            HttpError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            HttpError::PayloadTooLarge => write!(f, "request body too large"),
+            HttpError::UriTooLong => write!(f, "request target too long"),
+            HttpError::HeaderFieldsTooLarge => write!(f, "request header fields too large"),
+            HttpError::VersionNotSupported => write!(f, "HTTP version not supported"),
+            HttpError::RequestTimeout => write!(f, "timed out reading the request"),
+            HttpError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<io::Error> for HttpError {
+    fn from(error: io::Error) -> Self {
+        HttpError::Io(error)
+    }
+}
+
+impl From<ChunkedError> for HttpError {
+    fn from(error: ChunkedError) -> Self {
+        match error {
+            ChunkedError::TooLarge => HttpError::PayloadTooLarge,
+            ChunkedError::TrailersTooLarge => HttpError::HeaderFieldsTooLarge,
+            ChunkedError::InvalidSize => HttpError::BadRequest("invalid chunk size"),
+            ChunkedError::MissingCrlf => HttpError::BadRequest("missing CRLF after chunk data"),
+            ChunkedError::LineTooLong => HttpError::BadRequest("chunk size line too long"),
+            ChunkedError::InvalidTrailer => HttpError::BadRequest("invalid trailer"),
+        }
+    }
+}
+
+// Header fields in the order they were received. Names compare case-insensitively and a
+// name may appear several times. Values are kept as the bytes the client sent, since obs-text
+// may be any byte and has to reach upstreams and scripts unchanged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Headers {
+    fields: Vec<(String, Vec<u8>)>,
+}
+
+impl Headers {
+    pub const fn new() -> Self {
+        Self { fields: Vec::new() }
+    }
 
-#[allow(dead_code)]
+    pub fn append(&mut self, name: &str, value: &[u8]) {
+        self.fields.push((name.to_string(), value.to_vec()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    // The first value of a header, None too when it isn't valid UTF-8
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_raw(name).and_then(|v| std::str::from_utf8(v).ok())
+    }
+
+    pub fn get_raw(&self, name: &str) -> Option<&[u8]> {
+        self.fields.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_slice())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.fields.iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_slice())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get_raw(name).is_some()
+    }
+
+    // Whether a comma separated header such as Connection lists a token, in any of its lines
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(|&b| b == b','))
+            .any(|t| t.trim_ascii().eq_ignore_ascii_case(token.as_bytes()))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_slice()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct HttpRequest {
     method: String,
-    path: String,
+    target: String,
     version: String,
-    headers: HashMap<String, String>,
-    body: Vec<u8>,
+    headers: Headers,
+    remote_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
 }
 
-pub struct HttpValidator<'stream> {
-    stream: &'stream mut TcpStream,
-    request: HttpRequest,
+impl HttpRequest {
+    // Parses a request head, mostly useful for tests
+    pub fn parse(bytes: &[u8]) -> Result<HttpRequest, HttpError> {
+        let mut validator = HttpValidator::new();
+        match validator.feed(bytes)? {
+            Status::Complete => Ok(validator.get_request()),
+            Status::Incomplete => Err(HttpError::BadRequest("incomplete request")),
+        }
+    }
+
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    // The request target as sent, e.g. `/search?q=rust`
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    // Whether the client wants the connection kept open after this request, see RFC 9112 section 9.3
+    pub fn keep_alive(&self) -> bool {
+        if self.headers.has_token("connection", "close") {
+            return false;
+        }
+        self.version == "HTTP/1.1" || self.headers.has_token("connection", "keep-alive")
+    }
+
+    // Records both ends of the connection the request came in on
+    pub fn with_addresses(mut self, remote_addr: Option<SocketAddr>, local_addr: Option<SocketAddr>) -> Self {
+        self.remote_addr = remote_addr;
+        self.local_addr = local_addr;
+        self
+    }
+
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
 }
 
-impl<'stream> HttpValidator<'stream> {
-    pub fn new(stream: &'stream mut TcpStream) -> HttpValidator<'stream> {
-        HttpValidator {
-            stream,
-            request: HttpRequest {
-                method: String::new(),
-                path: String::new(),
-                version: String::new(),
-                headers: HashMap::new(),
-                body: Vec::new(),
+// Size limits applied while parsing
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    // Longest request line, 414 when the target makes it longer
+    pub max_request_line: usize,
+    // Request line and header fields together, 431 beyond that
+    pub max_head: usize,
+    pub max_headers: usize,
+    // Decoded body size, 413 beyond that
+    pub max_body: u64,
+}
+
+impl Limits {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            // Zero lifts the limit
+            max_body: match config.client_max_body_size() {
+                0 => u64::MAX,
+                size => size,
             },
+            ..Self::default()
         }
     }
+}
 
-    pub fn validate(&mut self) -> bool {
-        let mut buffer = [0; 1024];
-        let mut request_data = String::new();
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_request_line: 8 * 1024,
+            max_head: 32 * 1024,
+            max_headers: 100,
+            max_body: 16 * 1024 * 1024,
+        }
+    }
+}
 
-        loop {
-            // Read data from the stream
-            let read_result = self.stream.read(&mut buffer);
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Status {
+    Incomplete,
+    // The head is in, along with the whole body or at least BODY_BUFFER bytes of it
+    Complete,
+}
 
-            // If reading failed, log error and return false
-            if let Err(e) = read_result {
-                eprintln!("Error reading from stream: {}", e);
-                return false;
-            }
+// How much of a body is read ahead before the request is handed on, the rest is read as the
+// handler asks for it
+pub const BODY_BUFFER: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum State {
+    RequestLine,
+    Headers,
+    Body(u64),
+    Chunked,
+    Done,
+}
 
-            let bytes_read = read_result.unwrap();
+// Characters allowed in methods and field names, see RFC 9110 section 5.6.2
+fn is_token(s: &[u8]) -> bool {
+    !s.is_empty() && s.iter().all(|&c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c))
+}
 
-            // If no bytes were read, we break the loop (client may have closed the connection)
-            if bytes_read == 0 {
-                break;
-            }
+// Incremental HTTP/1.1 request parser, see RFC 9112. Bytes are fed as they arrive from the
+// client, in pieces of any size, until the head and the start of the body have been seen.
+// The body is then decoded as it's read, see `read_body`, and whatever follows it, such as a
+// pipelined request, is kept until `finish_body` moves on to the next request.
+pub struct HttpValidator {
+    limits: Limits,
+    buffer: Vec<u8>,
+    // Start of the bytes not parsed yet
+    position: usize,
+    state: State,
+    head_size: usize,
+    chunked: ChunkedParser,
+    request: HttpRequest,
+    // Content-Length of the current body, None when it's chunked
+    body_length: Option<u64>,
+    // Decoded body bytes not read yet
+    body: Vec<u8>,
+    trailers: Headers,
+}
 
-            // Convert the buffer to a valid UTF-8 string and append to request_data
-            let utf8_result = String::from_utf8(buffer[..bytes_read].to_vec());
-            if let Err(e) = utf8_result {
-                eprintln!("Invalid UTF-8 sequence: {}", e);
-                return false;
-            }
+impl Default for HttpValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-            // Append the valid UTF-8 data
-            request_data.push_str(&utf8_result.unwrap());
+impl HttpValidator {
+    pub fn new() -> HttpValidator {
+        Self::with_limits(Limits::default())
+    }
 
-            // You can add additional checks if needed to detect incomplete requests
-            // e.g., Check if we've read a full HTTP request (by looking for "\r\n\r\n")
-            if request_data.contains("\r\n\r\n") {
-                break;
-            }
+    pub fn with_limits(limits: Limits) -> HttpValidator {
+        HttpValidator {
+            limits,
+            buffer: Vec::new(),
+            position: 0,
+            state: State::RequestLine,
+            head_size: 0,
+            chunked: ChunkedParser::new(limits.max_body),
+            request: HttpRequest::default(),
+            body_length: Some(0),
+            body: Vec::new(),
+            trailers: Headers::new(),
         }
+    }
 
-        // Validate the accumulated request data
-        if !self.is_valid_request(&request_data) {
-            eprintln!("Invalid request data: {}", request_data);
-            return false;
+    // Whether bytes of a next request are waiting, e.g. after a pipelined request
+    pub fn has_buffered(&self) -> bool {
+        self.position < self.buffer.len()
+    }
+
+    // Whether parsing of the current request has started
+    pub fn in_progress(&self) -> bool {
+        self.state != State::RequestLine || self.buffer[self.position..].iter().any(|c| !c.is_ascii_whitespace())
+    }
+
+    // Whether the head has been parsed and the body is coming in
+    pub fn reading_body(&self) -> bool {
+        matches!(self.state, State::Body(_) | State::Chunked)
+    }
+
+    // Adds bytes received from the client and parses as far as possible. Nothing past the
+    // current request is parsed before `finish_body`.
+    pub fn feed(&mut self, data: &[u8]) -> Result<Status, HttpError> {
+        // Don't let parsed bytes pile up while a large body comes in
+        if self.position > 64 * 1024 {
+            self.buffer.drain(..self.position);
+            self.position = 0;
         }
+        self.buffer.extend_from_slice(data);
 
-        println!("Request validated successfully: {}", request_data);
+        while self.state != State::Done && self.body.len() < BODY_BUFFER {
+            if !self.advance()? {
+                return Ok(Status::Incomplete);
+            }
+        }
+        Ok(Status::Complete)
+    }
 
-        true
+    // Content-Length of the current request's body, zero without one and None when chunked
+    pub fn body_length(&self) -> Option<u64> {
+        self.body_length
     }
 
-    fn is_valid_request(&mut self, data: &str) -> bool {
-        let mut lines = data.lines();
+    // Whether the current request's body has been read to the end
+    pub fn body_done(&self) -> bool {
+        self.state == State::Done && self.body.is_empty()
+    }
 
-        let methods = ["GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "PATCH"];
-        if let Some(request_line) = lines.next() {
-            let parts: Vec<&str> = request_line.split_whitespace().collect();
+    // Fields sent after a chunked body, only complete once it has been read to the end
+    pub fn trailers(&self) -> &Headers {
+        &self.trailers
+    }
 
-            // Validate the request line structure
-            if parts.len() != 3 {
-                eprintln!("Invalid request line format: {}", request_line);
-                return false;
+    // Moves decoded body bytes into `buf`. Zero means the body is over, None that more has
+    // to be fed first.
+    pub fn read_body(&mut self, buf: &mut [u8]) -> Result<Option<usize>, HttpError> {
+        while self.body.is_empty() && self.state != State::Done {
+            if !self.advance()? {
+                return Ok(None);
             }
+        }
+        let n = buf.len().min(self.body.len());
+        buf[..n].copy_from_slice(&self.body[..n]);
+        self.body.drain(..n);
+        Ok(Some(n))
+    }
 
-            // Validate the HTTP method
-            let method = parts[0];
-            if !methods.contains(&method) {
-                eprintln!("Invalid HTTP method: {}", method);
-                return false;
+    // Gets ready for the next request, dropping whatever the handler left of this one's body.
+    // False when the rest of the body hasn't even arrived, the connection can't be reused then.
+    pub fn finish_body(&mut self) -> bool {
+        if self.state == State::RequestLine {
+            return true;
+        }
+        self.body.clear();
+        while self.state != State::Done {
+            match self.advance() {
+                Ok(true) => self.body.clear(),
+                Ok(false) | Err(_) => return false,
             }
-            self.request.method = method.to_string();
+        }
 
-            self.request.path = parts[1].to_string();
+        self.buffer.drain(..self.position);
+        self.position = 0;
+        self.state = State::RequestLine;
+        self.head_size = 0;
+        self.body_length = Some(0);
+        self.trailers = Headers::new();
+        true
+    }
 
-            // Validate the HTTP version (e.g., HTTP/1.1)
-            let version = parts[2];
-            if !version.starts_with("HTTP/") {
-                eprintln!("Invalid HTTP version: {}", version);
-                return false;
+    // Reads from the client until a request is ready to be handled, see `Status::Complete`.
+    // None means the client closed the connection cleanly before starting another request.
+    pub fn validate<R: Read>(&mut self, stream: &mut R) -> Result<Option<HttpRequest>, HttpError> {
+        let mut buffer = [0; 16 * 1024];
+        let mut status = self.feed(&[])?;
+
+        while status == Status::Incomplete {
+            let n = match stream.read(&mut buffer) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if n == 0 {
+                if !self.in_progress() {
+                    return Ok(None);
+                }
+                return Err(HttpError::BadRequest("connection closed mid-request"));
             }
+            status = self.feed(&buffer[..n])?;
+        }
 
-            self.request.version = version.to_string();
-        } else {
-            eprintln!("Missing request line.");
-            return false;
+        Ok(Some(self.get_request()))
+    }
+
+    // Takes the parsed request, its body is read through the validator
+    pub fn get_request(&mut self) -> HttpRequest {
+        std::mem::take(&mut self.request)
+    }
+
+    // Returns the next line without its line ending, or None until one has fully arrived
+    fn next_line(&mut self, limit: usize) -> Result<Option<Vec<u8>>, HttpError> {
+        let rest = &self.buffer[self.position..];
+        let end = match rest.iter().position(|&c| c == b'\n') {
+            Some(end) => end,
+            None if rest.len() > limit => return Err(self.line_too_long()),
+            None => return Ok(None),
+        };
+        if end > limit {
+            return Err(self.line_too_long());
         }
 
-        // Step 2: Parse and Validate Headers
-        let mut headers = HashMap::new();
-        while let Some(line) = lines.next() {
-            let line = line.trim();
+        let mut line = rest[..end].to_vec();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        self.position += end + 1;
+        self.head_size += end + 1;
+        if self.head_size > self.limits.max_head {
+            return Err(HttpError::HeaderFieldsTooLarge);
+        }
+        Ok(Some(line))
+    }
 
-            // Empty line indicates the end of headers
-            if line.is_empty() {
-                break;
-            }
+    fn line_too_long(&self) -> HttpError {
+        match self.state {
+            State::RequestLine => HttpError::UriTooLong,
+            _ => HttpError::HeaderFieldsTooLarge,
+        }
+    }
 
-            // Parse the header into key-value pairs
-            if let Some((key, value)) = line.split_once(':') {
-                headers.insert(key.trim().to_string(), value.trim().to_string());
-            } else {
-                eprintln!("Malformed header: {}", line);
-                return false;
+    // Takes one step, returns false when more bytes are needed
+    fn advance(&mut self) -> Result<bool, HttpError> {
+        match self.state {
+            State::RequestLine => {
+                let line = match self.next_line(self.limits.max_request_line)? {
+                    Some(line) => line,
+                    None => return Ok(false),
+                };
+                // Empty lines before the request line are ignored, see RFC 9112 section 2.2
+                if !line.is_empty() {
+                    self.parse_request_line(&line)?;
+                    self.state = State::Headers;
+                }
+            }
+            State::Headers => {
+                let limit = self.limits.max_head;
+                let line = match self.next_line(limit)? {
+                    Some(line) => line,
+                    None => return Ok(false),
+                };
+                if line.is_empty() {
+                    self.state = self.body_state()?;
+                } else {
+                    if self.request.headers.len() >= self.limits.max_headers {
+                        return Err(HttpError::HeaderFieldsTooLarge);
+                    }
+                    let (name, value) = parse_field(&line).map_err(HttpError::BadRequest)?;
+                    self.request.headers.append(&name, &value);
+                }
+            }
+            State::Body(remaining) => {
+                let take = self.take_body(remaining);
+                if take == 0 {
+                    return Ok(false);
+                }
+                self.state = if take == remaining { State::Done } else { State::Body(remaining - take) };
             }
+            State::Chunked => {
+                let input = &self.buffer[self.position..];
+                match self.chunked.parse(input)? {
+                    Step::Incomplete => return Ok(false),
+                    Step::Framing(n) => self.position += n,
+                    Step::Data(n) => {
+                        self.body.extend_from_slice(&input[..n]);
+                        self.position += n;
+                    }
+                    Step::Done(n) => {
+                        self.position += n;
+                        self.trailers = self.chunked.take_trailers();
+                        self.state = State::Done;
+                    }
+                }
+            }
+            State::Done => {}
         }
+        Ok(true)
+    }
 
-        let mut cl: usize = 0;
+    // Moves up to remaining buffered bytes into the body, returns how many
+    fn take_body(&mut self, remaining: u64) -> u64 {
+        let available = (self.buffer.len() - self.position) as u64;
+        let room = BODY_BUFFER.saturating_sub(self.body.len()) as u64;
+        let take = available.min(remaining).min(room);
+        let end = self.position + take as usize;
+        self.body.extend_from_slice(&self.buffer[self.position..end]);
+        self.position = end;
+        take
+    }
 
-        // Optional: Validate required headers
-        if let Some(content_length) = headers.get("Content-Length") {
-            let clr = content_length.parse::<usize>();
-            if clr.is_err() {
-                eprintln!("Invalid Content-Length: {}", content_length);
-                return false;
+    fn parse_request_line(&mut self, line: &[u8]) -> Result<(), HttpError> {
+        let mut parts = line.split(|&c| c == b' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version), None) => (method, target, version),
+            _ => return Err(HttpError::BadRequest("malformed request line")),
+        };
+
+        if !is_token(method) {
+            return Err(HttpError::BadRequest("invalid method"));
+        }
+        if target.is_empty() || !target.iter().all(|c| c.is_ascii_graphic()) {
+            return Err(HttpError::BadRequest("invalid request target"));
+        }
+
+        match version {
+            b"HTTP/1.0" | b"HTTP/1.1" => {}
+            [b'H', b'T', b'T', b'P', b'/', major, b'.', minor] if major.is_ascii_digit() && minor.is_ascii_digit() => {
+                return Err(HttpError::VersionNotSupported);
             }
-            cl = clr.unwrap();
+            _ => return Err(HttpError::BadRequest("invalid HTTP version")),
         }
-        self.request.headers = headers;
 
+        // All three were checked to be ASCII above
+        self.request.method = String::from_utf8_lossy(method).into_owned();
+        self.request.target = String::from_utf8_lossy(target).into_owned();
+        self.request.version = String::from_utf8_lossy(version).into_owned();
+        Ok(())
+    }
 
-        // Step 3: Parse and Validate Body (if applicable)
-        let body: String = lines.collect::<Vec<&str>>().join("\n");
+    // Works out how the body is delimited once all header fields are in, see RFC 9112 section 6.3
+    fn body_state(&mut self) -> Result<State, HttpError> {
+        let headers = &self.request.headers;
 
-        if cl > 0 {
-            if body.is_empty() {
-                eprintln!("Body expected but missing.");
-                return false;
+        if self.request.version == "HTTP/1.1" && headers.get_all("host").count() != 1 {
+            return Err(HttpError::BadRequest("HTTP/1.1 requests need exactly one Host header"));
+        }
+        // A host name or address, never anything but ASCII
+        if headers.get_all("host").any(|host| !host.iter().all(u8::is_ascii_graphic)) {
+            return Err(HttpError::BadRequest("invalid Host header"));
+        }
+
+        let content_length = content_length(headers)?;
+        if headers.contains("transfer-encoding") {
+            // Both at once is a classic request smuggling setup
+            if content_length.is_some() {
+                return Err(HttpError::BadRequest("both Transfer-Encoding and Content-Length"));
+            }
+            let last = headers.get_all("transfer-encoding")
+                .flat_map(|value| value.split(|&b| b == b','))
+                .map(<[u8]>::trim_ascii)
+                .filter(|coding| !coding.is_empty())
+                .last();
+            if !last.is_some_and(|coding| coding.eq_ignore_ascii_case(b"chunked")) {
+                return Err(HttpError::BadRequest("request body must end with the chunked coding"));
             }
+            self.chunked = ChunkedParser::new(self.limits.max_body);
+            self.body_length = None;
+            return Ok(State::Chunked);
         }
 
-        // Optional: Additional body validation can be added here
-        println!("Valid Request!");
-        println!("Method: {}", self.request.method);
-        println!("Path: {}", self.request.path);
-        println!("Version: {}", self.request.version);
-        println!("Headers");
-        for (key, value) in self.request.headers.iter() {
-            println!("{}: {}", key, value);
+        let length = content_length.unwrap_or(0);
+        if length > self.limits.max_body {
+            return Err(HttpError::PayloadTooLarge);
         }
-        println!("Body: {}", body);
+        self.body_length = Some(length);
+        Ok(if length > 0 { State::Body(length) } else { State::Done })
+    }
+}
 
-        true
+// Content-Length may be repeated, or be a list, as long as every value is the same
+fn content_length(headers: &Headers) -> Result<Option<u64>, HttpError> {
+    let mut length = None;
+    for value in headers.get_all("content-length").flat_map(|value| value.split(|&b| b == b',')) {
+        let value = value.trim_ascii();
+        if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+            return Err(HttpError::BadRequest("invalid Content-Length"));
+        }
+        // Only digits, so this can only fail on overflow
+        let value = std::str::from_utf8(value).unwrap_or_default().parse::<u64>().map_err(|_| HttpError::PayloadTooLarge)?;
+        if length.is_some_and(|length| length != value) {
+            return Err(HttpError::BadRequest("conflicting Content-Length values"));
+        }
+        length = Some(value);
     }
+    Ok(length)
+}
 
-    pub fn get_request(&mut self) -> HttpRequest {
-        HttpRequest {
-            method: String::new(),
-            path: String::new(),
-            version: String::new(),
-            headers: HashMap::new(),
-            body: Vec::new(),
+// Splits a header or trailer line into its name and raw value, or says what's wrong with it
+pub(crate) fn parse_field(line: &[u8]) -> Result<(String, Vec<u8>), &'static str> {
+    // Line folding is obsolete and a smuggling risk, see RFC 9112 section 5.2
+    if line[0] == b' ' || line[0] == b'\t' {
+        return Err("obsolete line folding");
+    }
+
+    let colon = line.iter().position(|&c| c == b':').ok_or("header field without a colon")?;
+    let (name, value) = (&line[..colon], &line[colon + 1..]);
+    // No whitespace allowed between the name and the colon either
+    if !is_token(name) {
+        return Err("invalid header field name");
+    }
+    if value.iter().any(|&c| (c < b' ' && c != b'\t') || c == 0x7f) {
+        return Err("control character in header field value");
+    }
+
+    // A token is ASCII
+    let name = String::from_utf8_lossy(name).into_owned();
+    let value = value.trim_ascii_start();
+    let end = value.iter().rposition(|&c| c != b' ' && c != b'\t').map_or(0, |i| i + 1);
+    Ok((name, value[..end].to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_of(bytes: &[u8]) -> Option<u16> {
+        HttpRequest::parse(bytes).unwrap_err().status()
+    }
+
+    // Parses a request whose body has fully arrived, returns it along with the body
+    fn parse_with_body(bytes: &[u8]) -> (HttpRequest, Vec<u8>, Headers) {
+        let mut validator = HttpValidator::new();
+        assert_eq!(validator.feed(bytes).unwrap(), Status::Complete);
+        let request = validator.get_request();
+        let body = read_body(&mut validator);
+        (request, body, validator.trailers().clone())
+    }
+
+    fn read_body(validator: &mut HttpValidator) -> Vec<u8> {
+        let mut body = Vec::new();
+        let mut buf = [0; 7];
+        while let Some(n) = validator.read_body(&mut buf).unwrap() {
+            if n == 0 {
+                return body;
+            }
+            body.extend_from_slice(&buf[..n]);
+        }
+        panic!("body incomplete");
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let (request, body, _) = parse_with_body(b"POST /search?q=rust HTTP/1.1\r\nHost: example.com\r\ncontent-length: 4\r\nAccept: text/html\r\nACCEPT:  text/plain \r\n\r\nbody");
+        assert_eq!(request.method(), "POST");
+        assert_eq!(request.target(), "/search?q=rust");
+        assert_eq!(request.path(), "/search");
+        assert_eq!(request.query(), Some("q=rust"));
+        assert_eq!(request.version(), "HTTP/1.1");
+        assert_eq!(request.header("Content-Length"), Some("4"));
+        assert_eq!(request.headers().get_all("accept").collect::<Vec<_>>(), [&b"text/html"[..], b"text/plain"]);
+        assert_eq!(body, b"body");
+
+        // Bare LFs and leading empty lines are tolerated
+        let (request, body, _) = parse_with_body(b"\r\nGET / HTTP/1.0\nX-Y: z\n\n");
+        assert_eq!(request.header("x-y"), Some("z"));
+        assert_eq!(request.query(), None);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn test_binary_body_byte_by_byte() {
+        let body: Vec<u8> = (0..=255u8).rev().collect();
+        let mut bytes = format!("PUT /blob HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        bytes.extend(&body);
+
+        let mut validator = HttpValidator::new();
+        let (last, rest) = bytes.split_last().unwrap();
+        for byte in rest {
+            assert_eq!(validator.feed(&[*byte]).unwrap(), Status::Incomplete);
         }
+        assert_eq!(validator.feed(&[*last]).unwrap(), Status::Complete);
+        assert_eq!(validator.body_length(), Some(256));
+        assert_eq!(read_body(&mut validator), body);
+    }
+
+    #[test]
+    fn test_chunked_body_with_trailers() {
+        let (_, body, trailers) = parse_with_body(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip, chunked\r\n\r\n5;ext=1\r\nhel\x00o\r\n3\r\n\xff\r\n\r\n0\r\nX-Sum: 42\r\n\r\n");
+        assert_eq!(body, b"hel\x00o\xff\r\n");
+        assert_eq!(trailers.get("x-sum"), Some("42"));
+    }
+
+    #[test]
+    fn test_large_body_read_as_it_arrives() {
+        let mut validator = HttpValidator::new();
+        let head = format!("PUT / HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n", 3 * BODY_BUFFER);
+        assert_eq!(validator.feed(head.as_bytes()).unwrap(), Status::Incomplete);
+        // Handed on once enough of the body is in, without waiting for the rest
+        assert_eq!(validator.feed(&vec![b'a'; BODY_BUFFER]).unwrap(), Status::Complete);
+        validator.get_request();
+
+        let mut buf = vec![0; 2 * BODY_BUFFER];
+        assert_eq!(validator.read_body(&mut buf).unwrap(), Some(BODY_BUFFER));
+        assert_eq!(validator.read_body(&mut buf).unwrap(), None);
+        validator.feed(&vec![b'b'; BODY_BUFFER]).unwrap();
+        assert_eq!(validator.read_body(&mut buf).unwrap(), Some(BODY_BUFFER));
+        assert!(buf[..BODY_BUFFER].iter().all(|&b| b == b'b'));
+
+        // What's left has to arrive before the next request can be read
+        assert!(!validator.finish_body());
+        validator.feed(&vec![b'c'; BODY_BUFFER]).unwrap();
+        validator.feed(b"GET /next HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert!(validator.finish_body());
+        assert_eq!(validator.feed(&[]).unwrap(), Status::Complete);
+        assert_eq!(validator.get_request().path(), "/next");
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let mut stream: &[u8] = b"GET /a HTTP/1.1\r\nHost: a\r\n\r\nPOST /b HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\n\r\nhiGET /c HTTP/1.1\r\nHost: a\r\n\r\n";
+        let mut validator = HttpValidator::new();
+        let paths: Vec<String> = std::iter::from_fn(|| {
+                assert!(validator.finish_body());
+                validator.validate(&mut stream).unwrap()
+            })
+            .map(|request| request.path().to_string())
+            .collect();
+        assert_eq!(paths, ["/a", "/b", "/c"]);
+    }
+
+    #[test]
+    fn test_connection_closed() {
+        let mut validator = HttpValidator::new();
+        assert!(validator.validate(&mut &b""[..]).unwrap().is_none());
+        assert!(validator.validate(&mut &b"\r\n"[..]).unwrap().is_none());
+        let error = validator.validate(&mut &b"GET / HTTP/1.1\r\nHost: a\r\n"[..]).unwrap_err();
+        assert_eq!(error.status(), Some(400));
+    }
+
+    #[test]
+    fn test_obs_text_values_kept_raw() {
+        let request = HttpRequest::parse(b"GET / HTTP/1.1\r\nHost: a\r\nX-Name: \tcaf\xe9 \r\n\r\n").unwrap();
+        assert_eq!(request.headers().get_raw("x-name"), Some(&b"caf\xe9"[..]));
+        assert_eq!(request.header("x-name"), None);
+        assert!(request.headers().contains("x-name"));
+
+        assert_eq!(status_of(b"GET / HTTP/1.1\r\nHost: caf\xe9\r\n\r\n"), Some(400));
+    }
+
+    #[test]
+    fn test_headers() {
+        let request = HttpRequest::parse(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: keep-alive, Upgrade\r\nconnection: TE\r\n\r\n").unwrap();
+        assert!(request.headers().has_token("Connection", "upgrade"));
+        assert!(request.headers().has_token("connection", "te"));
+        assert!(!request.headers().has_token("connection", "close"));
+        assert!(request.keep_alive());
+        assert_eq!(request.headers().iter().next(), Some(("Host", &b"a"[..])));
+
+        let mut headers = request.headers().clone();
+        headers.remove("CONNECTION");
+        assert_eq!(headers.len(), 1);
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let keep_alive = |bytes: &[u8]| HttpRequest::parse(bytes).unwrap().keep_alive();
+        assert!(keep_alive(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: Close\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.0\r\n\r\n"));
+        assert!(keep_alive(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        assert_eq!(status_of(b"GET /\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"GET  / HTTP/1.1\r\nHost: a\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"G(T / HTTP/1.1\r\nHost: a\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"GET / HTTQ/1.1\r\nHost: a\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"GET / HTTP/2.0\r\nHost: a\r\n\r\n"), Some(505));
+        assert_eq!(status_of(b"GET /\xff HTTP/1.1\r\nHost: a\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"GET / HTTP/1.1\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"GET / HTTP/1.1\r\nHost : a\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"GET / HTTP/1.1\r\nHost: a\r\nX: 1\r\n 2\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"GET / HTTP/1.1\r\nHost: a\r\nNo colon\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"GET / HTTP/1.1\r\nHost: a\x00b\r\n\r\n"), Some(400));
+    }
+
+    #[test]
+    fn test_body_framing_errors() {
+        assert_eq!(status_of(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: -1\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab"), Some(400));
+        assert_eq!(status_of(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked, gzip\r\n\r\n"), Some(400));
+        assert_eq!(status_of(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\nxyz\r\n"), Some(400));
+        assert_eq!(status_of(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabc\r\n0\r\n\r\n"), Some(400));
+
+        // Repeating the same length is fine
+        let (_, body, _) = parse_with_body(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 2, 2\r\n\r\nab");
+        assert_eq!(body, b"ab");
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits { max_request_line: 32, max_head: 128, max_headers: 3, max_body: 8 };
+        let status = |bytes: &[u8]| HttpValidator::with_limits(limits).feed(bytes).unwrap_err().status();
+
+        let long_target = format!("GET /{} HTTP/1.1\r\n", "a".repeat(40));
+        assert_eq!(status(long_target.as_bytes()), Some(414));
+        // Detected before the line ends
+        assert_eq!(status(&long_target.as_bytes()[..36]), Some(414));
+
+        let long_header = format!("GET / HTTP/1.1\r\nX: {}\r\n", "a".repeat(200));
+        assert_eq!(status(long_header.as_bytes()), Some(431));
+        assert_eq!(status(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n"), Some(431));
+
+        assert_eq!(status(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 9\r\n\r\n"), Some(413));
+        assert_eq!(status(b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 99999999999999999999999\r\n\r\n"), Some(413));
+        assert_eq!(status(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n4\r\n"), Some(413));
+        assert_eq!(status(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n"), Some(413));
+        let unlimited = Limits { max_body: u64::MAX, ..limits };
+        let error = HttpValidator::with_limits(unlimited)
+            .feed(b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n")
+            .unwrap_err();
+        assert_eq!(error.status(), Some(413));
     }
 }
@@ -0,0 +1,78 @@
+use crate::http_validator::HttpRequest;
+use std::net::{IpAddr, SocketAddr};
+
+// What a balancer gets to look at when picking a server for a request
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    pub peer: Option<SocketAddr>,
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    // Servers the balancer must not pick, e.g. because health checks marked them down
+    pub skip: Vec<String>,
+}
+
+impl RequestContext {
+    pub fn from_request(request: &HttpRequest) -> RequestContext {
+        RequestContext {
+            peer: request.remote_addr(),
+            method: request.method().to_string(),
+            uri: request.target().to_string(),
+            version: request.version().to_string(),
+            // Balancers only look at text, other values are left out
+            headers: request.headers().iter()
+                .filter_map(|(name, value)| Some((name.to_string(), std::str::from_utf8(value).ok()?.to_string())))
+                .collect(),
+            skip: vec![],
+        }
+    }
+
+    pub fn is_available(&self, server: &str) -> bool {
+        !self.skip.iter().any(|s| s == server)
+    }
+
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.peer.map(|peer| peer.ip())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn path(&self) -> &str {
+        self.uri.split_once('?').map_or(self.uri.as_str(), |(path, _)| path)
+    }
+
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, value)| value.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_request() {
+        let head = b"GET /cart?item=3 HTTP/1.1\r\nHost: shop.test\r\nCookie: a=1; PHPSESSID=abc\r\n\r\n";
+        let request = HttpRequest::parse(head).unwrap().with_addresses(Some("10.1.2.3:4567".parse().unwrap()), None);
+        let context = RequestContext::from_request(&request);
+
+        assert_eq!(context.method, "GET");
+        assert_eq!(context.uri, "/cart?item=3");
+        assert_eq!(context.path(), "/cart");
+        assert_eq!(context.version, "HTTP/1.1");
+        assert_eq!(context.header("host"), Some("shop.test"));
+        assert_eq!(context.cookie("PHPSESSID"), Some("abc"));
+        assert_eq!(context.cookie("missing"), None);
+        assert_eq!(context.client_ip(), Some("10.1.2.3".parse().unwrap()));
+    }
+}
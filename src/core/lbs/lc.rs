@@ -1,8 +1,10 @@
-use crate::core::lbs::LoadBalancer;
+use crate::core::lbs::{LoadBalancer, RequestContext};
 
 pub struct LeastConn {
     servers: Vec<String>,
     connections: Vec<usize>,
+    // Ties are broken round-robin starting here so idle servers share the load
+    next: usize,
 }
 
 impl LeastConn {
@@ -10,35 +12,102 @@ impl LeastConn {
         Self {
             connections: vec![0; servers.len()],
             servers,
+            next: 0,
         }
     }
 }
 
 impl LoadBalancer for LeastConn {
-    fn select_server(&mut self) -> Option<String> {
-        let mut least_connection_index  = 0;
-        for i in 1..self.connections.len() {
-            if self.connections[i] == 0 {
-                least_connection_index = i;
-                break;
-            }
-
-            if self.connections[i] < self.connections[least_connection_index] {
-                least_connection_index = i;
-            }
-        }
-
-        println!("<<<<<<<<<<<<< Connections Status >>>>>>>>>>>>>>>>>>>>>");
-        for (i, c) in self.connections.iter().enumerate() {
-            println!("{}: {} connected clients", i, c);
-        }
+    fn select_server(&mut self, context: &RequestContext) -> Option<String> {
+        let len = self.servers.len();
+        let least_connection_index = (0..len)
+            .map(|offset| (self.next + offset) % len)
+            .filter(|&i| context.is_available(&self.servers[i]))
+            .min_by_key(|&i| self.connections[i])?;
 
         self.connections[least_connection_index] += 1;
+        self.next = (least_connection_index + 1) % len;
         Some(self.servers[least_connection_index].clone())
-        
     }
 
     fn request_complete(&mut self, server: String) {
-        self.connections[self.servers.iter().position(|s| s == &server).unwrap()] -= 1;
+        if let Some(index) = self.servers.iter().position(|s| s == &server) {
+            self.connections[index] = self.connections[index].saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers() -> Vec<String> {
+        vec!["server1".to_string(), "server2".to_string(), "server3".to_string()]
+    }
+
+    fn select(lb: &mut LeastConn) -> String {
+        lb.select_server(&RequestContext::default()).unwrap()
+    }
+
+    #[test]
+    fn test_empty_servers() {
+        let mut lb = LeastConn::new(vec![]);
+        assert_eq!(lb.select_server(&RequestContext::default()), None);
+    }
+
+    #[test]
+    fn test_picks_least_connected() {
+        let mut lb = LeastConn::new(servers());
+        assert_eq!(select(&mut lb), "server1");
+        assert_eq!(select(&mut lb), "server2");
+        assert_eq!(select(&mut lb), "server3");
+
+        // server2 finishes first, so it is the only one with a free slot
+        lb.request_complete("server2".to_string());
+        assert_eq!(select(&mut lb), "server2");
+        assert_eq!(lb.connections, vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn test_completed_requests_free_the_server() {
+        let mut lb = LeastConn::new(servers());
+        for _ in 0..10 {
+            let server = select(&mut lb);
+            lb.request_complete(server);
+        }
+        assert_eq!(lb.connections, vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn test_ties_rotate() {
+        let mut lb = LeastConn::new(servers());
+        let mut picked = Vec::new();
+        for _ in 0..6 {
+            let server = select(&mut lb);
+            lb.request_complete(server.clone());
+            picked.push(server);
+        }
+        assert_eq!(picked, ["server1", "server2", "server3", "server1", "server2", "server3"]);
+    }
+
+    #[test]
+    fn test_skips_unavailable_servers() {
+        let mut lb = LeastConn::new(servers());
+        let context = RequestContext {
+            skip: vec!["server1".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(lb.select_server(&context), Some("server2".to_string()));
+        assert_eq!(lb.select_server(&context), Some("server3".to_string()));
+        assert_eq!(lb.select_server(&context), Some("server2".to_string()));
+        assert_eq!(lb.connections, vec![0, 2, 1]);
+    }
+
+    #[test]
+    fn test_unknown_or_extra_completion_is_ignored() {
+        let mut lb = LeastConn::new(servers());
+        lb.request_complete("server9".to_string());
+        lb.request_complete("server1".to_string());
+        assert_eq!(lb.connections, vec![0, 0, 0]);
     }
 }
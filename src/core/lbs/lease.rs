@@ -0,0 +1,82 @@
+use crate::core::lbs::{LoadBalancer, RequestContext};
+use std::sync::{Arc, Mutex};
+
+pub type SharedLoadBalancer = Arc<Mutex<Box<dyn LoadBalancer>>>;
+
+// A server handed out by the balancer. Dropping the lease reports the request as complete,
+// so the balancer's bookkeeping stays right whether the request succeeded, failed or panicked.
+pub struct ConnectionLease {
+    lb: SharedLoadBalancer,
+    server: String,
+}
+
+impl ConnectionLease {
+    pub fn acquire(lb: &SharedLoadBalancer, context: &RequestContext) -> Option<ConnectionLease> {
+        let server = lb.lock().unwrap_or_else(|e| e.into_inner()).select_server(context)?;
+        Some(ConnectionLease {
+            lb: lb.clone(),
+            server,
+        })
+    }
+
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+}
+
+impl Drop for ConnectionLease {
+    fn drop(&mut self) {
+        // A panicking request may have poisoned the lock, the counters are still fine to update
+        self.lb.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .request_complete(self.server.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Counting {
+        active: Arc<Mutex<i32>>,
+    }
+
+    impl LoadBalancer for Counting {
+        fn select_server(&mut self, _context: &RequestContext) -> Option<String> {
+            *self.active.lock().unwrap() += 1;
+            Some("server1".to_string())
+        }
+
+        fn request_complete(&mut self, _server: String) {
+            *self.active.lock().unwrap() -= 1;
+        }
+    }
+
+    #[test]
+    fn test_lease_completes_on_drop() {
+        let active = Arc::new(Mutex::new(0));
+        let lb: SharedLoadBalancer = Arc::new(Mutex::new(Box::new(Counting { active: active.clone() })));
+
+        let lease = ConnectionLease::acquire(&lb, &RequestContext::default()).unwrap();
+        assert_eq!(lease.server(), "server1");
+        assert_eq!(*active.lock().unwrap(), 1);
+        drop(lease);
+        assert_eq!(*active.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_lease_completes_on_panic() {
+        let active = Arc::new(Mutex::new(0));
+        let lb: SharedLoadBalancer = Arc::new(Mutex::new(Box::new(Counting { active: active.clone() })));
+
+        let thread_lb = lb.clone();
+        let result = std::thread::spawn(move || {
+            let _lease = ConnectionLease::acquire(&thread_lb, &RequestContext::default()).unwrap();
+            panic!("upstream went away");
+        }).join();
+
+        assert!(result.is_err());
+        assert_eq!(*active.lock().unwrap(), 0);
+    }
+}
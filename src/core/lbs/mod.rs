@@ -1,12 +1,20 @@
+pub mod context;
 pub mod lc;
+pub mod lease;
 pub mod rr;
 pub mod none;
+pub mod source;
+pub mod wrr;
 
 pub trait LoadBalancer: Send + Sync {
-    fn select_server(&mut self) -> Option<String>;
+    fn select_server(&mut self, context: &RequestContext) -> Option<String>;
     fn request_complete(&mut self, _server: String) {}
 }
 
+pub use self::context::*;
 pub use self::none::*;
 pub use self::lc::*;
+pub use self::lease::*;
 pub use self::rr::*;
+pub use self::source::*;
+pub use self::wrr::*;
@@ -1,25 +1,51 @@
 use rand::Rng;
-use crate::core::lbs::LoadBalancer;
+use crate::core::lbs::{LoadBalancer, RequestContext};
 
 pub struct None {
     servers: Vec<String>,
-    slen: usize,
 }
 
 impl None {
     pub fn new(servers: Vec<String>) -> Self {
         Self {
-            slen: servers.len(),
             servers,
         }
     }
 }
 
 impl LoadBalancer for None{
-    fn select_server(&mut self) -> Option<String> {
+    fn select_server(&mut self, context: &RequestContext) -> Option<String> {
+        let available: Vec<&String> = self.servers.iter().filter(|s| context.is_available(s)).collect();
+        if available.is_empty() {
+            return Option::None;
+        }
+
         let mut rng = rand::thread_rng();
-        let index = rng.gen_range(0..self.slen);
+        let index = rng.gen_range(0..available.len());
+
+        Some(available[index].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_skips_unavailable_servers() {
+        let mut lb = None::new(vec!["server1".to_string(), "server2".to_string()]);
+        let context = RequestContext {
+            skip: vec!["server1".to_string()],
+            ..Default::default()
+        };
+        for _ in 0..20 {
+            assert_eq!(lb.select_server(&context), Some("server2".to_string()));
+        }
 
-        Some(self.servers[index].clone())
+        let context = RequestContext {
+            skip: vec!["server1".to_string(), "server2".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(lb.select_server(&context), Option::None);
     }
 }
\ No newline at end of file
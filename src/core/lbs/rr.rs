@@ -1,4 +1,4 @@
-use crate::core::lbs::LoadBalancer;
+use crate::core::lbs::{LoadBalancer, RequestContext};
 
 pub struct RoundRobin {
     servers: Vec<String>,
@@ -17,19 +17,20 @@ impl RoundRobin {
 }
 
 impl LoadBalancer for RoundRobin {
-    fn select_server(&mut self) -> Option<String> {
+    fn select_server(&mut self, context: &RequestContext) -> Option<String> {
         if self.servers.is_empty() {
             return None;
         }
 
-        let current = self.last_index;
-        self.last_index = (self.last_index+1) % self.slen;
-        println!("current index: {}", current);
-        Some(self.servers[current].clone())
-    }
-
-    fn request_complete(&mut self, _server: String) {
-        println!("request complete: {}", _server);
+        // Walk at most one full cycle looking for a server we're allowed to use
+        for _ in 0..self.slen {
+            let current = self.last_index;
+            self.last_index = (self.last_index+1) % self.slen;
+            if context.is_available(&self.servers[current]) {
+                return Some(self.servers[current].clone());
+            }
+        }
+        None
     }
 }
 
@@ -40,14 +41,14 @@ mod tests {
     #[test]
     fn test_empty_servers() {
         let mut lb = RoundRobin::new(vec![]);
-        assert_eq!(lb.select_server(), None);
+        assert_eq!(lb.select_server(&RequestContext::default()), None);
     }
 
     #[test]
     fn test_single_server() {
         let mut lb = RoundRobin::new(vec!["server1".to_string()]);
-        assert_eq!(lb.select_server(), Some("server1".to_string()));
-        assert_eq!(lb.select_server(), Some("server1".to_string()));
+        assert_eq!(lb.select_server(&RequestContext::default()), Some("server1".to_string()));
+        assert_eq!(lb.select_server(&RequestContext::default()), Some("server1".to_string()));
     }
 
     #[test]
@@ -59,11 +60,11 @@ mod tests {
         ];
         let mut lb = RoundRobin::new(servers);
 
-        assert_eq!(lb.select_server(), Some("server1".to_string()));
-        assert_eq!(lb.select_server(), Some("server2".to_string()));
-        assert_eq!(lb.select_server(), Some("server3".to_string()));
+        assert_eq!(lb.select_server(&RequestContext::default()), Some("server1".to_string()));
+        assert_eq!(lb.select_server(&RequestContext::default()), Some("server2".to_string()));
+        assert_eq!(lb.select_server(&RequestContext::default()), Some("server3".to_string()));
         // Should wrap around to beginning
-        assert_eq!(lb.select_server(), Some("server1".to_string()));
+        assert_eq!(lb.select_server(&RequestContext::default()), Some("server1".to_string()));
     }
 
     #[test]
@@ -75,11 +76,35 @@ mod tests {
         let mut lb = RoundRobin::new(servers);
 
         // First cycle
-        assert_eq!(lb.select_server(), Some("server1".to_string()));
-        assert_eq!(lb.select_server(), Some("server2".to_string()));
+        assert_eq!(lb.select_server(&RequestContext::default()), Some("server1".to_string()));
+        assert_eq!(lb.select_server(&RequestContext::default()), Some("server2".to_string()));
 
         // Second cycle
-        assert_eq!(lb.select_server(), Some("server1".to_string()));
-        assert_eq!(lb.select_server(), Some("server2".to_string()));
+        assert_eq!(lb.select_server(&RequestContext::default()), Some("server1".to_string()));
+        assert_eq!(lb.select_server(&RequestContext::default()), Some("server2".to_string()));
+    }
+
+    #[test]
+    fn test_skips_unavailable_servers() {
+        let servers = vec![
+            "server1".to_string(),
+            "server2".to_string(),
+            "server3".to_string(),
+        ];
+        let mut lb = RoundRobin::new(servers);
+        let context = RequestContext {
+            skip: vec!["server2".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(lb.select_server(&context), Some("server1".to_string()));
+        assert_eq!(lb.select_server(&context), Some("server3".to_string()));
+        assert_eq!(lb.select_server(&context), Some("server1".to_string()));
+
+        let context = RequestContext {
+            skip: vec!["server1".to_string(), "server2".to_string(), "server3".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(lb.select_server(&context), None);
     }
 }
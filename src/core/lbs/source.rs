@@ -0,0 +1,119 @@
+use std::net::IpAddr;
+use crate::core::lbs::{LoadBalancer, RequestContext};
+
+// Pins each client IP to one server so file based sessions keep working
+pub struct Source {
+    servers: Vec<String>,
+}
+
+impl Source {
+    pub fn new(servers: Vec<String>) -> Self {
+        Self {
+            servers,
+        }
+    }
+}
+
+// FNV-1a, stable across restarts unlike the std hasher
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl LoadBalancer for Source {
+    fn select_server(&mut self, context: &RequestContext) -> Option<String> {
+        if self.servers.is_empty() {
+            return None;
+        }
+
+        // Without a client address every request starts at the first server
+        let start = match context.client_ip() {
+            None => 0,
+            Some(IpAddr::V4(ip)) => (hash(&ip.octets()) % self.servers.len() as u64) as usize,
+            Some(IpAddr::V6(ip)) => (hash(&ip.octets()) % self.servers.len() as u64) as usize,
+        };
+
+        // When the client's server is unavailable move on to the next one, so only
+        // clients of that server get remapped
+        (0..self.servers.len())
+            .map(|offset| &self.servers[(start + offset) % self.servers.len()])
+            .find(|server| context.is_available(server))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from(ip: IpAddr) -> RequestContext {
+        RequestContext {
+            peer: Some((ip, 50000).into()),
+            ..Default::default()
+        }
+    }
+
+    fn servers() -> Vec<String> {
+        vec!["server1".to_string(), "server2".to_string(), "server3".to_string()]
+    }
+
+    #[test]
+    fn test_no_client_address() {
+        let mut lb = Source::new(servers());
+        assert_eq!(lb.select_server(&RequestContext::default()), Some("server1".to_string()));
+    }
+
+    #[test]
+    fn test_empty_servers() {
+        let mut lb = Source::new(vec![]);
+        assert_eq!(lb.select_server(&from("10.0.0.1".parse().unwrap())), None);
+    }
+
+    #[test]
+    fn test_same_client_same_server() {
+        let mut lb = Source::new(servers());
+        let client: IpAddr = "192.168.1.20".parse().unwrap();
+        let first = lb.select_server(&from(client));
+        for _ in 0..10 {
+            assert_eq!(lb.select_server(&from(client)), first);
+        }
+
+        // A fresh balancer, e.g. after a restart, makes the same choice
+        assert_eq!(Source::new(servers()).select_server(&from(client)), first);
+    }
+
+    #[test]
+    fn test_unavailable_server_only_moves_its_clients() {
+        let mut lb = Source::new(servers());
+        let clients: Vec<IpAddr> = (0..=50u8).map(|i| IpAddr::from([10, 0, 0, i])).collect();
+        let before: Vec<String> = clients.iter().map(|c| lb.select_server(&from(*c)).unwrap()).collect();
+
+        for (client, server) in clients.iter().zip(&before) {
+            let mut context = from(*client);
+            context.skip = vec!["server2".to_string()];
+            let after = lb.select_server(&context).unwrap();
+            assert_ne!(after, "server2");
+            if server != "server2" {
+                assert_eq!(&after, server);
+            }
+        }
+    }
+
+    #[test]
+    fn test_clients_are_spread() {
+        let mut lb = Source::new(servers());
+        let mut hits = [0; 3];
+        for i in 0..=255u8 {
+            let server = lb.select_server(&from(IpAddr::from([10, 0, 0, i]))).unwrap();
+            hits[servers().iter().position(|s| *s == server).unwrap()] += 1;
+        }
+        assert!(hits.iter().all(|&h| h > 0));
+
+        let v6 = lb.select_server(&from("2001:db8::1".parse().unwrap()));
+        assert!(v6.is_some());
+    }
+}
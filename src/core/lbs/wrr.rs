@@ -0,0 +1,94 @@
+use crate::config::UpstreamServer;
+use crate::core::lbs::{LoadBalancer, RequestContext};
+
+// Smooth weighted round-robin as done by nginx. Every pick adds each server's weight to its
+// current weight, takes the highest and subtracts the total from it, which spreads heavier
+// servers out instead of sending them bursts, e.g. weights 5,1,1 give a a b a c a a.
+pub struct WeightedRoundRobin {
+    servers: Vec<UpstreamServer>,
+    current_weights: Vec<i64>,
+}
+
+impl WeightedRoundRobin {
+    pub fn new(servers: Vec<UpstreamServer>) -> Self {
+        Self {
+            current_weights: vec![0; servers.len()],
+            servers,
+        }
+    }
+}
+
+impl LoadBalancer for WeightedRoundRobin {
+    fn select_server(&mut self, context: &RequestContext) -> Option<String> {
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (i, server) in self.servers.iter().enumerate() {
+            if !context.is_available(&server.address) {
+                continue;
+            }
+            self.current_weights[i] += server.weight as i64;
+            total += server.weight as i64;
+            if best.is_none_or(|b| self.current_weights[i] > self.current_weights[b]) {
+                best = Some(i);
+            }
+        }
+
+        let best = best?;
+        self.current_weights[best] -= total;
+        Some(self.servers[best].address.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weighted(servers: &[(&str, u32)]) -> WeightedRoundRobin {
+        WeightedRoundRobin::new(servers.iter()
+            .map(|(address, weight)| UpstreamServer { weight: *weight, ..UpstreamServer::new(address) })
+            .collect())
+    }
+
+    fn picks(lb: &mut WeightedRoundRobin, n: usize) -> Vec<String> {
+        (0..n).map(|_| lb.select_server(&RequestContext::default()).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_empty_servers() {
+        let mut lb = weighted(&[]);
+        assert_eq!(lb.select_server(&RequestContext::default()), None);
+    }
+
+    #[test]
+    fn test_smooth_sequence() {
+        let mut lb = weighted(&[("a", 5), ("b", 1), ("c", 1)]);
+        assert_eq!(picks(&mut lb, 7), ["a", "a", "b", "a", "c", "a", "a"]);
+        // The cycle repeats
+        assert_eq!(picks(&mut lb, 7), ["a", "a", "b", "a", "c", "a", "a"]);
+    }
+
+    #[test]
+    fn test_equal_weights_is_round_robin() {
+        let mut lb = weighted(&[("a", 1), ("b", 1), ("c", 1)]);
+        assert_eq!(picks(&mut lb, 6), ["a", "b", "c", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_skips_unavailable_servers() {
+        let mut lb = weighted(&[("a", 5), ("b", 1), ("c", 1)]);
+        let context = RequestContext {
+            skip: vec!["a".to_string()],
+            ..Default::default()
+        };
+        let picked: Vec<String> = (0..4).map(|_| lb.select_server(&context).unwrap()).collect();
+        assert_eq!(picked, ["b", "c", "b", "c"]);
+    }
+
+    #[test]
+    fn test_distribution_matches_weights() {
+        let mut lb = weighted(&[("a", 3), ("b", 2), ("c", 1)]);
+        let picked = picks(&mut lb, 600);
+        let count = |name: &str| picked.iter().filter(|p| *p == name).count();
+        assert_eq!((count("a"), count("b"), count("c")), (300, 200, 100));
+    }
+}
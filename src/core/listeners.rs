@@ -0,0 +1,221 @@
+use crate::config::{Listen, ListenAddress};
+use crate::socket::Listener;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+fn set_option(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(fd, level, name, &value as *const _ as *const libc::c_void, std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn bind_to(fd: RawFd, address: &SocketAddr) -> io::Result<()> {
+    let result = match address {
+        SocketAddr::V4(v4) => {
+            let mut sin: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = v4.port().to_be();
+            sin.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(v4.ip().octets()) };
+            unsafe {
+                libc::bind(fd, &sin as *const _ as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+            }
+        }
+        SocketAddr::V6(v6) => {
+            let mut sin6: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = v6.port().to_be();
+            sin6.sin6_addr = libc::in6_addr { s6_addr: v6.ip().octets() };
+            sin6.sin6_flowinfo = v6.flowinfo();
+            sin6.sin6_scope_id = v6.scope_id();
+            unsafe {
+                libc::bind(fd, &sin6 as *const _ as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t)
+            }
+        }
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Opens the socket for a listen line
+pub fn bind(listen: &Listen) -> io::Result<Listener> {
+    match &listen.address {
+        ListenAddress::Tcp(address) => bind_tcp(address, listen).map(Listener::Tcp),
+        ListenAddress::Unix(path) => bind_unix(path, listen.backlog).map(Listener::Unix),
+    }
+}
+
+// Done by hand rather than with TcpListener::bind to set the backlog, and to keep IPv6 sockets
+// off IPv4 so [::]:80 and 0.0.0.0:80 can both be listened on
+fn bind_tcp(address: &SocketAddr, listen: &Listen) -> io::Result<TcpListener> {
+    let family = if address.is_ipv6() { libc::AF_INET6 } else { libc::AF_INET };
+    let fd = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Closed again if anything below fails
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    if address.is_ipv6() {
+        set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, listen.ipv6only as libc::c_int)?;
+    }
+    bind_to(fd, address)?;
+    if unsafe { libc::listen(fd, listen.backlog) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(TcpListener::from(socket))
+}
+
+// A socket file left behind by a server that's gone is replaced, one still answering isn't
+fn bind_unix(path: &Path, backlog: i32) -> io::Result<UnixListener> {
+    let stale = std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket())
+        && UnixStream::connect(path).is_err();
+    if stale {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    // Listening again only changes the backlog
+    if unsafe { libc::listen(listener.as_raw_fd(), backlog) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(listener)
+}
+
+fn is_bound_to(listener: &Listener, address: &ListenAddress) -> bool {
+    match (listener, address) {
+        (Listener::Tcp(listener), ListenAddress::Tcp(address)) => listener.local_addr().is_ok_and(|addr| &addr == address),
+        (Listener::Unix(listener), ListenAddress::Unix(path)) => listener.local_addr().is_ok_and(|addr| addr.as_pathname() == Some(path.as_path())),
+        _ => false,
+    }
+}
+
+// A listener for every listen line. Ones `inherited` from the process we replace are taken
+// over for the addresses they're bound to, the rest are bound here. Inherited listeners no
+// listen line asks for anymore are closed.
+pub fn open(listen: &[Listen], inherited: Vec<Listener>) -> io::Result<Vec<Listener>> {
+    let mut inherited: Vec<Option<Listener>> = inherited.into_iter().map(Some).collect();
+    listen.iter()
+        .map(|listen| {
+            let reused = inherited.iter_mut()
+                .find(|listener| listener.as_ref().is_some_and(|l| is_bound_to(l, &listen.address)))
+                .and_then(Option::take);
+            match reused {
+                Some(listener) => Ok(listener),
+                None => bind(listen).map_err(|e| io::Error::new(e.kind(), format!("can't listen on {}: {}", listen.address, e))),
+            }
+        })
+        .collect()
+}
+
+// The fds to hand over to a new process on upgrade
+pub fn raw_fds(listeners: &[Listener]) -> Vec<RawFd> {
+    listeners.iter().map(AsRawFd::as_raw_fd).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use tempfile::TempDir;
+
+    fn listen(address: &str) -> Listen {
+        Listen::new(address.parse::<SocketAddr>().unwrap())
+    }
+
+    fn local_addr(listener: &Listener) -> SocketAddr {
+        match listener {
+            Listener::Tcp(listener) => listener.local_addr().unwrap(),
+            Listener::Unix(_) => panic!("not a TCP listener"),
+        }
+    }
+
+    #[test]
+    fn test_bind() {
+        let listener = bind(&Listen { backlog: 1, ..listen("127.0.0.1:0") }).unwrap();
+        let addr = local_addr(&listener);
+        let _client = TcpStream::connect(addr).unwrap();
+        assert!(listener.accept().is_ok());
+
+        // Taken already
+        assert_eq!(bind(&Listen::new(addr)).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn test_ipv6only() {
+        let Ok(v6) = bind(&listen("[::]:0")) else {
+            // No IPv6 here
+            return;
+        };
+        let port = local_addr(&v6).port();
+        // IPv4 on the same port is left to its own listener
+        let v4 = bind(&Listen::new(SocketAddr::from(([0, 0, 0, 0], port)))).unwrap();
+        drop((v4, v6));
+
+        let dual = bind(&Listen { ipv6only: false, ..listen("[::]:0") }).unwrap();
+        let port = local_addr(&dual).port();
+        assert!(bind(&Listen::new(SocketAddr::from(([0, 0, 0, 0], port)))).is_err());
+        assert!(TcpStream::connect(("127.0.0.1", port)).is_ok());
+    }
+
+    #[test]
+    fn test_bind_unix() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("servw.sock");
+        let unix = Listen::new(ListenAddress::Unix(path.clone()));
+
+        let listener = bind(&unix).unwrap();
+        let _client = UnixStream::connect(&path).unwrap();
+        assert!(listener.accept().is_ok());
+        // Still served, so left alone
+        assert_eq!(bind(&unix).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+        // Dropping the listener leaves the file behind
+        drop(listener);
+        assert!(path.exists());
+        let listener = bind(&unix).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(listener);
+
+        // Anything but a socket isn't ours to remove
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "data").unwrap();
+        assert!(bind(&unix).is_err());
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_open_reuses_inherited_listeners() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("servw.sock");
+        let kept = TcpListener::bind("127.0.0.1:0").unwrap();
+        let kept_addr = kept.local_addr().unwrap();
+        let kept_fd = kept.as_raw_fd();
+        let kept_unix = UnixListener::bind(&path).unwrap();
+        let kept_unix_fd = kept_unix.as_raw_fd();
+        let dropped = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dropped_addr = dropped.local_addr().unwrap();
+
+        let listeners = open(
+            &[listen("127.0.0.1:0"), Listen::new(kept_addr), Listen::new(ListenAddress::Unix(path.clone()))],
+            vec![kept_unix.into(), dropped.into(), kept.into()],
+        ).unwrap();
+        assert_eq!(listeners.len(), 3);
+        assert_ne!(local_addr(&listeners[0]).port(), 0);
+        assert_eq!(raw_fds(&listeners)[1..], [kept_fd, kept_unix_fd]);
+        // Nothing asked for it, so it's closed
+        assert!(TcpStream::connect(dropped_addr).is_err());
+
+        let taken = open(&[Listen::new(kept_addr)], vec![]).unwrap_err();
+        assert!(taken.to_string().contains(&kept_addr.to_string()));
+    }
+}
@@ -1,5 +1,17 @@
+pub mod access;
+pub mod chunked;
 pub mod config;
+pub mod connection;
+pub mod event_loop;
+pub mod health;
 pub mod http_validator;
+pub mod listeners;
+pub mod request_body;
+pub mod signals;
+pub mod socket;
+pub mod upgrade;
+pub mod upstream_pool;
+pub mod workers;
 
 pub mod lbs;
 pub mod handlers;
@@ -0,0 +1,299 @@
+use crate::handlers::simple_response;
+use crate::http_validator::{Headers, HttpError, HttpValidator, BODY_BUFFER};
+use std::fs::File;
+use std::io::{self, BufReader, Cursor, Read, Seek, Write};
+
+// Most of an unread body dropped to keep the connection open, beyond that it's closed instead
+const DISCARD_LIMIT: u64 = 1024 * 1024;
+
+static NO_TRAILERS: Headers = Headers::new();
+
+// The body of the request a handler is answering. It's decoded as the handler reads it, part
+// of it may already have come in with the head, the rest is read from the client on demand, so
+// bodies of any size go through without being held in memory. Reading fails with an io::Error
+// wrapping the HttpError that tells the handler what to answer, see `client_error`.
+pub struct RequestBody<'a> {
+    // None for requests that never had a body to read
+    validator: Option<&'a mut HttpValidator>,
+    // Where more of the body comes from, the client connection
+    source: Option<&'a mut dyn Read>,
+    // The source fails with WouldBlock while the client has nothing more to send
+    nonblocking: bool,
+}
+
+impl<'a> RequestBody<'a> {
+    // A body for the request the validator last returned, read further from `source`
+    pub fn new(validator: &'a mut HttpValidator, source: Option<&'a mut dyn Read>) -> Self {
+        Self { validator: Some(validator), source, nonblocking: false }
+    }
+
+    // Like `new`, for a source that doesn't wait for the client. Reads pass WouldBlock on, and
+    // only TimedOut from the source means the client took too long.
+    pub fn nonblocking(validator: &'a mut HttpValidator, source: Option<&'a mut dyn Read>) -> Self {
+        Self { validator: Some(validator), source, nonblocking: true }
+    }
+
+    pub fn empty() -> Self {
+        Self { validator: None, source: None, nonblocking: false }
+    }
+
+    // Content-Length the client sent, zero without a body and None when it's chunked
+    pub fn length(&self) -> Option<u64> {
+        self.validator.as_ref().map_or(Some(0), |validator| validator.body_length())
+    }
+
+    // Whether the body has been read to the end
+    pub fn is_done(&self) -> bool {
+        self.validator.as_ref().is_none_or(|validator| validator.body_done())
+    }
+
+    // Fields sent after a chunked body, known once it has been read to the end
+    pub fn trailers(&self) -> &Headers {
+        self.validator.as_ref().map_or(&NO_TRAILERS, |validator| validator.trailers())
+    }
+
+    // Reads the whole body, into memory while it's small and into an unnamed temporary file
+    // beyond that. For handlers that need its length before passing it on.
+    pub fn spool(&mut self) -> io::Result<SpooledBody> {
+        let mut memory = Vec::new();
+        (&mut *self).take(BODY_BUFFER as u64).read_to_end(&mut memory)?;
+        if self.is_done() {
+            return Ok(SpooledBody { len: memory.len() as u64, data: Spool::Memory(Cursor::new(memory)) });
+        }
+
+        let mut file = tempfile::tempfile()?;
+        file.write_all(&memory)?;
+        let len = memory.len() as u64 + io::copy(self, &mut file)?;
+        file.rewind()?;
+        Ok(SpooledBody { len, data: Spool::File(BufReader::new(file)) })
+    }
+
+    // Drops whatever the handler left unread, so the next request on the connection can be
+    // read. False when the connection has to be closed instead.
+    pub fn finish(&mut self) -> bool {
+        let Some(validator) = self.validator.as_mut() else {
+            return true;
+        };
+        if validator.finish_body() {
+            return true;
+        }
+        // A bit more of the body is worth waiting for, rather than closing the connection
+        let read = io::copy(&mut (&mut *self).take(DISCARD_LIMIT), &mut io::sink());
+        read.is_ok() && self.is_done() && self.validator.as_mut().is_some_and(|validator| validator.finish_body())
+    }
+}
+
+impl Read for RequestBody<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(validator) = self.validator.as_mut() else {
+            return Ok(0);
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let nonblocking = self.nonblocking;
+        let mut chunk = [0; 16 * 1024];
+        loop {
+            if let Some(n) = validator.read_body(buf).map_err(into_io)? {
+                return Ok(n);
+            }
+            let source = self.source.as_mut()
+                .ok_or_else(|| into_io(HttpError::BadRequest("connection closed mid-request")))?;
+            let n = match source.read(&mut chunk) {
+                Ok(0) => return Err(into_io(HttpError::BadRequest("connection closed mid-request"))),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock && nonblocking => return Err(e),
+                Err(e) if crate::connection::is_timeout(&e) => return Err(into_io(HttpError::RequestTimeout)),
+                Err(e) => return Err(into_io(HttpError::Io(e))),
+            };
+            validator.feed(&chunk[..n]).map_err(into_io)?;
+        }
+    }
+}
+
+fn into_io(error: HttpError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+// What went wrong on the client's side when reading the body failed, None when the handler
+// itself is to blame, such as a temporary file that couldn't be written
+pub fn client_error(error: &io::Error) -> Option<&HttpError> {
+    error.get_ref().and_then(|inner| inner.downcast_ref::<HttpError>())
+}
+
+// Answers a request whose body couldn't be read, with the client's 4xx or a 500 when the
+// failure was on our side. Fails when the client is gone and there's nobody to answer.
+pub fn reject_body(error: io::Error, out: &mut dyn Write) -> io::Result<()> {
+    let status = match client_error(&error) {
+        Some(HttpError::Io(_)) => return Err(error),
+        Some(e) => e.status().unwrap_or(400),
+        None => 500,
+    };
+    println!("Request body failed: {}", error);
+    out.write_all(simple_response(status).as_bytes())
+}
+
+enum Spool {
+    Memory(Cursor<Vec<u8>>),
+    File(BufReader<File>),
+}
+
+// A request body read in full, see `RequestBody::spool`
+pub struct SpooledBody {
+    len: u64,
+    data: Spool,
+}
+
+impl SpooledBody {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for SpooledBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.data {
+            Spool::Memory(cursor) => cursor.read(buf),
+            Spool::File(file) => file.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_validator::Status;
+
+    // Parses the head, then hands out the body with `rest` still to come from the client
+    fn body_of<'a>(validator: &'a mut HttpValidator, head: &[u8], rest: &'a mut &[u8]) -> RequestBody<'a> {
+        validator.feed(head).unwrap();
+        validator.get_request();
+        RequestBody::new(validator, Some(rest))
+    }
+
+    #[test]
+    fn test_body_read_from_client() {
+        let mut validator = HttpValidator::new();
+        let mut rest: &[u8] = b"lo\r\n0\r\nX-Sum: 1\r\n\r\nGET / HTTP/1.1\r\n";
+        let mut body = body_of(&mut validator, b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel", &mut rest);
+        assert_eq!(body.length(), None);
+        let mut read = String::new();
+        body.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "hello");
+        assert!(body.is_done());
+        assert_eq!(body.trailers().get("x-sum"), Some("1"));
+        assert!(body.finish());
+
+        // The next request is left for the connection
+        validator.feed(b"Host: a\r\n\r\n").unwrap();
+        assert_eq!(validator.get_request().path(), "/");
+    }
+
+    #[test]
+    fn test_client_errors() {
+        let mut validator = HttpValidator::new();
+        let mut rest: &[u8] = b"ab";
+        let mut body = body_of(&mut validator, b"PUT / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\n", &mut rest);
+        let error = body.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(client_error(&error).and_then(HttpError::status), Some(400));
+        assert!(!body.finish());
+
+        let limits = crate::http_validator::Limits { max_body: 4, ..Default::default() };
+        let mut validator = HttpValidator::with_limits(limits);
+        let mut rest: &[u8] = b"3\r\nabc\r\n";
+        let mut body = body_of(&mut validator, b"PUT / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nab\r\n", &mut rest);
+        let error = body.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(client_error(&error).and_then(HttpError::status), Some(413));
+
+        assert!(client_error(&io::Error::other("disk full")).is_none());
+    }
+
+    #[test]
+    fn test_unread_body_is_skipped() {
+        let mut validator = HttpValidator::new();
+        let mut rest: &[u8] = b"defGET /next HTTP/1.1\r\nHost: a\r\n\r\n";
+        let mut body = body_of(&mut validator, b"PUT / HTTP/1.1\r\nHost: a\r\nContent-Length: 6\r\n\r\nabc", &mut rest);
+        assert!(body.finish());
+        assert_eq!(validator.feed(&[]).unwrap(), Status::Complete);
+        assert_eq!(validator.get_request().path(), "/next");
+    }
+
+    #[test]
+    fn test_spool() {
+        let mut validator = HttpValidator::new();
+        let mut rest: &[u8] = b"";
+        let mut body = body_of(&mut validator, b"PUT / HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\nabc", &mut rest);
+        let mut spooled = body.spool().unwrap();
+        assert_eq!(spooled.len(), 3);
+        assert!(matches!(spooled.data, Spool::Memory(_)));
+        let mut read = String::new();
+        spooled.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "abc");
+
+        // Large ones go to a file
+        let data: Vec<u8> = (0..3 * BODY_BUFFER).map(|i| i as u8).collect();
+        let mut validator = HttpValidator::new();
+        let head = format!("PUT / HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\n\r\n", data.len());
+        let mut rest = &data[BODY_BUFFER..];
+        let mut body = body_of(&mut validator, &[head.as_bytes(), &data[..BODY_BUFFER]].concat(), &mut rest);
+        let mut spooled = body.spool().unwrap();
+        assert_eq!(spooled.len(), data.len() as u64);
+        assert!(matches!(spooled.data, Spool::File(_)));
+        let mut read = Vec::new();
+        spooled.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    // Hands out the bytes it's given one at a time, then would block
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((&byte, rest)) = self.0.split_first() else {
+                return Err(io::ErrorKind::WouldBlock.into());
+            };
+            buf[0] = byte;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_nonblocking_source() {
+        let mut validator = HttpValidator::new();
+        validator.feed(b"PUT / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\n").unwrap();
+        validator.get_request();
+        let mut source = Trickle(b"ab");
+        let mut body = RequestBody::nonblocking(&mut validator, Some(&mut source));
+        let mut read = Vec::new();
+        let error = body.read_to_end(&mut read).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(read, b"ab");
+        assert!(!body.is_done());
+        // Without waiting for the rest
+        assert!(!body.finish());
+
+        // Blocking sources that time out leave the client too slow
+        let mut validator = HttpValidator::new();
+        let mut source = Trickle(b"");
+        validator.feed(b"PUT / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\n").unwrap();
+        validator.get_request();
+        let error = RequestBody::new(&mut validator, Some(&mut source)).read(&mut [0; 4]).unwrap_err();
+        assert_eq!(client_error(&error).and_then(HttpError::status), Some(408));
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut body = RequestBody::empty();
+        assert_eq!(body.length(), Some(0));
+        assert!(body.is_done());
+        assert_eq!(body.read(&mut [0; 4]).unwrap(), 0);
+        assert!(body.finish());
+    }
+}
@@ -0,0 +1,90 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+pub use libc::{SIGHUP, SIGINT, SIGTERM, SIGUSR2};
+
+// Write end of the pipe the signal handler reports to
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    // The interrupted code may be about to read errno after a failed call, a failed write
+    // here mustn't change what it sees
+    let errno = unsafe { *libc::__errno_location() };
+    let fd = PIPE.load(Ordering::Relaxed);
+    if fd >= 0 {
+        let byte = signal as u8;
+        // write(2) is async-signal-safe, and the pipe doesn't block, a full pipe drops the signal
+        unsafe {
+            libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+        }
+    }
+    unsafe { *libc::__errno_location() = errno };
+}
+
+// Turns signals into bytes on a pipe, so they can be dealt with outside the signal handler
+pub struct Signals {
+    pipe: File,
+}
+
+impl Signals {
+    // Catches `signals` from now on, only one Signals may be installed per process
+    pub fn install(signals: &[libc::c_int]) -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let pipe = unsafe { File::from_raw_fd(fds[0]) };
+        if unsafe { libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK) } != 0
+            || PIPE.compare_exchange(-1, fds[1], Ordering::SeqCst, Ordering::SeqCst).is_err()
+        {
+            unsafe { libc::close(fds[1]) };
+            return Err(io::Error::other("signal handlers are already installed"));
+        }
+
+        for &signal in signals {
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(Self { pipe })
+    }
+
+    // Blocks until one of the signals arrives and returns it
+    pub fn wait(&mut self) -> io::Result<libc::c_int> {
+        let mut byte = [0; 1];
+        loop {
+            match self.pipe.read(&mut byte) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => return Ok(libc::c_int::from(byte[0])),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signals_are_reported() {
+        let mut signals = Signals::install(&[libc::SIGUSR1, libc::SIGUSR2]).unwrap();
+        unsafe {
+            libc::raise(libc::SIGUSR2);
+            libc::raise(libc::SIGUSR1);
+        }
+        assert_eq!(signals.wait().unwrap(), libc::SIGUSR2);
+        assert_eq!(signals.wait().unwrap(), libc::SIGUSR1);
+
+        assert!(Signals::install(&[libc::SIGUSR1]).is_err());
+    }
+}
@@ -0,0 +1,324 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// The path of a `unix:/path` address, None for host:port ones
+pub fn unix_path(address: &str) -> Option<&Path> {
+    address.strip_prefix("unix:").map(Path::new)
+}
+
+// One address a server can be reached on, host names already looked up
+#[derive(Debug, Clone)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+// The addresses behind `unix:/path` or host:port, in the order they should be tried
+pub fn resolve(address: &str) -> io::Result<Vec<Peer>> {
+    if let Some(path) = unix_path(address) {
+        return Ok(vec![Peer::Unix(path.to_path_buf())]);
+    }
+    let peers: Vec<Peer> = address.to_socket_addrs()?.map(Peer::Tcp).collect();
+    if peers.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no address", address)));
+    }
+    Ok(peers)
+}
+
+// A connection over TCP or a Unix domain socket, everything past connecting and accepting
+// works the same on both
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    // Connects to `unix:/path`, or to the first address of host:port that answers within
+    // `timeout`. Unix sockets either take the connection or refuse it right away.
+    pub fn connect(address: &str, timeout: Duration) -> io::Result<Stream> {
+        if let Some(path) = unix_path(address) {
+            return UnixStream::connect(path).map(Stream::Unix);
+        }
+
+        let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no address", address));
+        for addr in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(Stream::Tcp(stream)),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    // Starts connecting without waiting for it, the socket turns writable once the connection
+    // is up or has failed, see `is_connected`
+    pub fn connect_nonblocking(peer: &Peer) -> io::Result<Stream> {
+        match peer {
+            Peer::Tcp(addr) => Ok(Stream::Tcp(OwnedFd::from(mio::net::TcpStream::connect(*addr)?).into())),
+            Peer::Unix(path) => Ok(Stream::Unix(OwnedFd::from(mio::net::UnixStream::connect(path)?).into())),
+        }
+    }
+
+    // Whether a connection started with `connect_nonblocking` is up, false while it's still
+    // being set up and the error when it failed
+    pub fn is_connected(&self) -> io::Result<bool> {
+        let (error, peer) = match self {
+            Stream::Tcp(stream) => (stream.take_error()?, stream.peer_addr().map(drop)),
+            Stream::Unix(stream) => (stream.take_error()?, stream.peer_addr().map(drop)),
+        };
+        if let Some(e) = error {
+            return Err(e);
+        }
+        match peer {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    // Unix socket peers have no address worth passing on
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.local_addr().ok(),
+            Stream::Unix(_) => None,
+        }
+    }
+
+    // Whether the peer closed the connection or sent something, without waiting or taking it
+    pub fn has_pending_input(&self) -> bool {
+        let mut byte = [0u8; 1];
+        let n = unsafe {
+            libc::recv(self.as_raw_fd(), byte.as_mut_ptr() as *mut libc::c_void, 1, libc::MSG_PEEK | libc::MSG_DONTWAIT)
+        };
+        n >= 0 || io::Error::last_os_error().kind() != io::ErrorKind::WouldBlock
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Tcp(stream)
+    }
+}
+
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Self {
+        Stream::Unix(stream)
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(stream) => stream.as_raw_fd(),
+            Stream::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+// A socket accepting connections over TCP or on a Unix domain socket
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            Listener::Unix(listener) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Listener::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener)
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+// The address the way a listen line writes it
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            Listener::Unix(listener) => match listener.local_addr().ok().and_then(|addr| addr.as_pathname().map(Path::to_path_buf)) {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => write!(f, "unix"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_unix_and_tcp_streams() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("servw.sock");
+        let unix = Listener::from(UnixListener::bind(&path).unwrap());
+        let tcp = Listener::from(TcpListener::bind("127.0.0.1:0").unwrap());
+        let unix_address = format!("unix:{}", path.display());
+        let tcp_address = tcp.to_string();
+        assert_eq!(unix.to_string(), unix_address);
+
+        for (listener, address) in [(unix, unix_address), (tcp, tcp_address)] {
+            let mut client = Stream::connect(&address, Duration::from_secs(1)).unwrap();
+            let mut server = listener.accept().unwrap();
+            assert!(!server.has_pending_input());
+            client.write_all(b"ping").unwrap();
+            let mut buffer = [0; 4];
+            server.read_exact(&mut buffer).unwrap();
+            assert_eq!(&buffer, b"ping");
+
+            drop(client);
+            std::thread::sleep(Duration::from_millis(20));
+            assert!(server.has_pending_input());
+        }
+
+        assert!(Stream::connect(&format!("unix:{}", dir.path().join("missing.sock").display()), Duration::from_secs(1)).is_err());
+    }
+
+    // Waits for a connection started without blocking to go through or fail
+    fn settle(stream: &Stream) -> io::Result<bool> {
+        for _ in 0..100 {
+            if stream.is_connected()? {
+                return Ok(true);
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        Ok(false)
+    }
+
+    #[test]
+    fn test_connect_nonblocking() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("servw.sock");
+        let unix = UnixListener::bind(&path).unwrap();
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addresses = [format!("unix:{}", path.display()), tcp.local_addr().unwrap().to_string()];
+
+        for address in &addresses {
+            let peers = resolve(address).unwrap();
+            let stream = Stream::connect_nonblocking(&peers[0]).unwrap();
+            assert!(settle(&stream).unwrap());
+        }
+        assert!(unix.accept().is_ok() && tcp.accept().is_ok());
+
+        // Refused, right away or once the attempt settles
+        drop(tcp);
+        let refused = resolve(&addresses[1]).unwrap();
+        assert!(Stream::connect_nonblocking(&refused[0]).and_then(|stream| settle(&stream)).is_err());
+        assert!(resolve("nowhere").is_err());
+    }
+}
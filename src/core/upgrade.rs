@@ -0,0 +1,203 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use crate::socket::Listener;
+use std::net::TcpListener;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::time::Duration;
+
+// Listening sockets handed over by the process being replaced, as a comma separated list of fds
+pub const LISTEN_FDS: &str = "SERVW_LISTEN_FDS";
+// Pipe the new process reports on once it is serving
+pub const READY_FD: &str = "SERVW_READY_FD";
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if cloexec { flags | libc::FD_CLOEXEC } else { flags & !libc::FD_CLOEXEC };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn socket_option(fd: RawFd, name: libc::c_int) -> Option<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, name, &mut value as *mut _ as *mut libc::c_void, &mut len)
+    };
+    (result == 0).then_some(value)
+}
+
+// The address family of a listening socket, None for anything else
+fn listening_family(fd: RawFd) -> Option<libc::c_int> {
+    socket_option(fd, libc::SO_ACCEPTCONN).filter(|&listening| listening != 0)?;
+    socket_option(fd, libc::SO_DOMAIN)
+}
+
+// Takes ownership of the listeners named in a LISTEN_FDS value. Every fd has to be a
+// listening socket, they are closed on exec again so scripts we run don't get them.
+pub fn listeners_from(fds: &str) -> io::Result<Vec<Listener>> {
+    let invalid = |fd: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a listening socket: {}", LISTEN_FDS, fd));
+    let fds = fds.split(',')
+        .map(|fd| {
+            let raw = fd.trim().parse::<RawFd>().ok();
+            raw.and_then(|raw| Some((raw, listening_family(raw)?))).ok_or_else(|| invalid(fd))
+        })
+        .collect::<io::Result<Vec<(RawFd, libc::c_int)>>>()?;
+
+    fds.into_iter()
+        .map(|(fd, family)| {
+            set_cloexec(fd, true)?;
+            Ok(match family {
+                libc::AF_UNIX => Listener::Unix(unsafe { UnixListener::from_raw_fd(fd) }),
+                _ => Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) }),
+            })
+        })
+        .collect()
+}
+
+// The listeners inherited from the process we replace, empty when started normally
+pub fn inherited_listeners() -> io::Result<Vec<Listener>> {
+    match std::env::var(LISTEN_FDS) {
+        Ok(fds) => listeners_from(&fds),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+// Lets the process that started us know we're serving, does nothing when started normally
+pub fn notify_ready() -> io::Result<()> {
+    let fd = match std::env::var(READY_FD).ok().and_then(|fd| fd.parse::<RawFd>().ok()) {
+        Some(fd) => fd,
+        None => return Ok(()),
+    };
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut pipe = unsafe { File::from_raw_fd(fd) };
+    pipe.write_all(b"1")
+}
+
+// Waits up to `timeout` for a byte on `pipe`, false when it closed or nothing came
+fn wait_readable(pipe: &mut File, timeout: Duration) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd { fd: pipe.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    match unsafe { libc::poll(&mut pollfd, 1, millis) } {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        0 => Ok(false),
+        _ => Ok(pipe.read(&mut [0; 1])? == 1),
+    }
+}
+
+// Starts `command` with `listeners` open in it and waits for it to report it's ready. The
+// listeners stay ours as well, until we close them. A process that exits or doesn't report
+// within `timeout` is killed and an error returned, so the caller can keep serving.
+pub fn spawn_successor(mut command: Command, listeners: &[RawFd], timeout: Duration) -> io::Result<Child> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let (mut ready, notify) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    let inherited: Vec<RawFd> = listeners.iter().copied().chain([notify.as_raw_fd()]).collect();
+    let fd_list = listeners.iter().map(|fd| fd.to_string()).collect::<Vec<_>>().join(",");
+    command.env(LISTEN_FDS, fd_list).env(READY_FD, notify.as_raw_fd().to_string());
+    // Runs in the child between fork and exec, only clears close-on-exec on our fds there
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &inherited {
+                set_cloexec(fd, false)?;
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+    // Otherwise the pipe can't tell us about a child that died
+    drop(notify);
+    match wait_readable(&mut ready, timeout) {
+        Ok(true) => Ok(child),
+        result => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(result.err().unwrap_or_else(|| io::Error::other("the new process didn't start serving")))
+        }
+    }
+}
+
+// Runs the binary we were started from again, with the same arguments
+pub fn upgrade_command() -> io::Result<Command> {
+    let mut args = std::env::args_os();
+    // argv[0] rather than /proc/self/exe, which still points at the old binary once replaced
+    let program = match args.next() {
+        Some(program) => program,
+        None => std::env::current_exe()?.into_os_string(),
+    };
+    let mut command = Command::new(program);
+    command.args(args);
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+    use std::process::Stdio;
+
+    // Stands in for the new binary
+    fn successor(script: &str) -> Command {
+        let mut command = Command::new("/bin/sh");
+        command.args(["-c", script]).stdin(Stdio::piped());
+        command
+    }
+
+    #[test]
+    fn test_successor_gets_the_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let script = r#"[ -S /proc/self/fd/$SERVW_LISTEN_FDS ] && echo 1 > /proc/self/fd/$SERVW_READY_FD && cat > /dev/null"#;
+        let mut child = spawn_successor(successor(script), &[listener.as_raw_fd()], Duration::from_secs(5)).unwrap();
+
+        // The listener is still open in the successor once we let go of it
+        drop(listener);
+        assert!(TcpStream::connect(addr).is_ok());
+        drop(child.stdin.take());
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_failed_successor() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(spawn_successor(successor("exit 1"), &[listener.as_raw_fd()], Duration::from_secs(5)).is_err());
+        assert!(spawn_successor(successor("exec sleep 10"), &[listener.as_raw_fd()], Duration::from_millis(100)).is_err());
+
+        // Still ours to accept on
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).unwrap();
+        assert!(listener.accept().is_ok());
+    }
+
+    #[test]
+    fn test_listeners_from() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = unsafe { libc::dup(listener.as_raw_fd()) };
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let unix = UnixListener::bind(dir.path().join("servw.sock")).unwrap();
+        let unix_fd = unsafe { libc::dup(unix.as_raw_fd()) };
+
+        let inherited = listeners_from(&format!("{},{}", fd, unix_fd)).unwrap();
+        assert!(matches!(&inherited[0], Listener::Tcp(listener) if listener.local_addr().unwrap() == addr));
+        assert!(matches!(&inherited[1], Listener::Unix(_)));
+
+        let stream = TcpStream::connect(addr).unwrap();
+        assert!(listeners_from(&stream.as_raw_fd().to_string()).is_err());
+        assert!(listeners_from("nope").is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use crate::config::Config;
+use crate::socket::Stream;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct IdleConnection {
+    stream: Stream,
+    since: Instant,
+}
+
+// Idle keep-alive connections to upstream servers, kept per server address so it works the
+// same whichever balancer picked the server. At most max_idle connections are kept for each
+// server, connections idle for longer than idle_timeout are closed rather than reused.
+pub struct UpstreamPool {
+    idle: Mutex<HashMap<String, Vec<IdleConnection>>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+impl UpstreamPool {
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Self {
+        Self {
+            idle: Mutex::new(HashMap::new()),
+            max_idle,
+            idle_timeout,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(config.upstream_keepalive(), config.upstream_keepalive_timeout())
+    }
+
+    // Whether connections are pooled at all, upstreams are asked to close them otherwise
+    pub fn is_enabled(&self) -> bool {
+        self.max_idle > 0
+    }
+
+    // Takes the most recently used idle connection to the server that is still good. A
+    // connection the upstream closed, or one with bytes nobody asked for, can't carry a request.
+    pub fn get(&self, server: &str) -> Option<Stream> {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.get_mut(server)?;
+        while let Some(connection) = connections.pop() {
+            if connection.since.elapsed() < self.idle_timeout && !connection.stream.has_pending_input() {
+                return Some(connection.stream);
+            }
+        }
+        None
+    }
+
+    // Hands back a connection that finished its exchange and may carry another request
+    pub fn put(&self, server: &str, stream: Stream) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.entry(server.to_string()).or_default();
+        connections.retain(|connection| connection.since.elapsed() < self.idle_timeout);
+        if connections.len() >= self.max_idle {
+            connections.remove(0);
+        }
+        connections.push(IdleConnection { stream, since: Instant::now() });
+    }
+
+    pub fn idle_count(&self, server: &str) -> usize {
+        self.idle.lock().unwrap().get(server).map_or(0, Vec::len)
+    }
+
+    // Closes every idle connection
+    pub fn clear(&self) {
+        self.idle.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+
+    // A connected pair of streams, the server end is returned to keep it open
+    fn connection(listener: &TcpListener) -> (Stream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client.into(), server)
+    }
+
+    #[test]
+    fn test_reuses_most_recent_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pool = UpstreamPool::new(4, Duration::from_secs(60));
+        assert!(pool.get("a").is_none());
+
+        let (first, _first_server) = connection(&listener);
+        let (second, _second_server) = connection(&listener);
+        let second_port = second.local_addr().unwrap().port();
+        pool.put("a", first);
+        pool.put("a", second);
+        assert_eq!(pool.idle_count("a"), 2);
+
+        assert_eq!(pool.get("a").unwrap().local_addr().unwrap().port(), second_port);
+        assert!(pool.get("b").is_none());
+        assert_eq!(pool.idle_count("a"), 1);
+
+        pool.clear();
+        assert!(pool.get("a").is_none());
+    }
+
+    #[test]
+    fn test_max_idle() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pool = UpstreamPool::new(2, Duration::from_secs(60));
+        let mut servers = Vec::new();
+        for _ in 0..3 {
+            let (client, server) = connection(&listener);
+            servers.push(server);
+            pool.put("a", client);
+        }
+        assert_eq!(pool.idle_count("a"), 2);
+
+        let disabled = UpstreamPool::new(0, Duration::from_secs(60));
+        disabled.put("a", connection(&listener).0);
+        assert_eq!(disabled.idle_count("a"), 0);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pool = UpstreamPool::new(2, Duration::from_millis(50));
+        let (client, _server) = connection(&listener);
+        pool.put("a", client);
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(pool.get("a").is_none());
+    }
+
+    #[test]
+    fn test_stale_connections_are_skipped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let pool = UpstreamPool::new(4, Duration::from_secs(60));
+
+        let (good, _good_server) = connection(&listener);
+        let good_port = good.local_addr().unwrap().port();
+        let (closed, closed_server) = connection(&listener);
+        let (chatty, mut chatty_server) = connection(&listener);
+        pool.put("a", good);
+        pool.put("a", closed);
+        pool.put("a", chatty);
+
+        drop(closed_server);
+        chatty_server.write_all(b"HTTP/1.1 408 Request Timeout\r\n\r\n").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+
+        let reused = pool.get("a").unwrap();
+        assert_eq!(reused.local_addr().unwrap().port(), good_port);
+        assert!(pool.get("a").is_none());
+    }
+}
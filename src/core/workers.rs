@@ -0,0 +1,199 @@
+use crate::config::Config;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+// What to do with new work once the queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueFull {
+    // Hand the work back, so the caller can answer 503
+    Reject,
+    // Block until a worker frees up a spot
+    Wait,
+}
+
+// A fixed number of threads running `work` on items taken from a bounded queue. Dropping the
+// pool lets the workers finish what was queued, then waits for them.
+pub struct WorkerPool<T: Send + 'static> {
+    sender: Option<SyncSender<T>>,
+    workers: Vec<JoinHandle<()>>,
+    when_full: QueueFull,
+}
+
+fn worker<T, F>(receiver: Arc<Mutex<Receiver<T>>>, work: Arc<F>)
+where
+    F: Fn(T) + Send + Sync + 'static,
+{
+    loop {
+        // Only held while waiting for an item, not while working on it
+        let item = match receiver.lock().unwrap().recv() {
+            Ok(item) => item,
+            Err(_) => return,
+        };
+        // A panicking handler takes its own connection down, not the worker
+        if panic::catch_unwind(AssertUnwindSafe(|| work(item))).is_err() {
+            eprintln!("Worker recovered from a panic");
+        }
+    }
+}
+
+impl<T: Send + 'static> WorkerPool<T> {
+    pub fn new<F>(workers: usize, queue_size: usize, when_full: QueueFull, work: F) -> Self
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let work = Arc::new(work);
+        let workers = (0..workers.max(1))
+            .map(|i| {
+                let receiver = receiver.clone();
+                let work = work.clone();
+                std::thread::Builder::new()
+                    .name(format!("worker-{}", i))
+                    .spawn(move || worker(receiver, work))
+                    .expect("failed to spawn worker thread")
+            })
+            .collect();
+
+        Self {
+            sender: Some(sender),
+            workers,
+            when_full,
+        }
+    }
+
+    pub fn from_config<F>(config: &Config, work: F) -> Self
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        Self::new(config.worker_threads(), config.worker_queue(), config.worker_queue_full(), work)
+    }
+
+    // Queues an item for the next free worker. With QueueFull::Reject a full queue hands
+    // the item straight back.
+    pub fn submit(&self, item: T) -> Result<(), T> {
+        let sender = self.sender.as_ref().expect("pool is running");
+        match self.when_full {
+            QueueFull::Reject => sender.try_send(item).map_err(|e| match e {
+                TrySendError::Full(item) | TrySendError::Disconnected(item) => item,
+            }),
+            QueueFull::Wait => sender.send(item).map_err(|e| e.0),
+        }
+    }
+
+    // Queues an item if there's room, whatever `when_full` says, for callers that can't block
+    pub fn try_submit(&self, item: T) -> Result<(), T> {
+        let sender = self.sender.as_ref().expect("pool is running");
+        sender.try_send(item).map_err(|e| match e {
+            TrySendError::Full(item) | TrySendError::Disconnected(item) => item,
+        })
+    }
+
+    pub fn when_full(&self) -> QueueFull {
+        self.when_full
+    }
+}
+
+impl<T: Send + 'static> Drop for WorkerPool<T> {
+    fn drop(&mut self) {
+        // Workers stop once the queue is drained and the sender is gone
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    #[test]
+    fn test_runs_everything_on_fixed_threads() {
+        let threads = Arc::new(Mutex::new(HashSet::new()));
+        let seen = threads.clone();
+        let (done, finished) = channel();
+        let pool = WorkerPool::new(3, 100, QueueFull::Wait, move |n: usize| {
+            seen.lock().unwrap().insert(std::thread::current().name().unwrap().to_string());
+            done.send(n).unwrap();
+        });
+        for n in 0..50 {
+            pool.submit(n).unwrap();
+        }
+        drop(pool);
+
+        let mut results: Vec<usize> = finished.iter().collect();
+        results.sort();
+        assert_eq!(results, (0..50).collect::<Vec<_>>());
+        assert!(threads.lock().unwrap().len() <= 3);
+    }
+
+    // One worker blocked on `release`, with `queue_size` spots behind it
+    fn blocked_pool(queue_size: usize, when_full: QueueFull) -> (WorkerPool<u32>, SyncSender<()>, Receiver<u32>) {
+        let (release, released) = mpsc::sync_channel::<()>(0);
+        let released = Mutex::new(released);
+        let (started, starts) = channel();
+        let (done, finished) = channel();
+        let pool = WorkerPool::new(1, queue_size, when_full, move |n| {
+            // Only the first start is waited for
+            let _ = started.send(());
+            released.lock().unwrap().recv().unwrap();
+            done.send(n).unwrap();
+        });
+        pool.submit(0).unwrap();
+        starts.recv().unwrap();
+        (pool, release, finished)
+    }
+
+    #[test]
+    fn test_rejects_when_queue_full() {
+        let (pool, release, finished) = blocked_pool(1, QueueFull::Reject);
+        assert_eq!(pool.submit(1), Ok(()));
+        assert_eq!(pool.submit(2), Err(2));
+
+        release.send(()).unwrap();
+        release.send(()).unwrap();
+        drop(pool);
+        assert_eq!(finished.iter().collect::<Vec<_>>(), [0, 1]);
+    }
+
+    #[test]
+    fn test_waits_when_queue_full() {
+        let (pool, release, finished) = blocked_pool(1, QueueFull::Wait);
+        pool.submit(1).unwrap();
+
+        let releaser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            for _ in 0..3 {
+                release.send(()).unwrap();
+            }
+        });
+        // Never blocks when asked not to
+        assert_eq!(pool.try_submit(2), Err(2));
+        // Blocks until the first item is done
+        pool.submit(2).unwrap();
+        drop(pool);
+        releaser.join().unwrap();
+        assert_eq!(finished.iter().collect::<Vec<_>>(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_survives_panics() {
+        let (done, finished) = channel();
+        let pool = WorkerPool::new(1, 10, QueueFull::Wait, move |n: u32| {
+            if n == 0 {
+                panic!("bad request");
+            }
+            done.send(n).unwrap();
+        });
+        pool.submit(0).unwrap();
+        pool.submit(1).unwrap();
+        drop(pool);
+        assert_eq!(finished.iter().collect::<Vec<_>>(), [1]);
+    }
+}
@@ -1,6 +1,18 @@
 mod core;
 
+pub use crate::core::access;
+pub use crate::core::chunked;
 pub use crate::core::config;
+pub use crate::core::connection;
+pub use crate::core::event_loop;
+pub use crate::core::health;
 pub use crate::core::http_validator;
+pub use crate::core::listeners;
+pub use crate::core::request_body;
+pub use crate::core::signals;
+pub use crate::core::socket;
+pub use crate::core::upgrade;
+pub use crate::core::upstream_pool;
+pub use crate::core::workers;
 pub use crate::core::lbs;
 pub use crate::core::handlers;
@@ -1,133 +1,140 @@
-use std::net::{TcpListener, TcpStream};
 use std::path::Path;
 use std::process::exit;
 use std::sync::{Arc, Mutex};
-use std::io::Write;
+use std::time::Duration;
 use servw::config::Config;
-use servw::lbs::{LeastConn, LoadBalancer, None, RoundRobin};
-use servw::handlers::{CgiHandler, Handler, ServerHandler};
+use servw::event_loop::EventLoop;
+use servw::health::{HealthChecker, HealthRegistry};
+use servw::signals::{Signals, SIGHUP, SIGINT, SIGTERM, SIGUSR2};
+use servw::socket::Listener;
+use servw::{listeners, upgrade};
+use servw::upstream_pool::UpstreamPool;
+use servw::lbs::{LeastConn, LoadBalancer, None, RoundRobin, Source, WeightedRoundRobin};
+use servw::handlers::{is_fastcgi_address, CgiHandler, FastCgiHandler, Handler, ReloadableHandler, ServerHandler, StaticFileHandler};
 
-fn main() -> std::io::Result<()> {
+// How long a new binary gets to start serving before the upgrade is called off
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(30);
 
-    let mut config = Config::new();
+fn main() -> std::io::Result<()> {
 
-    match config.parse("http.conf") {
-        Ok(_) => {},
+    let config = match load_config() {
+        Ok(config) => config,
         Err(e) => {
-            println!("Config parsing error: {}", e);
+            println!("Config error: {}", e);
             exit(1);
         }
+    };
+
+    // Started by SIGUSR2, the previous process hands over the sockets it was listening on
+    let listeners = listeners::open(&config.listen(), upgrade::inherited_listeners()?)?;
+    for listener in &listeners {
+        println!("Listening on {}", listener);
     }
 
+    // Shared by every generation, idle connections are kept per server address
+    let pool = Arc::new(UpstreamPool::from_config(&config));
+    let handler = build_handler(&config, pool.clone());
+    println!("starting listening to the incoming requests");
+    serve(listeners, Arc::new(ReloadableHandler::new(handler)), &config, pool)
+}
+
+fn load_config() -> std::io::Result<Config> {
+    let mut config = Config::new();
+    config.parse("http.conf")?;
+
     // check if the root folder exists
     if !Path::new(&config.root()).exists() {
-        println!("Error: Root folder does not exist");
-        exit(1);
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "Root folder does not exist"));
     }
+    Ok(config)
+}
 
-    let port = config.listen();
-    println!("Listening to port {}", port);
-    let listener = TcpListener::bind("127.0.0.1:".to_string() + port)?;
+// Builds the handlers for one generation of the config
+fn build_handler(config: &Config, pool: Arc<UpstreamPool>) -> Box<dyn Handler> {
     let alb_type = config.lb_algo();
-
-
     println!("alb_type: {:?}", alb_type);
 
     if alb_type == "off" {
-        println!("Load balancing is disabled. We will use cgi pass instead.");
-        for stream in listener.incoming() {
-            let config = config.clone();
-            std::thread::spawn(move || {
-                let mut stream = stream.unwrap();
-                // copy the config to pass to CgiHandler
-                let cgi_handler: Box<dyn Handler> = Box::new(CgiHandler::new(config));
-                match handle_connection(&stream, cgi_handler) {
-                    Ok(result) => {
-                        match stream.write_all(result.as_bytes()) {
-                            Ok(_) => {
-                                stream.flush().unwrap_or_default();
-                            },
-                            Err(e) => {
-                                println!("Write error: {}", e);
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        println!("Connection error: {}", e);
-                        let error_response = format!(
-                            "HTTP/1.1 500 Internal Server Error\r\n\
-                                Content-Length: {}\r\n\
-                                Content-Type: text/plain\r\n\
-                                Connection: close\r\n\
-                                \r\n\
-                                {}", 
-                            e.to_string().len(),
-                            e
-                        );
-                        if let Err(write_err) = stream.write_all(error_response.as_bytes()) {
-                            println!("Error writing error response: {}", write_err);
-                        }
-                        stream.flush().unwrap_or_default();
-                    }
-                }
-            });
-        }
-        return Ok(());
+        println!("Load balancing is disabled. Serving files from root and passing scripts to cgi instead.");
+        // pass either names a FastCGI socket or a CGI interpreter
+        let scripts: Box<dyn Handler> = if is_fastcgi_address(config.pass()) {
+            Box::new(FastCgiHandler::new(config.clone()))
+        } else {
+            Box::new(CgiHandler::new(config.clone()))
+        };
+        return Box::new(StaticFileHandler::new(config.clone(), scripts));
     }
 
+    // alb_algo only takes the names below, Config::parse rejects anything else
     let lb: Box<dyn LoadBalancer> = match alb_type {
-        "none" => Box::new(None::new(config.servers())),
         "roundrobin" => Box::new(RoundRobin::new(config.servers())),
+        "weighted" => Box::new(WeightedRoundRobin::new(config.upstreams())),
         "leastconn" => Box::new(LeastConn::new(config.servers())),
-        _ => {
-            println!("Error: Invalid load balancing algorithm");
-            exit(1);
-        }
+        "source" => Box::new(Source::new(config.servers())),
+        _ => Box::new(None::new(config.servers())),
     };
 
     let mutexlb = Arc::new(Mutex::new(lb));
-    println!("starting listening to the incoming requests");
-    for stream in listener.incoming() {
-        let mutexlb = mutexlb.clone();
-        std::thread::spawn(move || {
-            let handler: Box<dyn Handler> = Box::new(ServerHandler::new(mutexlb));
-            let mut stream = stream.unwrap(); 
-            match handle_connection(&stream, handler) {
-                Ok(result) => {
-                    // Single write with proper error handling
-                    match stream.write_all(result.as_bytes()) {
-                        Ok(_) => {
-                            stream.flush().unwrap_or_default();
-                        },
-                        Err(e) => {
-                            println!("Write error: {}", e);
-                        }
-                    }
-                }
-                Err(e) => {
-                    println!("Connection error: {}", e);
-                    let error_response = format!(
-                        "HTTP/1.1 500 Internal Server Error\r\n\
-                            Content-Length: {}\r\n\
-                            Content-Type: text/plain\r\n\
-                            Connection: close\r\n\
-                            \r\n\
-                            {}", 
-                        e.to_string().len(),
-                        e
-                    );
-                    if let Err(write_err) = stream.write_all(error_response.as_bytes()) {
-                        println!("Error writing error response: {}", write_err);
+    let health = Arc::new(HealthRegistry::from_config(config));
+    if HealthChecker::spawn(config, health.clone()).is_some() {
+        println!("Health checking upstream servers every {:?}", config.health_check_interval());
+    }
+    Box::new(ServerHandler::new(config.clone(), mutexlb, health, pool))
+}
+
+// Re-reads http.conf and swaps in handlers built from it. A config that doesn't parse is
+// logged and ignored, the current one stays in place. Only what the handlers use is reloaded,
+// listen, the worker pool and the connection settings need a restart.
+fn reload(handler: &ReloadableHandler, pool: &Arc<UpstreamPool>) {
+    match load_config() {
+        Ok(config) => {
+            handler.swap(build_handler(&config, pool.clone()));
+            println!("Reloaded configuration");
+        }
+        Err(e) => println!("Keeping the current configuration, reload failed: {}", e),
+    }
+}
+
+// Parks client connections on an event loop, complete requests go to a fixed pool of worker
+// threads. When every worker is busy and the queue is full, new requests either get a 503 or
+// wait, see worker_queue_full. SIGTERM or SIGINT stops accepting connections and gives the
+// requests in flight shutdown_timeout to finish before exiting, SIGHUP reloads the config.
+// SIGUSR2 starts the binary again on the same listening sockets, once the new process is
+// serving this one shuts down like on SIGTERM.
+fn serve(listeners: Vec<Listener>, handler: Arc<ReloadableHandler>, config: &Config, pool: Arc<UpstreamPool>) -> std::io::Result<()> {
+    let listener_fds = listeners::raw_fds(&listeners);
+    let mut event_loop = EventLoop::new(listeners, Box::new(handler.clone()), config)?;
+    let shutdown = event_loop.shutdown_handle();
+    let mut signals = Signals::install(&[SIGTERM, SIGINT, SIGHUP, SIGUSR2])?;
+    let reload_pool = pool.clone();
+    std::thread::spawn(move || {
+        while let Ok(signal) = signals.wait() {
+            println!("Received signal {}", signal);
+            if signal == SIGHUP {
+                reload(&handler, &reload_pool);
+                continue;
+            }
+            if signal == SIGUSR2 {
+                match upgrade::upgrade_command().and_then(|command| upgrade::spawn_successor(command, &listener_fds, UPGRADE_TIMEOUT)) {
+                    Ok(child) => println!("Handed the listeners over to process {}", child.id()),
+                    Err(e) => {
+                        println!("Upgrade failed, still serving: {}", e);
+                        continue;
                     }
-                    stream.flush().unwrap_or_default();
                 }
             }
-        });
+            shutdown.request();
+            return;
+        }
+    });
+    if let Err(e) = upgrade::notify_ready() {
+        println!("Couldn't tell the previous process we're serving: {}", e);
     }
-    Ok(())
-}
 
-fn handle_connection(stream: &TcpStream, handler: Box<dyn Handler>) -> Result<String, std::io::Error> {
-    let result = handler.handle(stream);
-    Ok(result)
+    println!("Serving with {} worker threads", config.worker_threads());
+    event_loop.run()?;
+    pool.clear();
+    println!("Shut down");
+    // Without waiting for workers still stuck on a request past the grace period
+    exit(0)
 }